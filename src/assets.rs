@@ -0,0 +1,366 @@
+//! Loading and sharing meshes and materials
+//!
+//! Assets are found by key, the path for files, and built or loaded only
+//! the first time they are asked for. Everyone asking gets a handle to
+//! the same asset, an `Rc`.
+//!
+//! A mesh's model space geometry, `MeshData`, is shared by every
+//! instance of it. Each instance keeps only what placing it in the world
+//! needs of its own: the world space positions of the vertices and the
+//! faces, which carry the colors its material gives them. Materials are
+//! shared as they are.
+//!
+//! Instances hold on to their handles, so `unload_unused` can drop the
+//! assets nothing uses any more. Mesh files can also be loaded on a
+//! background thread and picked up with `poll` once they are ready.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use mesh::Mesh;
+use obj;
+use scene;
+use scene::Material;
+use toml;
+use types::Color;
+use types::Vec3;
+
+/// The geometry of a mesh in model space, loaded once and shared by its
+/// instances
+#[derive(Debug,Clone)]
+pub struct MeshData {
+    pub vertices: Rc<Vec<Vec3>>,
+    /// Vertex indices and color of each face
+    pub faces: Vec<([usize; 3], Color)>,
+}
+
+impl MeshData {
+    pub fn from_mesh(mesh: &Mesh) -> MeshData {
+        MeshData {
+            vertices: Rc::new(mesh.mesh.vertices.borrow().iter().map(|v| v.position()).collect()),
+            faces: mesh.mesh.faces.borrow().iter().map(|f| (f.indices(), f.get_color())).collect(),
+        }
+    }
+
+    /// A new instance of this geometry, holding on to the handle. Its
+    /// model space positions are the shared ones, placing it only moves
+    /// its own world space copy.
+    pub fn instance(data: &Rc<MeshData>) -> Mesh {
+        let mut mesh = Mesh::new();
+        for &r in data.vertices.iter() {
+            mesh.add_vertex(r);
+        }
+        *mesh.mesh.model.borrow_mut() = Some(data.vertices.clone());
+        for &(v, color) in data.faces.iter() {
+            mesh.add_triangle(v[0], v[1], v[2], color);
+        }
+        mesh.asset = Some(data.clone());
+        mesh
+    }
+}
+
+/// A loaded asset and whether it has been handed out yet
+struct Entry<T> {
+    asset: Rc<T>,
+    used: bool,
+}
+
+impl<T> Entry<T> {
+    fn new(asset: T) -> Entry<T> {
+        Entry { asset: Rc::new(asset), used: false }
+    }
+
+    fn take(&mut self) -> Rc<T> {
+        self.used = true;
+        self.asset.clone()
+    }
+
+    /// Handed out before and no handles are left
+    fn unused(&self) -> bool {
+        self.used && Rc::strong_count(&self.asset) == 1
+    }
+}
+
+/// Loaded meshes and materials by key
+pub struct Assets {
+    meshes: HashMap<String, Entry<MeshData>>,
+    materials: HashMap<String, Entry<Material>>,
+    /// Mesh files being loaded on other threads
+    loading: HashMap<String, Receiver<io::Result<MeshData>>>,
+}
+
+fn key<P>(path: P) -> String where P: AsRef<Path> {
+    path.as_ref().to_string_lossy().into_owned()
+}
+
+impl Assets {
+    pub fn new() -> Assets {
+        Assets {
+            meshes: HashMap::new(),
+            materials: HashMap::new(),
+            loading: HashMap::new(),
+        }
+    }
+
+    /// The mesh for a key, built with `build` if it isn't loaded yet
+    pub fn mesh<F>(&mut self, key: &str, build: F) -> io::Result<Rc<MeshData>>
+        where F: FnOnce() -> io::Result<Mesh>
+    {
+        if !self.meshes.contains_key(key) {
+            let mesh = try!(build());
+            self.meshes.insert(key.to_string(), Entry::new(MeshData::from_mesh(&mesh)));
+        }
+        Ok(self.meshes.get_mut(key).unwrap().take())
+    }
+
+    /// A mesh from an OBJ file, waiting for it if it is loading in the
+    /// background
+    pub fn load_mesh<P>(&mut self, path: P) -> io::Result<Rc<MeshData>> where P: AsRef<Path> {
+        let key = key(&path);
+        if let Some(rx) = self.loading.remove(&key) {
+            let data = match rx.recv() {
+                Ok(result) => try!(result),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::Other,
+                                                    format!("loading {} failed", key))),
+            };
+            self.meshes.insert(key.clone(), Entry::new(data));
+        }
+        self.mesh(&key, || obj::load(&path))
+    }
+
+    /// Start loading an OBJ file on another thread, `poll` picks it up
+    /// and `loaded_mesh` hands it out once it is ready
+    pub fn load_mesh_in_background<P>(&mut self, path: P) where P: AsRef<Path> {
+        let key = key(&path);
+        if self.meshes.contains_key(&key) || self.loading.contains_key(&key) {
+            return;
+        }
+        let path = path.as_ref().to_path_buf();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(obj::load(&path).map(|mesh| MeshData::from_mesh(&mesh)));
+        });
+        self.loading.insert(key, rx);
+    }
+
+    /// A mesh that is already loaded, `None` while it is still loading
+    pub fn loaded_mesh(&mut self, key: &str) -> Option<Rc<MeshData>> {
+        self.meshes.get_mut(key).map(|entry| entry.take())
+    }
+
+    pub fn is_loading(&self, key: &str) -> bool {
+        self.loading.contains_key(key)
+    }
+
+    /// Take in the meshes finished loading in the background, returning
+    /// the ones that failed and why
+    pub fn poll(&mut self) -> Vec<(String, io::Error)> {
+        let mut finished = vec![];
+        for (key, rx) in self.loading.iter() {
+            match rx.try_recv() {
+                Ok(result) => finished.push((key.clone(), result)),
+                Err(mpsc::TryRecvError::Empty) => {},
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    let e = io::Error::new(io::ErrorKind::Other, "the loading thread stopped");
+                    finished.push((key.clone(), Err(e)));
+                },
+            }
+        }
+
+        let mut errors = vec![];
+        for (key, result) in finished.into_iter() {
+            self.loading.remove(&key);
+            match result {
+                Ok(data) => { self.meshes.insert(key, Entry::new(data)); },
+                Err(e) => errors.push((key, e)),
+            }
+        }
+        errors
+    }
+
+    /// The material for a key, `material` if it isn't loaded yet
+    pub fn material(&mut self, key: &str, material: Material) -> Rc<Material> {
+        self.materials.entry(key.to_string()).or_insert_with(|| Entry::new(material)).take()
+    }
+
+    /// A material from a file of `key = value` pairs like the
+    /// `[object.material]` table of a scene
+    pub fn load_material<P>(&mut self, path: P) -> io::Result<Rc<Material>>
+        where P: AsRef<Path>
+    {
+        let key = key(&path);
+        if !self.materials.contains_key(&key) {
+            let mut text = String::new();
+            try!(try!(File::open(&path)).read_to_string(&mut text));
+            let invalid = |e: toml::Error| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}", key, e))
+            };
+            let tables = try!(toml::parse(&text).map_err(&invalid));
+            if let Some(table) = tables.get(1) {
+                return Err(invalid(toml::Error::new(table.position,
+                                                    "a material has no tables".to_string())));
+            }
+            let material = try!(scene::parse_material(&tables[0]).map_err(&invalid));
+            self.materials.insert(key.clone(), Entry::new(material));
+        }
+        Ok(self.materials.get_mut(&key).unwrap().take())
+    }
+
+    /// Drop a loaded asset so the next request loads it again, handles
+    /// already out keep the old one
+    pub fn forget(&mut self, key: &str) {
+        self.meshes.remove(key);
+        self.materials.remove(key);
+    }
+
+    /// Drop every asset that was handed out and is no longer held by
+    /// anything, returns how many were dropped
+    pub fn unload_unused(&mut self) -> usize {
+        let before = self.len();
+        self.meshes.retain(|_, entry| !entry.unused());
+        self.materials.retain(|_, entry| !entry.unused());
+        before - self.len()
+    }
+
+    /// How many assets are loaded
+    pub fn len(&self) -> usize {
+        self.meshes.len() + self.materials.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Assets;
+    use super::MeshData;
+    use math::Transform;
+    use mesh::Mesh;
+    use scene::Material;
+
+    /// A file of its own in the temp directory
+    fn temp_file(name: &str, text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("esparia-assets-{}-{}", process::id(), name));
+        File::create(&path).unwrap().write_all(text.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn meshes_are_built_once() {
+        let mut assets = Assets::new();
+        let built = Cell::new(0);
+        let build = || -> io::Result<Mesh> {
+            built.set(built.get() + 1);
+            Ok(Mesh::new_diamond(1.0))
+        };
+        let a = assets.mesh("diamond", &build).unwrap();
+        let b = assets.mesh("diamond", &build).unwrap();
+        assert_eq!(built.get(), 1);
+        assert!(Rc::ptr_eq(&a, &b));
+        assert_eq!(assets.len(), 1);
+    }
+
+    #[test]
+    fn instances_share_model_space_geometry() {
+        let data = Rc::new(MeshData::from_mesh(&Mesh::new_diamond(1.0)));
+        let mut a = MeshData::instance(&data);
+        let b = MeshData::instance(&data);
+        {
+            let model_a = a.mesh.model.borrow();
+            let model_b = b.mesh.model.borrow();
+            assert!(Rc::ptr_eq(model_a.as_ref().unwrap(), &data.vertices));
+            assert!(Rc::ptr_eq(model_b.as_ref().unwrap(), &data.vertices));
+        }
+
+        // Placing one moves only its own vertices
+        a.place(&Transform::translation([10.0, 0.0, 0.0]));
+        let first = data.vertices[0];
+        assert_eq!(a.mesh.vertices.borrow()[0].position(), [first[0] + 10.0, first[1], first[2]]);
+        assert_eq!(b.mesh.vertices.borrow()[0].position(), first);
+        assert_eq!(data.vertices[0], first);
+        assert!(Rc::ptr_eq(a.mesh.model.borrow().as_ref().unwrap(), &data.vertices));
+    }
+
+    #[test]
+    fn materials_are_shared() {
+        let mut assets = Assets::new();
+        let glass = Material { color: Some([0.5, 0.5, 1.0, 0.3]), ..Material::new() };
+        let a = assets.material("glass", glass);
+        let b = assets.material("glass", Material::new());
+        assert!(Rc::ptr_eq(&a, &b));
+        assert_eq!(*b, glass);
+
+        let path = temp_file("glass.toml", "color = [0.5, 0.5, 1, 0.3]\nwireframe = true\n");
+        let loaded = assets.load_material(&path).unwrap();
+        let again = assets.load_material(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(Rc::ptr_eq(&loaded, &again));
+        assert_eq!(*loaded, Material { wireframe: true, ..glass });
+    }
+
+    #[test]
+    fn unused_assets_are_unloaded() {
+        let mut assets = Assets::new();
+        let data = assets.mesh("diamond", || Ok(Mesh::new_diamond(1.0))).unwrap();
+        let mesh = MeshData::instance(&data);
+        drop(data);
+        let material = assets.material("plain", Material::new());
+        assert_eq!(assets.len(), 2);
+
+        // Still held by the instance and the handle
+        assert_eq!(assets.unload_unused(), 0);
+        drop(mesh);
+        assert_eq!(assets.unload_unused(), 1);
+        drop(material);
+        assert_eq!(assets.unload_unused(), 1);
+        assert_eq!(assets.len(), 0);
+    }
+
+    #[test]
+    fn background_loads_are_picked_up_by_poll() {
+        let good = temp_file("good.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n");
+        let bad = temp_file("bad.obj", "v 0 0 zero\n");
+        let good_key = good.to_string_lossy().into_owned();
+        let bad_key = bad.to_string_lossy().into_owned();
+
+        let mut assets = Assets::new();
+        assets.load_mesh_in_background(&good);
+        assets.load_mesh_in_background(&bad);
+        assert!(assets.is_loading(&good_key));
+        assert!(assets.loaded_mesh(&good_key).is_none());
+
+        let mut errors = vec![];
+        for _ in 0..500 {
+            errors.extend(assets.poll().into_iter().map(|(key, _)| key));
+            if !assets.is_loading(&good_key) && !assets.is_loading(&bad_key) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(&good).unwrap();
+        fs::remove_file(&bad).unwrap();
+
+        assert_eq!(errors, vec![bad_key.clone()]);
+        let data = assets.loaded_mesh(&good_key).unwrap();
+        assert_eq!(data.vertices.len(), 3);
+        assert_eq!(data.faces.len(), 1);
+        assert!(assets.loaded_mesh(&bad_key).is_none());
+    }
+}
@@ -0,0 +1,340 @@
+//! Behaviours attached to objects
+//!
+//! A behaviour is gameplay code carried by an object. It is started
+//! before its first update, updated once per world update and told about
+//! events sent to its object. Through the context it gets it can look up
+//! and change other objects, the camera and the lights.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+
+use camera::Camera;
+use ecs::Collider;
+use ecs::Components;
+use ecs::System;
+use input::Input;
+use lights::LightSource;
+use math::Transform;
+use types::Vec3;
+use world::Handle;
+use world::World;
+use world::WorldObject;
+
+/// Something that happened that behaviours can react to
+#[derive(Debug,Clone,PartialEq)]
+pub enum Event {
+    /// A key was typed
+    Key(String),
+    /// The object's collider touches another's, sent on every update
+    /// while they touch. Colliders are checked at the end of an update,
+    /// so this arrives on the update after they came to touch.
+    Collision(Handle),
+    /// Sent by the game or another behaviour
+    Message(String),
+}
+
+/// Gameplay code carried by an object
+pub trait Behaviour {
+    /// Called once, before the first update
+    fn start(&mut self, _ctx: &mut Context) {}
+
+    /// Called on every world update with the seconds since the last one
+    fn update(&mut self, _dt: f64, _ctx: &mut Context) {}
+
+    /// Called for each event sent to the object
+    fn on_event(&mut self, _event: &Event, _ctx: &mut Context) {}
+
+    /// Write what `load` needs to carry on from here, for save games
+    fn save(&self, _state: &mut State) {}
+
+    /// Carry on from a saved state, `start` is not called after this
+    fn load(&mut self, _state: &State) {}
+}
+
+/// Numbers a behaviour keeps in a save game, by key
+#[derive(Debug,Clone,PartialEq)]
+pub struct State {
+    values: BTreeMap<String, Vec<f64>>,
+}
+
+impl State {
+    pub fn new() -> State {
+        State { values: BTreeMap::new() }
+    }
+
+    pub fn set(&mut self, key: &str, values: &[f64]) {
+        self.values.insert(key.to_string(), values.to_vec());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[f64]> {
+        self.values.get(key).map(|v| &v[..])
+    }
+
+    /// The first number under a key
+    pub fn number(&self, key: &str) -> Option<f64> {
+        self.get(key).and_then(|v| v.first().cloned())
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<String, Vec<f64>> {
+        self.values.iter()
+    }
+}
+
+/// What a behaviour can see and change of the world
+pub struct Context<'a> {
+    world: &'a mut World,
+    me: Handle,
+}
+
+impl<'a> Context<'a> {
+    /// The object the behaviour is attached to
+    pub fn me(&self) -> Handle {
+        self.me
+    }
+
+    /// Seconds since the world started
+    pub fn time(&self) -> f64 {
+        self.world.t
+    }
+
+    pub fn object(&self, object: Handle) -> Option<&WorldObject> {
+        self.world.get(object)
+    }
+
+    pub fn object_mut(&mut self, object: Handle) -> Option<&mut WorldObject> {
+        self.world.get_mut(object)
+    }
+
+    pub fn find(&self, name: &str) -> Option<Handle> {
+        self.world.find(name)
+    }
+
+    pub fn tagged(&self, tag: &str) -> Vec<Handle> {
+        self.world.tagged(tag)
+    }
+
+    /// An object's transform relative to its parent
+    pub fn transform(&self, object: Handle) -> Option<Transform> {
+        self.world.get(object).map(|o| *o.local())
+    }
+
+    pub fn set_transform(&mut self, object: Handle, transform: Transform) {
+        self.world.set_transform(object, transform);
+    }
+
+    pub fn translate(&mut self, object: Handle, r: Vec3) {
+        self.world.translate_object(object, r);
+    }
+
+    /// Add an object at the top of the scene graph
+    pub fn spawn(&mut self, object: WorldObject) -> Handle {
+        self.world.object(object)
+    }
+
+    pub fn remove(&mut self, object: Handle) {
+        self.world.remove(object);
+    }
+
+    pub fn camera(&mut self) -> &mut Camera {
+        &mut self.world.camera
+    }
+
+    pub fn lights(&mut self) -> &mut Vec<LightSource> {
+        &mut self.world.lights
+    }
+
+    pub fn components(&mut self) -> &mut Components {
+        &mut self.world.components
+    }
+
+    /// Buttons held and pressed, read by action and axis
+    pub fn input(&self) -> &Input {
+        &self.world.input
+    }
+
+    /// Send an event to an object, delivered on the next update
+    pub fn send(&mut self, object: Handle, event: Event) {
+        self.world.send(object, event);
+    }
+
+    /// Send an event to every object, delivered on the next update
+    pub fn broadcast(&mut self, event: Event) {
+        self.world.broadcast(event);
+    }
+}
+
+/// The behaviours of one object, a component
+pub struct Behaviours {
+    /// Each behaviour and whether it has been started
+    items: Vec<(Box<Behaviour>, bool)>,
+}
+
+impl Behaviours {
+    pub fn new() -> Behaviours {
+        Behaviours { items: vec![] }
+    }
+
+    pub fn push(&mut self, behaviour: Box<Behaviour>) {
+        self.items.push((behaviour, false));
+    }
+
+    /// The saved state of each behaviour, in order
+    pub fn save(&self) -> Vec<State> {
+        self.items.iter().map(|&(ref behaviour, _)| {
+            let mut state = State::new();
+            behaviour.save(&mut state);
+            state
+        }).collect()
+    }
+
+    /// Restore each behaviour from a state `save` returned, in order
+    pub fn load(&mut self, states: &[State]) {
+        for (&mut (ref mut behaviour, ref mut started), state) in
+            self.items.iter_mut().zip(states.iter())
+        {
+            behaviour.load(state);
+            *started = true;
+        }
+    }
+}
+
+/// Starts and updates behaviours, then delivers the events sent before
+/// the update began
+pub struct BehaviourSystem;
+
+impl System for BehaviourSystem {
+    fn run(&mut self, world: &mut World, dt: f64) {
+        // Only what was sent before this update is delivered in it, events
+        // sent by the behaviours below wait for the next one
+        let mut events = world.take_events();
+        let contacts: Vec<(Handle, Handle)> = match world.components.storage::<Collider>() {
+            Some(colliders) => colliders.iter()
+                .flat_map(|(&me, c)| c.contacts.iter().map(move |&other| (me, other)))
+                .collect(),
+            None => vec![],
+        };
+        for (me, other) in contacts.into_iter() {
+            events.push((Some(me), Event::Collision(other)));
+        }
+
+        let mut all = world.components.take::<Behaviours>();
+
+        for (&me, behaviours) in all.iter_mut() {
+            for &mut (ref mut behaviour, ref mut started) in behaviours.items.iter_mut() {
+                if world.get(me).is_none() {
+                    break;
+                }
+                let mut ctx = Context { world: world, me: me };
+                if !*started {
+                    behaviour.start(&mut ctx);
+                    *started = true;
+                }
+                behaviour.update(dt, &mut ctx);
+            }
+        }
+
+        for (target, event) in events.into_iter() {
+            let targets = match target {
+                Some(object) => vec![object],
+                None => all.entities(),
+            };
+            for me in targets.into_iter() {
+                if let Some(behaviours) = all.get_mut(me) {
+                    for &mut (ref mut behaviour, _) in behaviours.items.iter_mut() {
+                        if world.get(me).is_none() {
+                            break;
+                        }
+                        behaviour.on_event(&event, &mut Context { world: world, me: me });
+                    }
+                }
+            }
+        }
+
+        // Behaviours of objects removed meanwhile go with them
+        all.retain(|me| world.get(me).is_some());
+        // Behaviours added meanwhile go after the ones already there
+        world.components.put_back(all, |mut behaviours, added| {
+            behaviours.items.extend(added.items);
+            behaviours
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::Behaviour;
+    use super::Behaviours;
+    use super::Context;
+    use super::Event;
+    use ecs::Collider;
+    use world::Handle;
+    use world::World;
+    use world::WorldObject;
+
+    /// Writes down what happens to it, and sends a message once
+    struct Logger {
+        log: Rc<RefCell<Vec<String>>>,
+        message_to: Option<Handle>,
+    }
+
+    impl Behaviour for Logger {
+        fn update(&mut self, _dt: f64, ctx: &mut Context) {
+            self.log.borrow_mut().push("update".to_string());
+            if let Some(target) = self.message_to.take() {
+                ctx.send(target, Event::Message("hello".to_string()));
+            }
+        }
+
+        fn on_event(&mut self, event: &Event, _ctx: &mut Context) {
+            let entry = match *event {
+                Event::Message(ref message) => message.clone(),
+                Event::Collision(_) => "collision".to_string(),
+                Event::Key(ref key) => key.clone(),
+            };
+            self.log.borrow_mut().push(entry);
+        }
+    }
+
+    fn logger(world: &mut World, object: Handle, message_to: Option<Handle>)
+              -> Rc<RefCell<Vec<String>>>
+    {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut behaviours = Behaviours::new();
+        behaviours.push(Box::new(Logger { log: log.clone(), message_to: message_to }));
+        world.components.insert(object, behaviours);
+        log
+    }
+
+    #[test]
+    fn events_sent_in_an_update_arrive_in_the_next() {
+        let mut world = World::new();
+        let sender = world.object(WorldObject::new());
+        let receiver = world.object(WorldObject::new());
+        // Whichever order the behaviours run in
+        logger(&mut world, sender, Some(receiver));
+        let log = logger(&mut world, receiver, None);
+
+        world.update(0.1);
+        assert_eq!(*log.borrow(), vec!["update"]);
+        world.update(0.1);
+        assert_eq!(*log.borrow(), vec!["update", "update", "hello"]);
+    }
+
+    #[test]
+    fn collisions_arrive_on_the_update_after_they_start() {
+        let mut world = World::new();
+        let a = world.object(WorldObject::new());
+        let b = world.object(WorldObject::new());
+        world.components.insert(a, Collider { radius: 1.0, contacts: vec![] });
+        world.components.insert(b, Collider { radius: 1.0, contacts: vec![] });
+        let log = logger(&mut world, a, None);
+
+        world.update(0.1);
+        assert_eq!(*log.borrow(), vec!["update"]);
+        world.update(0.1);
+        assert_eq!(*log.borrow(), vec!["update", "update", "collision"]);
+    }
+}
@@ -0,0 +1,118 @@
+//! Bounding volumes used to skip geometry the camera can't see
+
+use std::f64::INFINITY;
+
+use types::Vec3;
+use vecmath::vec3_add;
+use vecmath::vec3_len;
+use vecmath::vec3_scale;
+use vecmath::vec3_sub;
+
+/// Axis aligned bounding box
+#[derive(Debug,Clone,Copy)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+/// Bounding sphere
+#[derive(Debug,Clone,Copy)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f64,
+}
+
+/// A group of nearby faces in a mesh that is culled as a whole
+#[derive(Debug,Clone)]
+pub struct Chunk {
+    pub faces: Vec<usize>,
+    pub aabb: Aabb,
+    pub sphere: Sphere,
+}
+
+/// Bounding volumes of a whole mesh and of its chunks
+#[derive(Debug,Clone)]
+pub struct MeshBounds {
+    pub aabb: Aabb,
+    pub sphere: Sphere,
+    pub chunks: Vec<Chunk>,
+}
+
+// ======================================================================
+// Aabb
+
+impl Aabb {
+    /// A box containing nothing, grows to fit the first point added
+    pub fn empty() -> Aabb {
+        Aabb { min: [INFINITY; 3], max: [-INFINITY; 3] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min[0] > self.max[0]
+    }
+
+    pub fn grow(&mut self, r: Vec3) {
+        for i in 0..3 {
+            self.min[i] = self.min[i].min(r[i]);
+            self.max[i] = self.max[i].max(r[i]);
+        }
+    }
+
+    pub fn translate(&mut self, r: Vec3) {
+        self.min = vec3_add(self.min, r);
+        self.max = vec3_add(self.max, r);
+    }
+
+    pub fn center(&self) -> Vec3 {
+        vec3_scale(vec3_add(self.min, self.max), 0.5)
+    }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            [a[0], a[1], a[2]],
+            [b[0], a[1], a[2]],
+            [a[0], b[1], a[2]],
+            [b[0], b[1], a[2]],
+            [a[0], a[1], b[2]],
+            [b[0], a[1], b[2]],
+            [a[0], b[1], b[2]],
+            [b[0], b[1], b[2]],
+        ]
+    }
+}
+
+// ======================================================================
+// Sphere
+
+impl Sphere {
+    /// Smallest sphere around the center of `aabb` holding all `points`
+    pub fn around<I>(aabb: &Aabb, points: I) -> Sphere
+        where I: Iterator<Item=Vec3>
+    {
+        let center = aabb.center();
+        let mut radius: f64 = 0.0;
+        for r in points {
+            radius = radius.max(vec3_len(vec3_sub(r, center)));
+        }
+        Sphere { center: center, radius: radius }
+    }
+
+    pub fn translate(&mut self, r: Vec3) {
+        self.center = vec3_add(self.center, r);
+    }
+}
+
+// ======================================================================
+// MeshBounds
+
+impl MeshBounds {
+    pub fn translate(&mut self, r: Vec3) {
+        self.aabb.translate(r);
+        self.sphere.translate(r);
+        for chunk in self.chunks.iter_mut() {
+            chunk.aabb.translate(r);
+            chunk.sphere.translate(r);
+        }
+    }
+}
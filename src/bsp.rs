@@ -0,0 +1,526 @@
+//! Binary space partitioning of static geometry
+//!
+//! Sorting faces by the distance to their centroid gets the order wrong
+//! for long or intersecting faces. A BSP tree splits faces along the
+//! planes of other faces until every face is entirely in front of or
+//! behind every splitting plane, which gives an exact back to front
+//! order of the static geometry from any camera position.
+//!
+//! Dynamic polygons are inserted every frame, split by the static planes
+//! but not by each other. Polygons that end up together on the same side
+//! of a leaf are ordered by the distance to their centroid, so two
+//! dynamic polygons can still come out in the wrong order. The tree also
+//! holds every static polygon and is walked whole, so static geometry
+//! drawn through it is not culled against the camera frustum.
+
+use std::f64::EPSILON;
+
+use depth::FaceRef;
+use types::Vec3;
+use vecmath::vec3_add;
+use vecmath::vec3_cross;
+use vecmath::vec3_dot;
+use vecmath::vec3_len;
+use vecmath::vec3_normalized;
+use vecmath::vec3_scale;
+use vecmath::vec3_sub;
+
+/// Points closer to a plane than this are considered on the plane
+const PLANE_EPSILON: f64 = 1e-6;
+
+/// Number of faces tried as the splitting plane of each node
+const SPLIT_CANDIDATES: usize = 8;
+
+/// A convex polygon that is all or part of a mesh face
+#[derive(Debug,Clone)]
+pub struct BspPolygon {
+    pub points: Vec<Vec3>,
+    /// The face the polygon was cut from, used for shading
+    pub face: FaceRef,
+    /// Whether this is the shadow of `face` rather than the face itself
+    pub shadow: bool,
+}
+
+#[derive(Debug,Clone,Copy)]
+struct Plane {
+    normal: Vec3,
+    d: f64,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Side {
+    Front,
+    Back,
+    On,
+    Spanning,
+}
+
+#[derive(Debug)]
+struct Node {
+    plane: Plane,
+    polygons: Vec<BspPolygon>,
+    front: Option<Box<Node>>,
+    back: Option<Box<Node>>,
+    /// Dynamic polygons inserted this frame, kept at the node when they
+    /// end up on a side with no child
+    dynamic: Vec<BspPolygon>,
+    dynamic_front: Vec<BspPolygon>,
+    dynamic_back: Vec<BspPolygon>,
+}
+
+/// BSP tree built from static geometry, dynamic geometry is inserted
+/// each frame and cleared before the next
+#[derive(Debug)]
+pub struct BspTree {
+    root: Option<Box<Node>>,
+    /// Dynamic polygons when the tree has no static geometry at all
+    dynamic: Vec<BspPolygon>,
+}
+
+// ======================================================================
+// Plane
+
+impl Plane {
+    fn from_points(points: &[Vec3]) -> Option<Plane> {
+        let normal = vec3_cross(
+            vec3_sub(points[1], points[0]),
+            vec3_sub(points[2], points[0]),
+        );
+        if vec3_len(normal) < EPSILON {
+            return None;
+        }
+        let normal = vec3_normalized(normal);
+        Some(Plane { normal: normal, d: vec3_dot(normal, points[0]) })
+    }
+
+    #[inline(always)]
+    fn distance(&self, r: Vec3) -> f64 {
+        vec3_dot(self.normal, r) - self.d
+    }
+
+    fn classify(&self, polygon: &BspPolygon) -> Side {
+        let (mut front, mut back) = (false, false);
+        for &r in polygon.points.iter() {
+            let d = self.distance(r);
+            if d > PLANE_EPSILON {
+                front = true;
+            } else if d < -PLANE_EPSILON {
+                back = true;
+            }
+        }
+        match (front, back) {
+            (true, true) => Side::Spanning,
+            (true, false) => Side::Front,
+            (false, true) => Side::Back,
+            (false, false) => Side::On,
+        }
+    }
+
+    /// Cut a spanning polygon in two along the plane
+    fn split(&self, polygon: &BspPolygon) -> (BspPolygon, BspPolygon) {
+        let mut front = vec![];
+        let mut back = vec![];
+        let n = polygon.points.len();
+        for i in 0..n {
+            let a = polygon.points[i];
+            let b = polygon.points[(i + 1) % n];
+            let da = self.distance(a);
+            let db = self.distance(b);
+            if da >= -PLANE_EPSILON {
+                front.push(a);
+            }
+            if da <= PLANE_EPSILON {
+                back.push(a);
+            }
+            if (da > PLANE_EPSILON && db < -PLANE_EPSILON) ||
+               (da < -PLANE_EPSILON && db > PLANE_EPSILON) {
+                let t = da / (da - db);
+                let r = vec3_add(a, vec3_scale(vec3_sub(b, a), t));
+                front.push(r);
+                back.push(r);
+            }
+        }
+        (BspPolygon { points: front, face: polygon.face, shadow: polygon.shadow },
+         BspPolygon { points: back, face: polygon.face, shadow: polygon.shadow })
+    }
+}
+
+// ======================================================================
+// Node
+
+impl Node {
+    /// Build a subtree out of a non-empty list of polygons
+    fn build(mut polygons: Vec<BspPolygon>) -> Node {
+        let i = Node::choose_splitter(&polygons);
+        let splitter = polygons.swap_remove(i);
+        let plane = Plane::from_points(&splitter.points).unwrap();
+
+        let mut node = Node {
+            plane: plane,
+            polygons: vec![splitter],
+            front: None,
+            back: None,
+            dynamic: vec![],
+            dynamic_front: vec![],
+            dynamic_back: vec![],
+        };
+
+        let mut front = vec![];
+        let mut back = vec![];
+        for polygon in polygons.into_iter() {
+            match plane.classify(&polygon) {
+                Side::Front => front.push(polygon),
+                Side::Back => back.push(polygon),
+                Side::On => node.polygons.push(polygon),
+                Side::Spanning => {
+                    let (f, b) = plane.split(&polygon);
+                    if usable(&f) {
+                        front.push(f);
+                    }
+                    if usable(&b) {
+                        back.push(b);
+                    }
+                },
+            }
+        }
+
+        if !front.is_empty() {
+            node.front = Some(Box::new(Node::build(front)));
+        }
+        if !back.is_empty() {
+            node.back = Some(Box::new(Node::build(back)));
+        }
+        node
+    }
+
+    /// Pick the splitting polygon among a few candidates that cuts the
+    /// fewest other polygons and balances the two sides best
+    fn choose_splitter(polygons: &[BspPolygon]) -> usize {
+        let step = (polygons.len() / SPLIT_CANDIDATES).max(1);
+        let mut best = (0, ::std::usize::MAX);
+        for i in (0..polygons.len()).filter(|i| i % step == 0).take(SPLIT_CANDIDATES) {
+            let plane = Plane::from_points(&polygons[i].points).unwrap();
+            let (mut front, mut back, mut splits) = (0, 0, 0);
+            for polygon in polygons.iter() {
+                match plane.classify(polygon) {
+                    Side::Front => front += 1,
+                    Side::Back => back += 1,
+                    Side::Spanning => splits += 1,
+                    Side::On => (),
+                }
+            }
+            let balance = if front > back { front - back } else { back - front };
+            let score = splits * 8 + balance;
+            if score < best.1 {
+                best = (i, score);
+            }
+        }
+        best.0
+    }
+
+    fn insert(&mut self, polygon: BspPolygon) {
+        match self.plane.classify(&polygon) {
+            Side::Front => self.insert_front(polygon),
+            Side::Back => self.insert_back(polygon),
+            Side::On => self.dynamic.push(polygon),
+            Side::Spanning => {
+                let (f, b) = self.plane.split(&polygon);
+                self.insert_front(f);
+                self.insert_back(b);
+            },
+        }
+    }
+
+    fn insert_front(&mut self, polygon: BspPolygon) {
+        match self.front {
+            Some(ref mut node) => node.insert(polygon),
+            None => self.dynamic_front.push(polygon),
+        }
+    }
+
+    fn insert_back(&mut self, polygon: BspPolygon) {
+        match self.back {
+            Some(ref mut node) => node.insert(polygon),
+            None => self.dynamic_back.push(polygon),
+        }
+    }
+
+    fn clear_dynamic(&mut self) {
+        self.dynamic.clear();
+        self.dynamic_front.clear();
+        self.dynamic_back.clear();
+        if let Some(ref mut node) = self.front {
+            node.clear_dynamic();
+        }
+        if let Some(ref mut node) = self.back {
+            node.clear_dynamic();
+        }
+    }
+
+    fn traverse<F>(&self, eye: Vec3, f: &mut F)
+        where F: FnMut(&BspPolygon)
+    {
+        let in_front = self.plane.distance(eye) >= 0.0;
+        if in_front {
+            Node::traverse_side(&self.back, &self.dynamic_back, eye, f);
+        } else {
+            Node::traverse_side(&self.front, &self.dynamic_front, eye, f);
+        }
+        for polygon in self.polygons.iter().chain(self.dynamic.iter()) {
+            f(polygon);
+        }
+        if in_front {
+            Node::traverse_side(&self.front, &self.dynamic_front, eye, f);
+        } else {
+            Node::traverse_side(&self.back, &self.dynamic_back, eye, f);
+        }
+    }
+
+    fn traverse_side<F>(
+        child: &Option<Box<Node>>,
+        dynamic: &Vec<BspPolygon>,
+        eye: Vec3,
+        f: &mut F
+    ) where F: FnMut(&BspPolygon)
+    {
+        match *child {
+            Some(ref node) => node.traverse(eye, f),
+            None => traverse_unordered(dynamic, eye, f),
+        }
+    }
+}
+
+/// Whether a polygon has enough area to define a plane
+fn usable(polygon: &BspPolygon) -> bool {
+    polygon.points.len() >= 3 && Plane::from_points(&polygon.points).is_some()
+}
+
+/// Draw polygons that share a convex region of the tree, which nothing
+/// splits any further, far to near by centroid
+fn traverse_unordered<F>(polygons: &Vec<BspPolygon>, eye: Vec3, f: &mut F)
+    where F: FnMut(&BspPolygon)
+{
+    if polygons.len() < 2 {
+        for polygon in polygons.iter() {
+            f(polygon);
+        }
+        return;
+    }
+    let mut order: Vec<(f64, usize)> = polygons.iter().enumerate().map(|(i, p)| {
+        let mut center = [0.0; 3];
+        for &r in p.points.iter() {
+            center = vec3_add(center, r);
+        }
+        let center = vec3_scale(center, 1.0 / p.points.len() as f64);
+        (vec3_len(vec3_sub(center, eye)), i)
+    }).collect();
+    order.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(a.1.cmp(&b.1)));
+    for &(_, i) in order.iter() {
+        f(&polygons[i]);
+    }
+}
+
+// ======================================================================
+// BspTree
+
+impl BspTree {
+    /// Build a tree out of static polygons, degenerate ones are dropped
+    pub fn new(polygons: Vec<BspPolygon>) -> BspTree {
+        let polygons: Vec<BspPolygon> = polygons.into_iter().filter(usable).collect();
+        let root = if polygons.is_empty() {
+            None
+        } else {
+            Some(Box::new(Node::build(polygons)))
+        };
+        BspTree { root: root, dynamic: vec![] }
+    }
+
+    /// Add a dynamic polygon for this frame, splitting it where it
+    /// crosses the static geometry
+    pub fn insert(&mut self, polygon: BspPolygon) {
+        if polygon.points.len() < 3 {
+            return;
+        }
+        match self.root {
+            Some(ref mut node) => node.insert(polygon),
+            None => self.dynamic.push(polygon),
+        }
+    }
+
+    /// Remove every dynamic polygon inserted since the last clear
+    pub fn clear_dynamic(&mut self) {
+        self.dynamic.clear();
+        if let Some(ref mut node) = self.root {
+            node.clear_dynamic();
+        }
+    }
+
+    /// Visit every polygon in back to front order as seen from `eye`
+    pub fn traverse<F>(&self, eye: Vec3, mut f: F)
+        where F: FnMut(&BspPolygon)
+    {
+        match self.root {
+            Some(ref node) => node.traverse(eye, &mut f),
+            None => traverse_unordered(&self.dynamic, eye, &mut f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BspPolygon;
+    use super::BspTree;
+    use super::Plane;
+    use super::PLANE_EPSILON;
+    use depth::FaceRef;
+    use types::Vec3;
+    use vecmath::vec3_add;
+    use vecmath::vec3_cross;
+    use vecmath::vec3_len;
+    use vecmath::vec3_sub;
+
+    fn polygon(face: u32, points: &[Vec3]) -> BspPolygon {
+        BspPolygon {
+            points: points.to_vec(),
+            face: FaceRef { object: 0, mesh: 0, face: face },
+            shadow: false,
+        }
+    }
+
+    fn area(polygon: &BspPolygon) -> f64 {
+        let p = &polygon.points;
+        let mut total = [0.0; 3];
+        for i in 1..p.len() - 1 {
+            total = vec3_add(total, vec3_cross(vec3_sub(p[i], p[0]), vec3_sub(p[i + 1], p[0])));
+        }
+        vec3_len(total) / 2.0
+    }
+
+    fn order(tree: &BspTree, eye: Vec3) -> Vec<BspPolygon> {
+        let mut order = vec![];
+        tree.traverse(eye, |p| order.push(p.clone()));
+        order
+    }
+
+    /// Whether every point is further than the epsilon on the eye's side
+    /// of the other polygon's plane, or on the other side
+    fn all_on_side(polygon: &BspPolygon, other: &BspPolygon, eye: Vec3, eye_side: bool) -> bool {
+        let plane = Plane::from_points(&other.points).unwrap();
+        let eye_sign = plane.distance(eye).signum();
+        polygon.points.iter().all(|&r| {
+            let d = plane.distance(r) * eye_sign;
+            if eye_side { d > PLANE_EPSILON } else { d < -PLANE_EPSILON }
+        })
+    }
+
+    /// No polygon is drawn before one it is in front of
+    fn assert_back_to_front(order: &[BspPolygon], eye: Vec3) {
+        for i in 0..order.len() {
+            for j in i + 1..order.len() {
+                let (a, b) = (&order[i], &order[j]);
+                assert!(!(all_on_side(a, b, eye, true) && all_on_side(b, a, eye, false)),
+                        "face {} is drawn before face {} behind it from {:?}",
+                        a.face.face, b.face.face, eye);
+            }
+        }
+    }
+
+    /// Corners of a cube around the origin
+    fn eyes() -> Vec<Vec3> {
+        let mut eyes = vec![];
+        for &x in [-5.0, 5.0].iter() {
+            for &y in [-4.0, 4.0].iter() {
+                for &z in [-3.0, 3.0].iter() {
+                    eyes.push([x, y, z]);
+                }
+            }
+        }
+        eyes
+    }
+
+    #[test]
+    fn intersecting_faces_are_split_and_ordered() {
+        // Two squares crossing along the y axis
+        let a = polygon(0, &[[-1.0, -1.0, 0.0], [1.0, -1.0, 0.0],
+                             [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0]]);
+        let b = polygon(1, &[[0.0, -1.0, -1.0], [0.0, -1.0, 1.0],
+                             [0.0, 1.0, 1.0], [0.0, 1.0, -1.0]]);
+        let tree = BspTree::new(vec![a, b]);
+        for eye in eyes().into_iter() {
+            let order = order(&tree, eye);
+            // One of them is cut in two where the other crosses it
+            assert_eq!(order.len(), 3);
+            for face in 0..2 {
+                let total: f64 = order.iter().filter(|p| p.face.face == face).map(area).sum();
+                assert!((total - 4.0).abs() < 1e-9);
+            }
+            assert_back_to_front(&order, eye);
+        }
+    }
+
+    #[test]
+    fn coplanar_faces_are_drawn_together() {
+        let square = |face: u32, z: f64, x: f64| {
+            polygon(face, &[[x - 1.0, -1.0, z], [x + 1.0, -1.0, z],
+                            [x + 1.0, 1.0, z], [x - 1.0, 1.0, z]])
+        };
+        let tree = BspTree::new(vec![square(0, 0.0, -1.0), square(1, 1.0, 0.0),
+                                     square(2, 0.0, 1.0), square(3, -1.0, 0.0)]);
+        let faces = |eye: Vec3| -> Vec<u32> {
+            order(&tree, eye).iter().map(|p| p.face.face).collect()
+        };
+
+        let front = faces([0.0, 0.0, 5.0]);
+        assert_eq!(front.len(), 4);
+        assert_eq!(front[0], 3);
+        assert_eq!(front[3], 1);
+        let back = faces([0.0, 0.0, -5.0]);
+        assert_eq!(back[0], 1);
+        assert_eq!(back[3], 3);
+        for eye in eyes().into_iter() {
+            assert_back_to_front(&order(&tree, eye), eye);
+        }
+    }
+
+    #[test]
+    fn spanning_polygons_are_split_on_the_plane() {
+        let plane = Plane { normal: [1.0, 0.0, 0.0], d: 0.0 };
+
+        // A corner on the plane goes to both halves
+        let triangle = polygon(0, &[[0.0, 1.0, 0.0], [-1.0, -1.0, 0.0], [1.0, -1.0, 0.0]]);
+        let (front, back) = plane.split(&triangle);
+        assert_eq!(front.points, vec![[0.0, 1.0, 0.0], [0.0, -1.0, 0.0], [1.0, -1.0, 0.0]]);
+        assert_eq!(back.points, vec![[0.0, 1.0, 0.0], [-1.0, -1.0, 0.0], [0.0, -1.0, 0.0]]);
+
+        let square = polygon(1, &[[-1.0, -1.0, 0.0], [3.0, -1.0, 0.0],
+                                  [3.0, 1.0, 0.0], [-1.0, 1.0, 0.0]]);
+        let (front, back) = plane.split(&square);
+        assert_eq!(front.points.len(), 4);
+        assert_eq!(back.points.len(), 4);
+        assert!(front.points.iter().all(|&r| plane.distance(r) >= -PLANE_EPSILON));
+        assert!(back.points.iter().all(|&r| plane.distance(r) <= PLANE_EPSILON));
+        assert!((area(&front) - 6.0).abs() < 1e-9);
+        assert!((area(&back) - 2.0).abs() < 1e-9);
+        assert_eq!(front.face.face, 1);
+    }
+
+    #[test]
+    fn dynamic_polygons_are_split_by_static_planes() {
+        let wall = polygon(0, &[[0.0, -2.0, -2.0], [0.0, -2.0, 2.0],
+                                [0.0, 2.0, 2.0], [0.0, 2.0, -2.0]]);
+        let mut tree = BspTree::new(vec![wall]);
+        tree.insert(polygon(1, &[[-1.0, -1.0, 0.0], [1.0, -1.0, 0.0], [1.0, 1.0, 0.0]]));
+
+        let eye = [5.0, 0.0, 1.0];
+        let drawn = order(&tree, eye);
+        let faces: Vec<u32> = drawn.iter().map(|p| p.face.face).collect();
+        assert_eq!(faces, vec![1, 0, 1]);
+        // The piece behind the wall comes first
+        assert!(drawn[0].points.iter().all(|r| r[0] <= PLANE_EPSILON));
+        assert!(drawn[2].points.iter().all(|r| r[0] >= -PLANE_EPSILON));
+        assert_back_to_front(&drawn, eye);
+
+        tree.clear_dynamic();
+        assert_eq!(order(&tree, eye).len(), 1);
+    }
+}
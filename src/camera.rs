@@ -1,11 +1,21 @@
 
 use std::f64::NAN;
 
+use bounds::{
+    Aabb,
+    Sphere,
+};
+
 use types::{
     Vec3,
     Mat3,
 };
 
+/// World units the width of the screen spans at a depth of `screen` in
+/// front of the camera
+const VIEW_SPAN: f64 = 1500.0;
+
+#[derive(Debug,Clone)]
 pub struct Camera {
     pub width: f64,
     pub height: f64,
@@ -13,6 +23,21 @@ pub struct Camera {
     pub theta: Vec3,
     pub projection: Mat3,
     pub screen: f64,
+    /// Faces this far or further from the camera are not drawn
+    pub far: f64,
+    /// Horizontal offset of the image in pixels, used to converge the
+    /// eyes of stereo views
+    pub shift: f64,
+}
+
+/// The volume of space a camera can see, used to cull whole meshes
+/// before looking at their faces
+pub struct Frustum {
+    r: Vec3,
+    projection: Mat3,
+    /// The edges of the screen lie at `|x| = slope * z` in camera space
+    slope: f64,
+    far: f64,
 }
 
 use math::{
@@ -21,10 +46,12 @@ use math::{
 };
 
 use vecmath::{
+    mat3_transposed,
     vec3_sub,
     vec3_add,
     vec3_square_len,
     vec3_scale,
+    vec3_len,
 };
 
 impl Camera {
@@ -37,6 +64,8 @@ impl Camera {
             r: [0.0, -200.0, -250.0],
             theta: [-0.4, 0.0, 0.0],
             screen: 300.0,
+            far: 600.0,
+            shift: 0.0,
             projection: [[0.0; 3]; 3]
         };
         camera.update_projection();
@@ -50,15 +79,46 @@ impl Camera {
     /// Get the x, y location as projected on the screen
     #[inline(always)]
     pub fn projected(&self, r: Vec3) -> [f64; 2] {
+        let p = self.project(r);
+        [p[0], p[1]]
+    }
+
+    /// Get the x, y location as projected on the screen along with the
+    /// depth of the point in front of the camera
+    #[inline(always)]
+    pub fn project(&self, r: Vec3) -> [f64; 3] {
         let dr = vec3_sub(r, self.r);
         let d =  mat3xv3_mul(self.projection, dr);
-        let scale = self.screen / d[2] / 1500.0;
-        let bx = scale * d[0] * self.width + self.width  / 2.0;
+        let scale = self.screen / d[2] / VIEW_SPAN;
+        let bx = scale * d[0] * self.width + self.width  / 2.0 + self.shift;
         let by = scale * d[1] * self.height + self.height / 2.0;
         if d[2] < 0.0 {
-            [NAN, NAN]
+            [NAN, NAN, d[2]]
         } else {
-            [bx, by]
+            [bx, by, d[2]]
+        }
+    }
+
+    /// Pixels across the screen that a sideways distance `x` covers at
+    /// `depth` in front of the camera
+    pub fn pixels_across(&self, x: f64, depth: f64) -> f64 {
+        x * self.screen / depth / VIEW_SPAN * self.width
+    }
+
+    /// Get the world direction seen through a point on the screen
+    pub fn unproject(&self, p: [f64; 2]) -> Vec3 {
+        let x = (p[0] - self.width / 2.0 - self.shift) * VIEW_SPAN / self.screen / self.width;
+        let y = (p[1] - self.height / 2.0) * VIEW_SPAN / self.screen / self.height;
+        mat3xv3_mul(mat3_transposed(self.projection), [x, y, 1.0])
+    }
+
+    /// Get the volume currently visible to the camera
+    pub fn frustum(&self) -> Frustum {
+        Frustum {
+            r: self.r,
+            projection: self.projection,
+            slope: VIEW_SPAN / 2.0 / self.screen,
+            far: self.far,
         }
     }
 
@@ -83,3 +143,57 @@ impl Camera {
     }
 
 }
+
+impl Frustum {
+    /// Move a point into camera space
+    #[inline(always)]
+    fn view(&self, r: Vec3) -> Vec3 {
+        mat3xv3_mul(self.projection, vec3_sub(r, self.r))
+    }
+
+    /// Signed distances of a camera space point outside the near, left,
+    /// right, top and bottom planes
+    #[inline(always)]
+    fn outside(&self, d: Vec3) -> [f64; 5] {
+        let k = self.slope;
+        let n = (1.0 + k * k).sqrt();
+        [
+            -d[2],
+            ( d[0] - k * d[2]) / n,
+            (-d[0] - k * d[2]) / n,
+            ( d[1] - k * d[2]) / n,
+            (-d[1] - k * d[2]) / n,
+        ]
+    }
+
+    pub fn sphere_visible(&self, sphere: &Sphere) -> bool {
+        if vec3_len(vec3_sub(sphere.center, self.r)) - sphere.radius >= self.far {
+            return false;
+        }
+        let d = self.view(sphere.center);
+        self.outside(d).iter().all(|&o| o <= sphere.radius)
+    }
+
+    /// Whether any part of the box may be visible, a box is only culled
+    /// when all of its corners are outside the same plane
+    pub fn aabb_visible(&self, aabb: &Aabb) -> bool {
+        if aabb.is_empty() {
+            return false;
+        }
+        let mut outside = [true; 5];
+        for corner in aabb.corners().iter() {
+            let o = self.outside(self.view(*corner));
+            for i in 0..5 {
+                if o[i] <= 0.0 {
+                    outside[i] = false;
+                }
+            }
+        }
+        !outside.iter().any(|&o| o)
+    }
+
+    /// Cheap sphere test first, then the tighter box test
+    pub fn visible(&self, aabb: &Aabb, sphere: &Sphere) -> bool {
+        !aabb.is_empty() && self.sphere_visible(sphere) && self.aabb_visible(aabb)
+    }
+}
@@ -0,0 +1,73 @@
+//! Fixed timestep simulation
+//!
+//! The world is simulated in ticks of a fixed length however long frames
+//! take, so gameplay plays out the same at any frame rate. Scaled real
+//! time is gathered in an accumulator that whole ticks are taken out of,
+//! and what is left over says how far along the next tick is, which is
+//! used to draw objects between where the last two ticks put them.
+
+use std::time::Duration;
+
+/// Ticks the simulation at a fixed rate from real time
+#[derive(Debug,Clone,Copy)]
+pub struct Clock {
+    /// Seconds of simulated time per tick
+    pub tick: f64,
+    /// Most ticks run for one frame, the rest of a long frame is dropped
+    /// so a slow machine doesn't fall further and further behind
+    pub max_ticks: u32,
+    /// Simulated seconds per real second, 0 pauses and below 1 is slow
+    /// motion
+    pub time_scale: f64,
+    accumulator: f64,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock {
+            tick: 1.0 / 60.0,
+            max_ticks: 5,
+            time_scale: 1.0,
+            accumulator: 0.0,
+        }
+    }
+
+    pub fn tick(mut self, tick: f64) -> Clock {
+        self.tick = tick;
+        self
+    }
+
+    pub fn max_ticks(mut self, max_ticks: u32) -> Clock {
+        self.max_ticks = max_ticks;
+        self
+    }
+
+    pub fn time_scale(mut self, time_scale: f64) -> Clock {
+        self.time_scale = time_scale;
+        self
+    }
+
+    /// Add `dt` seconds of real time, returns how many ticks to run
+    pub fn advance(&mut self, dt: f64) -> u32 {
+        self.accumulator += dt.max(0.0) * self.time_scale.max(0.0);
+        let mut ticks = 0;
+        while self.accumulator >= self.tick && ticks < self.max_ticks {
+            self.accumulator -= self.tick;
+            ticks += 1;
+        }
+        if ticks == self.max_ticks {
+            self.accumulator = self.accumulator.min(self.tick);
+        }
+        ticks
+    }
+
+    /// How far from 0 to 1 the next tick is along, for interpolation
+    pub fn alpha(&self) -> f64 {
+        (self.accumulator / self.tick).max(0.0).min(1.0)
+    }
+}
+
+#[inline(always)]
+pub fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
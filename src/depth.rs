@@ -0,0 +1,187 @@
+//! Back to front ordering of faces for the painter's algorithm
+//!
+//! Faces are queued as small index/key pairs and radix sorted, the
+//! buffers are kept between frames so a frame allocates nothing once
+//! they have grown to fit the scene.
+
+use std::mem;
+
+/// Which face of which mesh a queued entry refers to
+#[derive(Debug,Clone,Copy)]
+pub struct FaceRef {
+    pub object: u32,
+    pub mesh: u32,
+    pub face: u32,
+}
+
+/// Items, usually faces, queued for drawing in one frame
+#[derive(Debug)]
+pub struct DepthQueue<T> {
+    faces: Vec<T>,
+    dists: Vec<f64>,
+    /// (sort key, index into `faces`) pairs
+    order: Vec<(u32, u32)>,
+    scratch: Vec<(u32, u32)>,
+}
+
+/// Sort key putting far faces first, NaN distances sort before
+/// everything else so they are drawn first and never cover real faces
+#[inline(always)]
+fn depth_key(dist: f64) -> u32 {
+    let dist = dist as f32;
+    if dist.is_nan() {
+        return 0;
+    }
+    // Distances are never negative, so the bits of the float sort the
+    // same way as the float itself. Clamp -0.0 to 0.0 to be sure, `max`
+    // may keep the sign of a zero.
+    let dist = if dist > 0.0 { dist } else { 0.0 };
+    !dist.to_bits()
+}
+
+impl<T> DepthQueue<T> where T: Copy {
+    pub fn new() -> DepthQueue<T> {
+        DepthQueue {
+            faces: vec![],
+            dists: vec![],
+            order: vec![],
+            scratch: vec![],
+        }
+    }
+
+    /// Forget last frame's faces, keeping the allocations
+    pub fn clear(&mut self) {
+        self.faces.clear();
+        self.dists.clear();
+        self.order.clear();
+    }
+
+    pub fn push(&mut self, face: T, dist: f64) {
+        let index = self.faces.len() as u32;
+        self.faces.push(face);
+        self.dists.push(dist);
+        self.order.push((depth_key(dist), index));
+    }
+
+    /// Order the queued faces back to front. The sort is stable, faces
+    /// at the same depth keep the order they were pushed in.
+    pub fn sort(&mut self) {
+        let n = self.order.len();
+        self.scratch.clear();
+        self.scratch.extend_from_slice(&self.order);
+
+        // Least significant byte first, skipping bytes that are the
+        // same for every key
+        for pass in 0..4 {
+            let shift = pass * 8;
+            let mut counts = [0usize; 256];
+            for &(key, _) in self.order.iter() {
+                counts[((key >> shift) & 0xff) as usize] += 1;
+            }
+            if counts.iter().any(|&c| c == n) {
+                continue;
+            }
+
+            let mut offsets = [0usize; 256];
+            let mut total = 0;
+            for (offset, &count) in offsets.iter_mut().zip(counts.iter()) {
+                *offset = total;
+                total += count;
+            }
+
+            for &(key, index) in self.order.iter() {
+                let bucket = ((key >> shift) & 0xff) as usize;
+                self.scratch[offsets[bucket]] = (key, index);
+                offsets[bucket] += 1;
+            }
+            mem::swap(&mut self.order, &mut self.scratch);
+        }
+    }
+
+    /// Iterate over the queued faces and their distances in sorted order
+    pub fn iter<'a>(&'a self) -> DepthIter<'a, T> {
+        DepthIter { queue: self, i: 0 }
+    }
+}
+
+/// Iterator over a sorted `DepthQueue`
+pub struct DepthIter<'a, T: 'a> {
+    queue: &'a DepthQueue<T>,
+    i: usize,
+}
+
+impl<'a, T> Iterator for DepthIter<'a, T> where T: Copy {
+    type Item = (T, f64);
+
+    fn next(&mut self) -> Option<(T, f64)> {
+        if self.i >= self.queue.order.len() {
+            return None;
+        }
+        let index = self.queue.order[self.i].1 as usize;
+        self.i += 1;
+        Some((self.queue.faces[index], self.queue.dists[index]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DepthQueue;
+
+    #[test]
+    fn sorts_like_a_stable_sort_by_distance() {
+        // Distances from a small LCG, with repeats and NaN thrown in
+        let mut seed: u64 = 12345;
+        let mut dists = vec![];
+        for i in 0..2000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let dist = match i % 100 {
+                0 => ::std::f64::NAN,
+                1 => 0.0,
+                2 => -0.0,
+                _ => (seed >> 40) as f64 / 1000.0,
+            };
+            dists.push(dist);
+            if i % 7 == 0 {
+                dists.push(dist);
+            }
+        }
+
+        let mut queue = DepthQueue::new();
+        for (i, &dist) in dists.iter().enumerate() {
+            queue.push(i, dist);
+        }
+        queue.sort();
+        let sorted: Vec<usize> = queue.iter().map(|(i, _)| i).collect();
+
+        // NaN first, then far to near by the f32 the keys are made of
+        let mut expected: Vec<usize> = (0..dists.len()).collect();
+        expected.sort_by(|&a, &b| {
+            let key = |i: usize| {
+                let d = dists[i] as f32;
+                if d.is_nan() { ::std::f32::INFINITY } else { d.max(0.0) }
+            };
+            key(b).partial_cmp(&key(a)).unwrap()
+        });
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn keeps_distances_with_their_items() {
+        let mut queue = DepthQueue::new();
+        queue.push('a', 1.0);
+        queue.push('b', 3.0);
+        queue.push('c', 2.0);
+        queue.sort();
+        let sorted: Vec<(char, f64)> = queue.iter().collect();
+        assert_eq!(sorted, vec![('b', 3.0), ('c', 2.0), ('a', 1.0)]);
+    }
+
+    #[test]
+    fn clear_forgets_items() {
+        let mut queue = DepthQueue::new();
+        queue.push(1, 1.0);
+        queue.clear();
+        queue.sort();
+        assert_eq!(queue.iter().count(), 0);
+    }
+}
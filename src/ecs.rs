@@ -0,0 +1,353 @@
+//! Entities, components and systems
+//!
+//! Every object in a world is an entity, its handle is the entity id and
+//! its transform and meshes are the components every entity has. Any
+//! other `'static` type can be attached as a component, each type in its
+//! own storage. Systems run in the order they were added on every update
+//! and find the entities they work on by querying for components.
+
+use std::any::Any;
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::collections::hash_map;
+use std::mem;
+
+use types::Vec3;
+use vecmath::vec3_len;
+use vecmath::vec3_scale;
+use vecmath::vec3_sub;
+use world::Handle;
+use world::World;
+
+/// Components of one type, by entity
+pub struct Storage<T> {
+    items: HashMap<Handle, T>,
+}
+
+impl<T> Storage<T> {
+    pub fn new() -> Storage<T> {
+        Storage { items: HashMap::new() }
+    }
+
+    /// Attach a component, replacing the entity's old one
+    pub fn insert(&mut self, entity: Handle, component: T) -> Option<T> {
+        self.items.insert(entity, component)
+    }
+
+    pub fn remove(&mut self, entity: Handle) -> Option<T> {
+        self.items.remove(&entity)
+    }
+
+    pub fn get(&self, entity: Handle) -> Option<&T> {
+        self.items.get(&entity)
+    }
+
+    pub fn get_mut(&mut self, entity: Handle) -> Option<&mut T> {
+        self.items.get_mut(&entity)
+    }
+
+    pub fn contains(&self, entity: Handle) -> bool {
+        self.items.contains_key(&entity)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<Handle, T> {
+        self.items.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> hash_map::IterMut<Handle, T> {
+        self.items.iter_mut()
+    }
+
+    /// Keep only the components of entities `f` returns true for
+    pub fn retain<F>(&mut self, mut f: F) where F: FnMut(Handle) -> bool {
+        self.items.retain(|&entity, _| f(entity));
+    }
+
+    /// Entities with this component
+    pub fn entities(&self) -> Vec<Handle> {
+        self.items.keys().cloned().collect()
+    }
+}
+
+/// A storage with its component type erased
+trait AnyStorage {
+    fn remove_entity(&mut self, entity: Handle);
+    fn contains_entity(&self, entity: Handle) -> bool;
+    fn as_any(&self) -> &Any;
+    fn as_any_mut(&mut self) -> &mut Any;
+}
+
+impl<T> AnyStorage for Storage<T> where T: Any {
+    fn remove_entity(&mut self, entity: Handle) {
+        self.items.remove(&entity);
+    }
+
+    fn contains_entity(&self, entity: Handle) -> bool {
+        self.items.contains_key(&entity)
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Every component of every entity, one storage per component type
+pub struct Components {
+    storages: HashMap<TypeId, Box<AnyStorage>>,
+}
+
+impl Components {
+    pub fn new() -> Components {
+        Components { storages: HashMap::new() }
+    }
+
+    pub fn storage<T>(&self) -> Option<&Storage<T>> where T: Any {
+        self.storages.get(&TypeId::of::<T>())
+            .and_then(|s| s.as_any().downcast_ref::<Storage<T>>())
+    }
+
+    /// The storage for a component type, created if there is none yet
+    pub fn storage_mut<T>(&mut self) -> &mut Storage<T> where T: Any {
+        self.storages.entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(Storage::<T>::new()))
+            .as_any_mut()
+            .downcast_mut::<Storage<T>>()
+            .unwrap()
+    }
+
+    pub fn insert<T>(&mut self, entity: Handle, component: T) -> Option<T> where T: Any {
+        self.storage_mut::<T>().insert(entity, component)
+    }
+
+    pub fn remove<T>(&mut self, entity: Handle) -> Option<T> where T: Any {
+        self.storage_mut::<T>().remove(entity)
+    }
+
+    pub fn get<T>(&self, entity: Handle) -> Option<&T> where T: Any {
+        self.storage::<T>().and_then(|s| s.get(entity))
+    }
+
+    pub fn get_mut<T>(&mut self, entity: Handle) -> Option<&mut T> where T: Any {
+        self.storage_mut::<T>().get_mut(entity)
+    }
+
+    /// Entities with a `T`
+    pub fn query<T>(&self) -> Vec<Handle> where T: Any {
+        self.storage::<T>().map_or(vec![], |s| s.entities())
+    }
+
+    /// Entities with both an `A` and a `B`
+    pub fn query2<A, B>(&self) -> Vec<Handle> where A: Any, B: Any {
+        match (self.storage::<A>(), self.storages.get(&TypeId::of::<B>())) {
+            (Some(a), Some(b)) => {
+                a.items.keys().cloned().filter(|&e| b.contains_entity(e)).collect()
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Take the whole storage of a component type out, so a system can
+    /// use the components while changing the world
+    pub fn take<T>(&mut self) -> Storage<T> where T: Any {
+        mem::replace(self.storage_mut::<T>(), Storage::new())
+    }
+
+    /// Put back a storage taken with `take`. An entity given a component
+    /// in the meantime keeps both, `merge` gets the one put back and the
+    /// one added and returns what the entity ends up with.
+    pub fn put_back<T, F>(&mut self, storage: Storage<T>, mut merge: F)
+        where T: Any, F: FnMut(T, T) -> T
+    {
+        let current = self.storage_mut::<T>();
+        for (entity, component) in storage.items.into_iter() {
+            let component = match current.remove(entity) {
+                Some(added) => merge(component, added),
+                None => component,
+            };
+            current.insert(entity, component);
+        }
+    }
+
+    /// Remove every component of an entity
+    pub fn remove_entity(&mut self, entity: Handle) {
+        for storage in self.storages.values_mut() {
+            storage.remove_entity(entity);
+        }
+    }
+}
+
+/// Gameplay run on every update, in the order systems were added
+pub trait System {
+    fn run(&mut self, world: &mut World, dt: f64);
+}
+
+// ======================================================================
+// Components
+
+/// Movement relative to the parent, in distance per second
+#[derive(Debug,Clone,Copy)]
+pub struct Velocity(pub Vec3);
+
+/// A sphere around the entity's position that touches other colliders
+#[derive(Debug,Clone)]
+pub struct Collider {
+    pub radius: f64,
+    /// Colliders touching this one as of the last update
+    pub contacts: Vec<Handle>,
+}
+
+impl Collider {
+    pub fn new(radius: f64) -> Collider {
+        Collider { radius: radius, contacts: vec![] }
+    }
+}
+
+/// Keeps one of the world's lights at the entity's position
+#[derive(Debug,Clone,Copy)]
+pub struct Light {
+    /// Index into `World::lights`
+    pub index: usize,
+}
+
+/// Code run for one entity on every update
+pub struct Script(pub Box<FnMut(Handle, &mut World, f64)>);
+
+// ======================================================================
+// Systems
+
+/// Runs every `Script`
+pub struct ScriptSystem;
+
+impl System for ScriptSystem {
+    fn run(&mut self, world: &mut World, dt: f64) {
+        let mut scripts = world.components.take::<Script>();
+        for (&entity, script) in scripts.iter_mut() {
+            (script.0)(entity, world, dt);
+        }
+        // Scripts of entities removed by a script go with them
+        scripts.retain(|entity| world.get(entity).is_some());
+        // A script added meanwhile runs after the one already there
+        world.components.put_back(scripts, |mut first, mut then| {
+            Script(Box::new(move |entity: Handle, world: &mut World, dt: f64| {
+                (first.0)(entity, world, dt);
+                (then.0)(entity, world, dt);
+            }))
+        });
+    }
+}
+
+/// Moves entities by their `Velocity`
+pub struct MovementSystem;
+
+impl System for MovementSystem {
+    fn run(&mut self, world: &mut World, dt: f64) {
+        let moving: Vec<(Handle, Velocity)> = match world.components.storage::<Velocity>() {
+            Some(storage) => storage.iter().map(|(&e, &v)| (e, v)).collect(),
+            None => return,
+        };
+        for (entity, velocity) in moving.into_iter() {
+            world.translate_object(entity, vec3_scale(velocity.0, dt));
+        }
+    }
+}
+
+/// Moves lights to their entities
+pub struct LightSystem;
+
+impl System for LightSystem {
+    fn run(&mut self, world: &mut World, _dt: f64) {
+        let lights: Vec<(Handle, Light)> = match world.components.storage::<Light>() {
+            Some(storage) => storage.iter().map(|(&e, &l)| (e, l)).collect(),
+            None => return,
+        };
+        world.update_transforms();
+        for (entity, light) in lights.into_iter() {
+            let r = match world.get(entity) {
+                Some(object) => object.world().translation,
+                None => continue,
+            };
+            if let Some(source) = world.lights.get_mut(light.index) {
+                source.r = r;
+            }
+        }
+    }
+}
+
+/// Finds the colliders touching each other
+pub struct CollisionSystem;
+
+impl System for CollisionSystem {
+    fn run(&mut self, world: &mut World, _dt: f64) {
+        let entities = world.components.query::<Collider>();
+        if entities.is_empty() {
+            return;
+        }
+        world.update_transforms();
+        let spheres: Vec<(Handle, Vec3, f64)> = entities.iter().filter_map(|&e| {
+            match (world.get(e), world.components.get::<Collider>(e)) {
+                (Some(object), Some(collider)) => {
+                    Some((e, object.world().translation, collider.radius))
+                },
+                _ => None,
+            }
+        }).collect();
+
+        let storage = world.components.storage_mut::<Collider>();
+        for &(e, _, _) in spheres.iter() {
+            if let Some(collider) = storage.get_mut(e) {
+                collider.contacts.clear();
+            }
+        }
+        for i in 0..spheres.len() {
+            for j in i + 1..spheres.len() {
+                let (a, ra, sa) = spheres[i];
+                let (b, rb, sb) = spheres[j];
+                if vec3_len(vec3_sub(ra, rb)) <= sa + sb {
+                    if let Some(collider) = storage.get_mut(a) {
+                        collider.contacts.push(b);
+                    }
+                    if let Some(collider) = storage.get_mut(b) {
+                        collider.contacts.push(a);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use world::World;
+    use world::WorldObject;
+
+    #[test]
+    fn components_added_while_taken_are_merged() {
+        let mut world = World::new();
+        let a = world.object(WorldObject::new());
+        let b = world.object(WorldObject::new());
+        world.components.insert(a, vec!["a"]);
+        world.components.insert(b, vec!["b"]);
+
+        let taken = world.components.take::<Vec<&'static str>>();
+        assert_eq!(world.components.get::<Vec<&'static str>>(a), None);
+        world.components.insert(a, vec!["added"]);
+        let c = world.object(WorldObject::new());
+        world.components.insert(c, vec!["c"]);
+        world.components.put_back(taken, |mut first, added| {
+            first.extend(added);
+            first
+        });
+
+        assert_eq!(world.components.get::<Vec<&'static str>>(a), Some(&vec!["a", "added"]));
+        assert_eq!(world.components.get::<Vec<&'static str>>(b), Some(&vec!["b"]));
+        assert_eq!(world.components.get::<Vec<&'static str>>(c), Some(&vec!["c"]));
+    }
+}
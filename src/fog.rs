@@ -0,0 +1,68 @@
+//! Distance fog blending far away faces into the background
+
+use types::Color;
+
+/// How quickly the fog thickens with distance from the camera
+#[derive(Debug,Clone,Copy)]
+pub enum FogMode {
+    /// No fog, faces keep their color up to the draw distance
+    None,
+    /// Fog thickens linearly from nothing at `start` to opaque at `end`
+    Linear { start: f64, end: f64 },
+    /// Fog thickens as `1 - e^(-density * d)`
+    Exponential { density: f64 },
+}
+
+/// Fog settings for a world
+#[derive(Debug,Clone,Copy)]
+pub struct Fog {
+    pub mode: FogMode,
+    /// Color faces fade toward, also used to clear the frame
+    pub color: Color,
+}
+
+impl Fog {
+    /// Create a fog of the given color that is disabled until a mode
+    /// is chosen
+    pub fn new(color: Color) -> Fog {
+        Fog { mode: FogMode::None, color: color }
+    }
+
+    pub fn linear(mut self, start: f64, end: f64) -> Fog {
+        self.mode = FogMode::Linear { start: start, end: end };
+        self
+    }
+
+    pub fn exponential(mut self, density: f64) -> Fog {
+        self.mode = FogMode::Exponential { density: density };
+        self
+    }
+
+    /// Fraction of fog between the camera and a point `dist` away,
+    /// from 0 (clear) to 1 (fully fogged)
+    pub fn amount(&self, dist: f64) -> f32 {
+        let amount = match self.mode {
+            FogMode::None => 0.0,
+            FogMode::Linear { start, end } => {
+                if end <= start {
+                    if dist < start { 0.0 } else { 1.0 }
+                } else {
+                    (dist - start) / (end - start)
+                }
+            },
+            FogMode::Exponential { density } => 1.0 - (-density * dist).exp(),
+        };
+        amount.max(0.0).min(1.0) as f32
+    }
+
+    /// Blend a face color toward the fog color, alpha is left alone
+    pub fn apply(&self, color: Color, dist: f64) -> Color {
+        let f = self.amount(dist);
+        [
+            color[0] + (self.color[0] - color[0]) * f,
+            color[1] + (self.color[1] - color[1]) * f,
+            color[2] + (self.color[2] - color[2]) * f,
+            color[3],
+        ]
+    }
+}
@@ -0,0 +1,370 @@
+//! Buttons mapped to named actions and axes
+//!
+//! `Input` follows which keys and mouse buttons are held from piston's
+//! press and release events, and which were pressed or released since
+//! the last update, so holding a key doesn't depend on key repeat.
+//! Gameplay asks about actions like "pause" and axes like "forward"
+//! rather than keys, and the bindings between them can be read from a
+//! config file:
+//!
+//! ```toml
+//! [actions]
+//! pause = ["p"]
+//! quicksave = ["f5"]
+//!
+//! [axes]
+//! # Pairs of the button for -1 and the button for 1
+//! strafe = ["a", "d"]
+//! rise = ["x", "2", "shift", "space"]
+//!
+//! [mouse]
+//! # The mouse direction moving an axis, `x` or `y`, and how far a pixel
+//! turn = ["x", 0.005]
+//! look = ["y", -0.005]
+//! ```
+//!
+//! Buttons are named by their lowercase key, `0` to `9`, `f1` to `f12`,
+//! `space`, `return`, `tab`, `backspace`, `escape`, the arrow keys `up`,
+//! `down`, `left` and `right`, `shift`, `ctrl` and `alt` for the keys on
+//! either side or `lshift`, `rshift` and so on for one of them, and
+//! `mouse-left`, `mouse-right` and `mouse-middle`.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use piston::input::Button;
+use piston::input::Key;
+use piston::input::MouseButton;
+use toml;
+use toml::Item;
+
+const KEYS: &'static [(&'static str, Key)] = &[
+    ("a", Key::A), ("b", Key::B), ("c", Key::C), ("d", Key::D), ("e", Key::E),
+    ("f", Key::F), ("g", Key::G), ("h", Key::H), ("i", Key::I), ("j", Key::J),
+    ("k", Key::K), ("l", Key::L), ("m", Key::M), ("n", Key::N), ("o", Key::O),
+    ("p", Key::P), ("q", Key::Q), ("r", Key::R), ("s", Key::S), ("t", Key::T),
+    ("u", Key::U), ("v", Key::V), ("w", Key::W), ("x", Key::X), ("y", Key::Y),
+    ("z", Key::Z),
+    ("0", Key::D0), ("1", Key::D1), ("2", Key::D2), ("3", Key::D3), ("4", Key::D4),
+    ("5", Key::D5), ("6", Key::D6), ("7", Key::D7), ("8", Key::D8), ("9", Key::D9),
+    ("f1", Key::F1), ("f2", Key::F2), ("f3", Key::F3), ("f4", Key::F4),
+    ("f5", Key::F5), ("f6", Key::F6), ("f7", Key::F7), ("f8", Key::F8),
+    ("f9", Key::F9), ("f10", Key::F10), ("f11", Key::F11), ("f12", Key::F12),
+    ("space", Key::Space), ("return", Key::Return), ("tab", Key::Tab),
+    ("backspace", Key::Backspace), ("escape", Key::Escape),
+    ("up", Key::Up), ("down", Key::Down), ("left", Key::Left), ("right", Key::Right),
+    ("lshift", Key::LShift), ("rshift", Key::RShift), ("lctrl", Key::LCtrl),
+    ("rctrl", Key::RCtrl), ("lalt", Key::LAlt), ("ralt", Key::RAlt),
+];
+
+/// The buttons with a name, see the module documentation for the names.
+/// Modifiers name the keys on both sides, unknown names none.
+pub fn buttons(name: &str) -> Vec<Button> {
+    let keys = |keys: &[Key]| keys.iter().map(|&key| Button::Keyboard(key)).collect();
+    match name {
+        "shift" => keys(&[Key::LShift, Key::RShift]),
+        "ctrl" => keys(&[Key::LCtrl, Key::RCtrl]),
+        "alt" => keys(&[Key::LAlt, Key::RAlt]),
+        "mouse-left" => vec![Button::Mouse(MouseButton::Left)],
+        "mouse-right" => vec![Button::Mouse(MouseButton::Right)],
+        "mouse-middle" => vec![Button::Mouse(MouseButton::Middle)],
+        _ => KEYS.iter()
+            .filter(|&&(n, _)| n == name)
+            .map(|&(_, key)| Button::Keyboard(key))
+            .collect(),
+    }
+}
+
+/// A direction the mouse moves in
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum MouseAxis {
+    X,
+    Y,
+}
+
+/// Which buttons trigger each action and move each axis
+#[derive(Debug,Clone)]
+pub struct Bindings {
+    actions: HashMap<String, Vec<Button>>,
+    /// The button for -1 and the button for 1 of each pair
+    axes: HashMap<String, Vec<(Button, Button)>>,
+    /// Mouse directions moving each axis and how far a pixel moves it
+    mouse: HashMap<String, Vec<(MouseAxis, f64)>>,
+}
+
+/// The buttons of each name in an array of names
+fn parse_buttons(item: &Item) -> Result<Vec<Vec<Button>>, toml::Error> {
+    let mut named = vec![];
+    for item in try!(item.as_array()).iter() {
+        let name = try!(item.as_str());
+        let buttons = buttons(name);
+        if buttons.is_empty() {
+            return Err(toml::Error::new(item.position, format!("unknown button `{}`", name)));
+        }
+        named.push(buttons);
+    }
+    Ok(named)
+}
+
+/// A mouse direction and scale, `["x", 0.005]`
+fn parse_mouse(item: &Item) -> Result<(MouseAxis, f64), toml::Error> {
+    let pair = try!(item.as_array());
+    if pair.len() != 2 {
+        return Err(toml::Error::new(item.position,
+                                    "expected a mouse direction and a scale".to_string()));
+    }
+    let mouse = match try!(pair[0].as_str()) {
+        "x" => MouseAxis::X,
+        "y" => MouseAxis::Y,
+        other => {
+            return Err(toml::Error::new(pair[0].position, format!(
+                "unknown mouse direction `{}`, expected x or y", other)));
+        },
+    };
+    Ok((mouse, try!(pair[1].as_number())))
+}
+
+impl Bindings {
+    /// No bindings at all
+    pub fn new() -> Bindings {
+        Bindings {
+            actions: HashMap::new(),
+            axes: HashMap::new(),
+            mouse: HashMap::new(),
+        }
+    }
+
+    /// Also trigger an action with a button
+    pub fn action(mut self, action: &str, button: Button) -> Bindings {
+        self.actions.entry(action.to_string()).or_insert_with(Vec::new).push(button);
+        self
+    }
+
+    /// Also move an axis to -1 with `negative` and to 1 with `positive`
+    pub fn axis(mut self, axis: &str, negative: Button, positive: Button) -> Bindings {
+        self.axes.entry(axis.to_string()).or_insert_with(Vec::new).push((negative, positive));
+        self
+    }
+
+    /// Also move an axis by `scale` for every pixel the mouse moves
+    /// along `mouse`
+    pub fn mouse_axis(mut self, axis: &str, mouse: MouseAxis, scale: f64) -> Bindings {
+        self.mouse.entry(axis.to_string()).or_insert_with(Vec::new).push((mouse, scale));
+        self
+    }
+
+    /// The actions a button triggers
+    pub fn actions_of(&self, button: Button) -> Vec<String> {
+        self.actions.iter()
+            .filter(|&(_, buttons)| buttons.contains(&button))
+            .map(|(action, _)| action.clone())
+            .collect()
+    }
+
+    /// Read bindings from the text of a config file, only the bindings
+    /// in it, not the defaults
+    pub fn parse(text: &str) -> Result<Bindings, toml::Error> {
+        let mut bindings = Bindings::new();
+        for table in try!(toml::parse(text)).iter() {
+            match (table.name.as_str(), table.array) {
+                ("", false) => try!(table.check_keys(&[])),
+                ("actions", false) => {
+                    for &(ref action, ref item) in table.entries.iter() {
+                        for buttons in try!(parse_buttons(item)).into_iter() {
+                            for button in buttons.into_iter() {
+                                bindings = bindings.action(action, button);
+                            }
+                        }
+                    }
+                },
+                ("axes", false) => {
+                    for &(ref axis, ref item) in table.entries.iter() {
+                        let named = try!(parse_buttons(item));
+                        if named.is_empty() || named.len() % 2 != 0 {
+                            return Err(toml::Error::new(item.position, format!(
+                                "axis `{}` needs pairs of a negative and a positive button",
+                                axis)));
+                        }
+                        for pair in named.chunks(2) {
+                            for &negative in pair[0].iter() {
+                                for &positive in pair[1].iter() {
+                                    bindings = bindings.axis(axis, negative, positive);
+                                }
+                            }
+                        }
+                    }
+                },
+                ("mouse", false) => {
+                    for &(ref axis, ref item) in table.entries.iter() {
+                        let (mouse, scale) = try!(parse_mouse(item));
+                        bindings = bindings.mouse_axis(axis, mouse, scale);
+                    }
+                },
+                (name, array) => {
+                    let header = if array { format!("[[{}]]", name) } else { format!("[{}]", name) };
+                    return Err(toml::Error::new(table.position,
+                                                format!("unknown table {}", header)));
+                },
+            }
+        }
+        Ok(bindings)
+    }
+
+    /// Read bindings from a config file
+    pub fn load<P>(path: P) -> io::Result<Bindings> where P: AsRef<Path> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut text));
+        Bindings::parse(&text).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}", path.display(), e))
+        })
+    }
+}
+
+impl Default for Bindings {
+    /// The keys the game has always used
+    fn default() -> Bindings {
+        Bindings::new()
+            .action("pause", Button::Keyboard(Key::P))
+            .action("quicksave", Button::Keyboard(Key::F5))
+            .action("quickload", Button::Keyboard(Key::F9))
+            .action("screenshot", Button::Keyboard(Key::F12))
+            .axis("strafe", Button::Keyboard(Key::A), Button::Keyboard(Key::D))
+            .axis("forward", Button::Keyboard(Key::S), Button::Keyboard(Key::W))
+            .axis("rise", Button::Keyboard(Key::X), Button::Keyboard(Key::D2))
+            .axis("turn", Button::Keyboard(Key::Left), Button::Keyboard(Key::Right))
+            .axis("look", Button::Keyboard(Key::Down), Button::Keyboard(Key::Up))
+            .mouse_axis("turn", MouseAxis::X, 0.005)
+            .mouse_axis("look", MouseAxis::Y, -0.005)
+    }
+}
+
+/// Buttons held, pressed and released, and the bindings to read them by
+pub struct Input {
+    pub bindings: Bindings,
+    held: HashSet<Button>,
+    /// Since the last update
+    pressed: HashSet<Button>,
+    /// Since the last update
+    released: HashSet<Button>,
+    /// Where the cursor was last seen, `None` until it is seen again
+    /// after focus is lost
+    cursor: Option<[f64; 2]>,
+    /// Pixels the mouse moved since the last update
+    motion: [f64; 2],
+}
+
+impl Input {
+    pub fn new(bindings: Bindings) -> Input {
+        Input {
+            bindings: bindings,
+            held: HashSet::new(),
+            pressed: HashSet::new(),
+            released: HashSet::new(),
+            cursor: None,
+            motion: [0.0; 2],
+        }
+    }
+
+    /// A button went down, false if it was already held
+    pub fn press(&mut self, button: Button) -> bool {
+        if !self.held.insert(button) {
+            return false;
+        }
+        self.pressed.insert(button);
+        true
+    }
+
+    /// A button went up
+    pub fn release(&mut self, button: Button) {
+        if self.held.remove(&button) {
+            self.released.insert(button);
+        }
+    }
+
+    /// Let go of every button, for when the window loses focus and
+    /// won't hear about releases. The cursor is forgotten too, so it
+    /// doesn't jump when it comes back somewhere else.
+    pub fn release_all(&mut self) {
+        let held: Vec<Button> = self.held.iter().cloned().collect();
+        for button in held.into_iter() {
+            self.release(button);
+        }
+        self.cursor = None;
+    }
+
+    /// The cursor is at `pos`, the mouse moved by how far it is from
+    /// where it was
+    pub fn move_cursor(&mut self, pos: [f64; 2]) {
+        if let Some(last) = self.cursor {
+            self.motion[0] += pos[0] - last[0];
+            self.motion[1] += pos[1] - last[1];
+        }
+        self.cursor = Some(pos);
+    }
+
+    /// Forget the presses, releases and mouse motion, called after each
+    /// update
+    pub fn clear(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+        self.motion = [0.0; 2];
+    }
+
+    pub fn is_held(&self, button: Button) -> bool {
+        self.held.contains(&button)
+    }
+
+    fn any(&self, action: &str, buttons: &HashSet<Button>) -> bool {
+        match self.bindings.actions.get(action) {
+            Some(bound) => bound.iter().any(|b| buttons.contains(b)),
+            None => false,
+        }
+    }
+
+    /// Whether any button of the action is held
+    pub fn held(&self, action: &str) -> bool {
+        self.any(action, &self.held)
+    }
+
+    /// Whether a button of the action was pressed since the last update
+    pub fn pressed(&self, action: &str) -> bool {
+        self.any(action, &self.pressed)
+    }
+
+    /// Whether a button of the action was released since the last update
+    pub fn released(&self, action: &str) -> bool {
+        self.any(action, &self.released)
+    }
+
+    /// -1 with a negative button of the axis held, 1 with a positive
+    /// one, 0 with neither or both
+    pub fn axis(&self, axis: &str) -> f64 {
+        let pairs = match self.bindings.axes.get(axis) {
+            Some(pairs) => pairs,
+            None => return 0.0,
+        };
+        let negative = pairs.iter().any(|&(negative, _)| self.is_held(negative));
+        let positive = pairs.iter().any(|&(_, positive)| self.is_held(positive));
+        match (negative, positive) {
+            (true, false) => -1.0,
+            (false, true) => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// How far the mouse moved an axis since the last update
+    pub fn motion(&self, axis: &str) -> f64 {
+        match self.bindings.mouse.get(axis) {
+            Some(bound) => bound.iter().map(|&(mouse, scale)| match mouse {
+                MouseAxis::X => self.motion[0] * scale,
+                MouseAxis::Y => self.motion[1] * scale,
+            }).sum(),
+            None => 0.0,
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Items carried by an object, a component
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+
+/// How many of each item an object carries
+#[derive(Debug,Clone,PartialEq)]
+pub struct Inventory {
+    items: BTreeMap<String, u32>,
+}
+
+impl Inventory {
+    pub fn new() -> Inventory {
+        Inventory { items: BTreeMap::new() }
+    }
+
+    pub fn add(&mut self, item: &str, count: u32) {
+        *self.items.entry(item.to_string()).or_insert(0) += count;
+    }
+
+    /// Take `count` of an item, false and nothing taken if there aren't
+    /// that many
+    pub fn remove(&mut self, item: &str, count: u32) -> bool {
+        let left = match self.items.get(item) {
+            Some(&n) if n >= count => n - count,
+            _ => return false,
+        };
+        if left == 0 {
+            self.items.remove(item);
+        } else {
+            self.items.insert(item.to_string(), left);
+        }
+        true
+    }
+
+    pub fn count(&self, item: &str) -> u32 {
+        self.items.get(item).cloned().unwrap_or(0)
+    }
+
+    /// Every item carried and how many, by name
+    pub fn items(&self) -> btree_map::Iter<String, u32> {
+        self.items.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+}
@@ -20,8 +20,35 @@ extern crate rand;
 pub mod app;
 
 mod world;
-mod types;
-mod camera;
+pub mod types;
+pub mod camera;
 mod math;
-mod mesh;
+pub mod mesh;
 mod lights;
+mod fog;
+mod bounds;
+mod depth;
+mod bsp;
+mod raster;
+mod render;
+mod shadow;
+pub mod occlusion;
+mod sky;
+mod outline;
+mod postprocess;
+mod svg;
+mod terminal;
+mod stereo;
+mod viewport;
+pub mod ecs;
+pub mod behaviour;
+mod clock;
+pub mod toml;
+pub mod obj;
+pub mod scene;
+pub mod reload;
+pub mod random;
+pub mod inventory;
+pub mod save;
+pub mod assets;
+pub mod input;
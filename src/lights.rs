@@ -1,12 +1,15 @@
 use vecmath::{
     vec3_sub,
     vec3_add,
+    vec3_normalized,
+    vec3_scale,
 };
 
 use types::{
     Vec3
 };
 
+#[derive(Debug,Clone)]
 pub struct LightSource {
     pub r: Vec3,
     pub direction: Vec3,
@@ -25,4 +28,31 @@ impl LightSource {
             point_source: true,
         }
     }
+
+    /// A light infinitely far away, like the sun, shining from
+    /// `direction` toward the origin
+    pub fn directional(direction: Vec3) -> LightSource {
+        let direction = vec3_normalized(direction);
+        LightSource {
+            r: vec3_scale(direction, 1e6),
+            direction: direction,
+            intensity: 1.0,
+            color: [1.0; 3],
+            point_source: false,
+        }
+    }
+
+    /// Unit vector from `r` toward the light
+    pub fn toward(&self, r: Vec3) -> Vec3 {
+        if self.point_source {
+            vec3_normalized(vec3_sub(self.r, r))
+        } else {
+            vec3_normalized(self.direction)
+        }
+    }
+
+    /// Unit vector along which light travels when it reaches `r`
+    pub fn ray(&self, r: Vec3) -> Vec3 {
+        vec3_scale(self.toward(r), -1.0)
+    }
 }
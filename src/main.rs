@@ -4,8 +4,35 @@
 
 extern crate esparia;
 
+use std::env;
+
 use esparia::app::Game;
 
 fn main() {
-    Game::new().run();
+    let args: Vec<String> = env::args().collect();
+    let game = match args.iter().position(|arg| arg == "--scene") {
+        Some(i) => {
+            let path = match args.get(i + 1) {
+                Some(path) => path,
+                None => {
+                    println!("--scene needs a path");
+                    return;
+                },
+            };
+            match Game::from_scene(path) {
+                Ok(game) => game,
+                Err(e) => {
+                    println!("Could not load {}: {}", path, e);
+                    return;
+                },
+            }
+        },
+        None => Game::new(),
+    };
+
+    if args.iter().any(|arg| arg == "--terminal") {
+        game.run_terminal();
+    } else {
+        game.run();
+    }
 }
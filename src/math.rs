@@ -84,3 +84,111 @@ pub fn vec3_rotate_around(r: Vec3, rotation: Mat3, around: Vec3) -> Vec3 {
     let rotated = mat3xv3_mul(rotation, translated);
     vecmath::vec3_add(rotated, around)
 }
+
+/// Distance along a ray to where it hits a triangle, if it does
+#[inline(always)]
+pub fn ray_triangle(origin: Vec3, direction: Vec3, triangle: [Vec3; 3]) -> Option<f64> {
+    let e1 = vecmath::vec3_sub(triangle[1], triangle[0]);
+    let e2 = vecmath::vec3_sub(triangle[2], triangle[0]);
+    let p = vecmath::vec3_cross(direction, e2);
+    let det = vecmath::vec3_dot(e1, p);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let s = vecmath::vec3_sub(origin, triangle[0]);
+    let u = vecmath::vec3_dot(s, p) / det;
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+    let q = vecmath::vec3_cross(s, e1);
+    let v = vecmath::vec3_dot(direction, q) / det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = vecmath::vec3_dot(e2, q) / det;
+    if t > 1e-9 { Some(t) } else { None }
+}
+
+/// An affine transform, a linear part for rotation and scale followed
+/// by a translation
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Transform {
+    pub linear: Mat3,
+    pub translation: Vec3,
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            linear: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            translation: [0.0; 3],
+        }
+    }
+
+    pub fn translation(r: Vec3) -> Transform {
+        Transform { translation: r, ..Transform::identity() }
+    }
+
+    pub fn rotation(theta: Vec3) -> Transform {
+        Transform { linear: mat_rotation(theta), ..Transform::identity() }
+    }
+
+    pub fn scale(scale: f64) -> Transform {
+        Transform {
+            linear: [[scale, 0.0, 0.0], [0.0, scale, 0.0], [0.0, 0.0, scale]],
+            ..Transform::identity()
+        }
+    }
+
+    /// The transform applying `other` first and then `self`
+    pub fn mul(&self, other: &Transform) -> Transform {
+        Transform {
+            linear: row_mat3_mul(self.linear, other.linear),
+            translation: self.apply(other.translation),
+        }
+    }
+
+    #[inline(always)]
+    pub fn apply(&self, r: Vec3) -> Vec3 {
+        vecmath::vec3_add(mat3xv3_mul(self.linear, r), self.translation)
+    }
+
+    pub fn inverse(&self) -> Transform {
+        let linear = vecmath::mat3_inv(self.linear);
+        Transform {
+            linear: linear,
+            translation: vecmath::vec3_scale(mat3xv3_mul(linear, self.translation), -1.0),
+        }
+    }
+
+    /// Rotation angles and scale that `rotation` and `scale` would build
+    /// the linear part from, assuming it has no shear and the same scale
+    /// along every axis
+    pub fn decompose(&self) -> (Vec3, f64) {
+        let m = self.linear;
+        let scale = vecmath::vec3_len(m[0]);
+        if scale < 1e-12 {
+            return ([0.0; 3], 0.0);
+        }
+        let r = |i: usize, j: usize| m[i][j] / scale;
+        // The rotation matrices turn by minus their angle
+        let y = r(0, 2).max(-1.0).min(1.0).asin();
+        let x = (-r(1, 2)).atan2(r(2, 2));
+        let z = (-r(0, 1)).atan2(r(0, 0));
+        ([-x, -y, -z], scale)
+    }
+
+    /// Blend towards `other` by `t` from 0 to 1, fine for the small
+    /// rotations between two ticks
+    pub fn lerp(&self, other: &Transform, t: f64) -> Transform {
+        let mix = |a: f64, b: f64| a + (b - a) * t;
+        let mut result = *self;
+        for i in 0..3 {
+            for j in 0..3 {
+                result.linear[i][j] = mix(self.linear[i][j], other.linear[i][j]);
+            }
+            result.translation[i] = mix(self.translation[i], other.translation[i]);
+        }
+        result
+    }
+}
@@ -1,11 +1,20 @@
+use assets::MeshData;
+use bounds::Aabb;
+use bounds::Chunk;
+use bounds::MeshBounds;
+use bounds::Sphere;
 use camera::Camera;
 use graphics::Graphics;
 use graphics::default_draw_state;
 use graphics::math::Matrix2d;
 use graphics;
 use lights::LightSource;
+use math::Transform;
 use math::mat_rotation;
 use math::vec3_rotate_around;
+use scene::Material;
+use std::cell::Ref;
+use std::collections::HashMap;
 use std::cell::RefCell;
 use std::rc::Rc;
 use types::Color;
@@ -27,7 +36,21 @@ pub struct Mesh {
     pub mesh: Rc<MeshContents>,
     pub r: Vec3,
     pub wireframe: bool,
+    /// Static meshes never move and can be baked into a BSP tree
+    pub static_geometry: bool,
+    /// Whether the mesh blocks light from the primary light
+    pub cast_shadows: bool,
+    /// Whether shadows of other meshes are drawn on this mesh
+    pub receive_shadows: bool,
     pub theta: Vec3,
+    /// Screen position and depth of every vertex for the current frame,
+    /// filled by `Mesh::project` and shared by all of the faces
+    pub projected: RefCell<Vec<[f64; 3]>>,
+    /// The loaded geometry this mesh is an instance of, held so the
+    /// asset manager knows it is in use
+    pub asset: Option<Rc<MeshData>>,
+    /// The material the mesh was drawn with, held for the same reason
+    pub material: Option<Rc<Material>>,
 }
 
 /// Contents of a 3D mesh
@@ -35,6 +58,16 @@ pub struct Mesh {
 pub struct MeshContents {
     pub faces: RefCell<Vec<Face>>,
     pub vertices: RefCell<Vec<Vertex>>,
+    /// Bounding volumes, `None` when the geometry changed since they
+    /// were last computed
+    pub bounds: RefCell<Option<MeshBounds>>,
+    /// Edges between faces, `None` when faces were added since they were
+    /// last found
+    pub edges: RefCell<Option<Vec<Edge>>>,
+    /// Vertex positions before the scene graph placed the mesh, `None`
+    /// until it first does. Shared with the other instances of a loaded
+    /// mesh, copied only if vertices are added.
+    pub model: RefCell<Option<Rc<Vec<Vec3>>>>,
 }
 
 /// A 3D Face
@@ -50,9 +83,38 @@ pub struct Face {
 pub struct Vertex {
     r: Vec3,
     faces: Vec<usize>,
+    /// Baked ambient occlusion, from 0 fully occluded to 1 fully open
+    occlusion: f32,
     mesh: Rc<MeshContents>,
 }
 
+/// An edge of a mesh and the one or two faces on either side
+#[derive(Debug,Clone,Copy)]
+pub struct Edge {
+    /// Ends of the edge in the order the first face goes around it
+    pub vertices: [usize; 2],
+    /// Faces on either side, both the same for an edge on the border of
+    /// an open mesh
+    pub faces: [usize; 2],
+    /// Whether the edge is between two faces
+    pub shared: bool,
+    /// Whether the two faces are wound the same way, so their normals
+    /// point out of the same side of the surface
+    pub consistent: bool,
+}
+
+/// How faces are lit
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Shading {
+    /// Light varies smoothly with the angle to the light
+    Smooth,
+    /// Light is quantized into this many flat bands, for a cel look
+    Toon(u32),
+}
+
+/// Meshes with more faces than this are split into chunks for culling
+const CHUNK_FACES: usize = 128;
+
 /// Cast light and shadows on faces
 #[derive(Debug,Clone)]
 pub struct Light {
@@ -86,11 +148,23 @@ impl Face {
         self
     }
 
+    pub fn get_color(&self) -> Color {
+        self.color
+    }
+
+    /// Whether the face lets faces behind it show through
+    pub fn is_translucent(&self) -> bool {
+        self.color[3] < 1.0
+    }
+
     pub fn distance(&self, r: Vec3) -> f64 {
+        vec3_square_len(vec3_sub(self.centroid(), r)).powf(0.5)
+    }
+
+    pub fn centroid(&self) -> Vec3 {
         let p = self.get_points();
         let total = vec3_add(p[0], vec3_add(p[1], p[2]));
-        let ave = vec3_scale(total, 1.0/3.0);
-        vec3_square_len(vec3_sub(ave, r)).powf(0.5)
+        vec3_scale(total, 1.0/3.0)
     }
 
     pub fn project(&self, camera: &Camera) -> graphics::types::Triangle {
@@ -102,6 +176,24 @@ impl Face {
         ]
     }
 
+    /// Project the face using vertex positions cached by `Mesh::project`
+    #[inline(always)]
+    pub fn project_cached(&self, projected: &[[f64; 3]]) -> graphics::types::Triangle {
+        let p = self.project_corners(projected);
+        [[p[0][0], p[0][1]], [p[1][0], p[1][1]], [p[2][0], p[2][1]]]
+    }
+
+    /// Screen position and depth of the corners, using vertex positions
+    /// cached by `Mesh::project`
+    #[inline(always)]
+    pub fn project_corners(&self, projected: &[[f64; 3]]) -> [[f64; 3]; 3] {
+        [
+            projected[self.vertices[0]],
+            projected[self.vertices[1]],
+            projected[self.vertices[2]],
+        ]
+    }
+
     pub fn project_lines(&self, camera: &Camera) -> [[f64; 4]; 3] {
         let p = self.project(camera);
         [
@@ -112,26 +204,51 @@ impl Face {
     }
 
     pub fn get_points(&self) -> [Vec3; 3] {
+        let vertices = self.mesh.vertices.borrow();
         [
-            self.mesh.vertices.borrow()[self.vertices[0]].r,
-            self.mesh.vertices.borrow()[self.vertices[1]].r,
-            self.mesh.vertices.borrow()[self.vertices[2]].r,
+            vertices[self.vertices[0]].r,
+            vertices[self.vertices[1]].r,
+            vertices[self.vertices[2]].r,
         ]
     }
 
+    /// Indices of the corners into the mesh's vertices
+    pub fn indices(&self) -> [usize; 3] {
+        self.vertices
+    }
+
+    /// Average baked ambient occlusion of the corners
+    pub fn occlusion(&self) -> f32 {
+        let vertices = self.mesh.vertices.borrow();
+        self.vertices.iter().fold(0.0, |sum, &v| sum + vertices[v].occlusion) / 3.0
+    }
+
+    /// Unit normal of the face's plane, pointing out of the side given
+    /// by the winding of its corners
     pub fn normal(&self) -> Vec3 {
         let points = self.get_points();
-        vec3_normalized(vec3_cross(points[1], points[2]))
+        vec3_normalized(vec3_cross(
+            vec3_sub(points[1], points[0]),
+            vec3_sub(points[2], points[0]),
+        ))
     }
 
-    pub fn shade(&self, lights: &Vec<LightSource>) -> Color {
+    pub fn shade(&self, lights: &Vec<LightSource>, shading: Shading) -> Color {
+        let light = &lights[0];
         let norm = self.normal();
-        let dot = vec3_dot(norm, vec3_normalized(lights[0].r)).abs();
-        let shade = (1.0 - dot * 0.4) as f32;
+        let dot = vec3_dot(norm, light.toward(self.centroid())).abs();
+        let dot = match shading {
+            Shading::Smooth => dot,
+            Shading::Toon(bands) => {
+                let bands = bands.max(1) as f64;
+                (dot * bands).ceil() / bands
+            },
+        };
+        let shade = (1.0 - dot * 0.4) as f32 * light.intensity * self.occlusion();
         [
-            self.color[0] * shade,
-            self.color[1] * shade,
-            self.color[2] * shade,
+            self.color[0] * shade * light.color[0] as f32,
+            self.color[1] * shade * light.color[1] as f32,
+            self.color[2] * shade * light.color[2] as f32,
             self.color[3],
         ]
     }
@@ -144,7 +261,19 @@ impl Face {
         g: &mut G
     ) where G: Graphics
     {
-        graphics::Polygon::new(self.shade(lights))
+        self.draw_colored(self.shade(lights, Shading::Smooth), camera, transform, g)
+    }
+
+    /// Draw the face filled with an already shaded color
+    pub fn draw_colored<G>(
+        &self,
+        color: Color,
+        camera: &Camera,
+        transform: Matrix2d,
+        g: &mut G
+    ) where G: Graphics
+    {
+        graphics::Polygon::new(color)
             .draw(&self.project(camera),
                   default_draw_state(),
                   transform,
@@ -168,6 +297,129 @@ impl Face {
 
 }
 
+// ======================================================================
+// Vertex
+
+impl Vertex {
+    pub fn position(&self) -> Vec3 {
+        self.r
+    }
+}
+
+// ======================================================================
+// MeshContents
+
+impl MeshContents {
+    /// Bounding volumes of the mesh, recomputed if the geometry changed
+    pub fn bounds(&self) -> Ref<MeshBounds> {
+        if self.bounds.borrow().is_none() {
+            let bounds = self.compute_bounds();
+            *self.bounds.borrow_mut() = Some(bounds);
+        }
+        Ref::map(self.bounds.borrow(), |b| b.as_ref().unwrap())
+    }
+
+    /// Edges between the faces of the mesh, found again if faces were
+    /// added
+    pub fn edges(&self) -> Ref<Vec<Edge>> {
+        if self.edges.borrow().is_none() {
+            let edges = self.find_edges();
+            *self.edges.borrow_mut() = Some(edges);
+        }
+        Ref::map(self.edges.borrow(), |e| e.as_ref().unwrap())
+    }
+
+    fn find_edges(&self) -> Vec<Edge> {
+        let mut edges: Vec<Edge> = vec![];
+        let mut index: HashMap<(usize, usize), usize> = HashMap::new();
+        for (i, face) in self.faces.borrow().iter().enumerate() {
+            let v = face.vertices;
+            for &(a, b) in [(v[0], v[1]), (v[1], v[2]), (v[2], v[0])].iter() {
+                let key = (a.min(b), a.max(b));
+                if let Some(&e) = index.get(&key) {
+                    let edge = &mut edges[e];
+                    // Faces past the second on a non-manifold edge are ignored
+                    if !edge.shared {
+                        edge.faces[1] = i;
+                        edge.shared = true;
+                        edge.consistent = a == edge.vertices[1];
+                    }
+                    continue;
+                }
+                index.insert(key, edges.len());
+                edges.push(Edge {
+                    vertices: [a, b],
+                    faces: [i, i],
+                    shared: false,
+                    consistent: true,
+                });
+            }
+        }
+        edges
+    }
+
+    /// Forget the bounding volumes after the geometry changed
+    fn invalidate_bounds(&self) {
+        *self.bounds.borrow_mut() = None;
+    }
+
+    fn compute_bounds(&self) -> MeshBounds {
+        let vertices_ref = self.vertices.borrow();
+        let faces_ref = self.faces.borrow();
+        let vertices: &Vec<Vertex> = &vertices_ref;
+        let faces: &Vec<Face> = &faces_ref;
+
+        let mut aabb = Aabb::empty();
+        for face in faces.iter() {
+            for &v in face.vertices.iter() {
+                aabb.grow(vertices[v].r);
+            }
+        }
+        let sphere = Sphere::around(&aabb, faces.iter().flat_map(move |face| {
+            face.vertices.iter().map(move |&v| vertices[v].r)
+        }));
+
+        // Lay a square grid over the x/z extent of large meshes and
+        // bucket each face by its centroid
+        let cells = ((faces.len() / CHUNK_FACES) as f64).sqrt().ceil().max(1.0) as usize;
+        let size = [
+            (aabb.max[0] - aabb.min[0]) / cells as f64,
+            (aabb.max[2] - aabb.min[2]) / cells as f64,
+        ];
+        let cell = |x: f64, min: f64, size: f64| -> usize {
+            if size > 0.0 {
+                (((x - min) / size) as usize).min(cells - 1)
+            } else {
+                0
+            }
+        };
+
+        let mut buckets: Vec<Vec<usize>> = vec![vec![]; cells * cells];
+        for (i, face) in faces.iter().enumerate() {
+            let p = face.get_points();
+            let x = (p[0][0] + p[1][0] + p[2][0]) / 3.0;
+            let z = (p[0][2] + p[1][2] + p[2][2]) / 3.0;
+            let (cx, cz) = (cell(x, aabb.min[0], size[0]), cell(z, aabb.min[2], size[1]));
+            buckets[cz * cells + cx].push(i);
+        }
+
+        let chunks = buckets.into_iter().filter(|b| !b.is_empty()).map(|b| {
+            let mut chunk_aabb = Aabb::empty();
+            for &i in b.iter() {
+                for &v in faces[i].vertices.iter() {
+                    chunk_aabb.grow(vertices[v].r);
+                }
+            }
+            let chunk_sphere = Sphere::around(&chunk_aabb, b.iter().flat_map(move |&i| {
+                faces[i].vertices.iter().map(move |&v| vertices[v].r)
+            }));
+            Chunk { faces: b, aabb: chunk_aabb, sphere: chunk_sphere }
+        }).collect();
+
+        MeshBounds { aabb: aabb, sphere: sphere, chunks: chunks }
+    }
+}
+
 // ======================================================================
 // Mesh
 
@@ -179,32 +431,103 @@ impl Mesh {
             mesh: Rc::new(MeshContents{
                 vertices: RefCell::new(vec![]),
                 faces: RefCell::new(vec![]),
+                bounds: RefCell::new(None),
+                edges: RefCell::new(None),
+                model: RefCell::new(None),
             }),
             wireframe: false,
+            static_geometry: false,
+            cast_shadows: true,
+            receive_shadows: true,
+            projected: RefCell::new(vec![]),
+            asset: None,
+            material: None,
         }
     }
 
-    fn add_vertex(&self, r: Vec3) -> usize {
+    /// Project every vertex onto the camera's screen once for this frame
+    pub fn project(&self, camera: &Camera) {
+        let mut projected = self.projected.borrow_mut();
+        projected.clear();
+        for vertex in self.mesh.vertices.borrow().iter() {
+            projected.push(camera.project(vertex.r));
+        }
+    }
+
+    /// Baked ambient occlusion of every vertex
+    pub fn occlusion(&self) -> Vec<f32> {
+        self.mesh.vertices.borrow().iter().map(|v| v.occlusion).collect()
+    }
+
+    /// Replace the baked ambient occlusion, one value per vertex
+    pub fn set_occlusion(&self, occlusion: &[f32]) {
+        for (vertex, &o) in self.mesh.vertices.borrow_mut().iter_mut().zip(occlusion.iter()) {
+            vertex.occlusion = o;
+        }
+    }
+
+    pub fn add_vertex(&self, r: Vec3) -> usize {
         self.mesh.vertices.borrow_mut().push(
             Vertex {
                 faces: vec![],
                 r: r,
+                occlusion: 1.0,
                 mesh: self.mesh.clone(),
             }
         );
+        if let Some(ref mut model) = *self.mesh.model.borrow_mut() {
+            Rc::make_mut(model).push(r);
+        }
+        self.mesh.invalidate_bounds();
         self.mesh.vertices.borrow().len() - 1
     }
 
     pub fn add_face(&self, face: Face) -> usize {
         self.mesh.faces.borrow_mut().push(face);
+        self.mesh.invalidate_bounds();
+        *self.mesh.edges.borrow_mut() = None;
         self.mesh.faces.borrow().len() - 1
     }
 
+    /// Add a face between three vertices
+    pub fn add_triangle(&self, a: usize, b: usize, c: usize, color: Color) -> usize {
+        self.add_face(Face::new(self.mesh.clone(), a, b, c).color(color))
+    }
+
+    /// Give every face the same color
+    pub fn set_color(&self, color: Color) {
+        for face in self.mesh.faces.borrow_mut().iter_mut() {
+            face.color = color;
+        }
+    }
+
+    /// Move the vertices to where `transform` puts their model space
+    /// positions. The first call takes the current positions as model
+    /// space, after that `translate` and `rotate` are overwritten the
+    /// next time the mesh is placed.
+    pub fn place(&mut self, transform: &Transform) {
+        let mut model = self.mesh.model.borrow_mut();
+        let mut vertices = self.mesh.vertices.borrow_mut();
+        if model.is_none() {
+            *model = Some(Rc::new(vertices.iter().map(|v| v.r).collect()));
+        }
+        if let Some(ref model) = *model {
+            for (vertex, &r) in vertices.iter_mut().zip(model.iter()) {
+                vertex.r = transform.apply(r);
+            }
+        }
+        self.r = transform.translation;
+        self.mesh.invalidate_bounds();
+    }
+
     pub fn translate(&mut self, r: Vec3) {
         self.r = vec3_add(r, self.r);
         for vertex in self.mesh.vertices.borrow_mut().iter_mut() {
             vertex.r = vec3_add(r, vertex.r);
         }
+        if let Some(ref mut bounds) = *self.mesh.bounds.borrow_mut() {
+            bounds.translate(r);
+        }
     }
 
     pub fn rotate(&mut self, theta: Vec3) {
@@ -213,12 +536,25 @@ impl Mesh {
         for vertex in self.mesh.vertices.borrow_mut().iter_mut() {
             vertex.r = vec3_rotate_around(vertex.r, rotation, self.r);
         }
+        self.mesh.invalidate_bounds();
     }
 
     pub fn wireframe(&mut self, wireframe: bool) {
         self.wireframe = wireframe;
     }
 
+    pub fn static_geometry(&mut self, static_geometry: bool) {
+        self.static_geometry = static_geometry;
+    }
+
+    pub fn cast_shadows(&mut self, cast_shadows: bool) {
+        self.cast_shadows = cast_shadows;
+    }
+
+    pub fn receive_shadows(&mut self, receive_shadows: bool) {
+        self.receive_shadows = receive_shadows;
+    }
+
     pub fn position(mut self, r: Vec3) -> Mesh {
         self.r = r;
         self
@@ -251,22 +587,23 @@ impl Mesh {
     }
 
     pub fn new_diamond(size: f64) -> Mesh {
-        let mut mesh = Mesh::new();
-        // let a = mesh.add_vertex(Vertex::new([-size,  0.0,  0.0]));
-        // let b = mesh.add_vertex(Vertex::new([  0.0,  0.0,  size]));
-        // let c = mesh.add_vertex(Vertex::new([ size,  0.0,  0.0]));
-        // let d = mesh.add_vertex(Vertex::new([  0.0,  0.0, -size]));
-        // let e = mesh.add_vertex(Vertex::new([  0.0,  size*2.0, 0.0]));
-        // let f = mesh.add_vertex(Vertex::new([  0.0, -size*2.0, 0.0]));
-        // let color = [0.1, 0.1, 0.9, 0.4];
-        // mesh.add_face(Face::new(a, e, b).color(color));
-        // mesh.add_face(Face::new(b, e, c).color(color));
-        // mesh.add_face(Face::new(c, e, d).color(color));
-        // mesh.add_face(Face::new(d, e, a).color(color));
-        // mesh.add_face(Face::new(a, f, b).color(color));
-        // mesh.add_face(Face::new(b, f, c).color(color));
-        // mesh.add_face(Face::new(c, f, d).color(color));
-        // mesh.add_face(Face::new(d, f, a).color(color));
+        let mesh = Mesh::new();
+        let a = mesh.add_vertex([-size,  0.0,  0.0]);
+        let b = mesh.add_vertex([  0.0,  0.0,  size]);
+        let c = mesh.add_vertex([ size,  0.0,  0.0]);
+        let d = mesh.add_vertex([  0.0,  0.0, -size]);
+        let e = mesh.add_vertex([  0.0,  size*2.0, 0.0]);
+        let f = mesh.add_vertex([  0.0, -size*2.0, 0.0]);
+        let color = [0.1, 0.1, 0.9, 0.4];
+        let m = &mesh.mesh;
+        mesh.add_face(Face::new(m.clone(), a, e, b).color(color));
+        mesh.add_face(Face::new(m.clone(), b, e, c).color(color));
+        mesh.add_face(Face::new(m.clone(), c, e, d).color(color));
+        mesh.add_face(Face::new(m.clone(), d, e, a).color(color));
+        mesh.add_face(Face::new(m.clone(), a, f, b).color(color));
+        mesh.add_face(Face::new(m.clone(), b, f, c).color(color));
+        mesh.add_face(Face::new(m.clone(), c, f, d).color(color));
+        mesh.add_face(Face::new(m.clone(), d, f, a).color(color));
         mesh
     }
 
@@ -0,0 +1,72 @@
+//! Loading meshes from Wavefront OBJ files
+//!
+//! Only vertex positions and faces are read, faces with more than three
+//! corners are split into a fan of triangles. OBJ files are y up while
+//! the world is y down, so y is flipped on the way in.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use mesh::Mesh;
+use types::Color;
+
+/// Color of faces until a material says otherwise
+const COLOR: Color = [0.6, 0.6, 0.6, 1.0];
+
+fn invalid(line: usize, message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, message))
+}
+
+/// Read a mesh from the text of an OBJ file
+pub fn parse(text: &str) -> io::Result<Mesh> {
+    let mesh = Mesh::new();
+    let mut vertices = 0;
+    for (n, line) in text.lines().enumerate() {
+        let n = n + 1;
+        let mut words = line.split('#').next().unwrap().split_whitespace();
+        match words.next() {
+            Some("v") => {
+                let r: Vec<f64> = try!(words.take(3).map(|w| {
+                    w.parse::<f64>().map_err(|_| invalid(n, format!("invalid number `{}`", w)))
+                }).collect());
+                if r.len() < 3 {
+                    return Err(invalid(n, "a vertex needs x, y and z".to_string()));
+                }
+                mesh.add_vertex([r[0], -r[1], r[2]]);
+                vertices += 1;
+            },
+            Some("f") => {
+                // Corners are `v`, `v/vt`, `v//vn` or `v/vt/vn`, counted
+                // from 1 or back from the last vertex when negative
+                let corners: Vec<usize> = try!(words.map(|w| {
+                    let v = w.split('/').next().unwrap();
+                    match v.parse::<i64>() {
+                        Ok(i) if i > 0 && i as usize <= vertices => Ok(i as usize - 1),
+                        Ok(i) if i < 0 && (-i) as usize <= vertices => {
+                            Ok(vertices - (-i) as usize)
+                        },
+                        _ => Err(invalid(n, format!("invalid vertex `{}`", w))),
+                    }
+                }).collect());
+                if corners.len() < 3 {
+                    return Err(invalid(n, "a face needs at least three vertices".to_string()));
+                }
+                // Flipping y turns the winding around
+                for i in 1..corners.len() - 1 {
+                    mesh.add_triangle(corners[0], corners[i + 1], corners[i], COLOR);
+                }
+            },
+            _ => {},
+        }
+    }
+    Ok(mesh)
+}
+
+/// Read a mesh from an OBJ file
+pub fn load<P>(path: P) -> io::Result<Mesh> where P: AsRef<Path> {
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+    parse(&text)
+}
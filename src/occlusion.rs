@@ -0,0 +1,343 @@
+//! Baked ambient occlusion
+//!
+//! Every vertex casts rays over the hemisphere above it and counts how
+//! many hit the mesh itself or other static geometry close by. Baking is
+//! slow, so the result is saved in a cache directory under a key made
+//! from the geometry and the settings, and loaded instead when nothing
+//! that would change it has changed.
+
+use std::f64::consts::PI;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+
+use math::ray_triangle;
+use mesh::Mesh;
+use types::Vec3;
+use vecmath::vec3_add;
+use vecmath::vec3_cross;
+use vecmath::vec3_dot;
+use vecmath::vec3_len;
+use vecmath::vec3_normalized;
+use vecmath::vec3_scale;
+use vecmath::vec3_sub;
+
+/// How far rays start off the surface so they don't hit it
+const RAY_OFFSET: f64 = 1e-3;
+
+/// First line of a saved occlusion file
+const HEADER: &'static str = "ambient-occlusion 2";
+
+/// FNV-1a, a small hash that stays the same between runs and builds
+struct Hasher(u64);
+
+impl Hasher {
+    fn new() -> Hasher {
+        Hasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, x: u64) {
+        for i in 0..8 {
+            self.0 ^= (x >> (i * 8)) & 0xff;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_mesh(&mut self, mesh: &Mesh) {
+        let vertices = mesh.mesh.vertices.borrow();
+        self.write(vertices.len() as u64);
+        for v in vertices.iter() {
+            for &x in v.position().iter() {
+                self.write(x.to_bits());
+            }
+        }
+        let faces = mesh.mesh.faces.borrow();
+        self.write(faces.len() as u64);
+        for face in faces.iter() {
+            for &i in face.indices().iter() {
+                self.write(i as u64);
+            }
+        }
+    }
+}
+
+/// Settings for baking ambient occlusion
+#[derive(Debug,Clone,Copy)]
+pub struct AmbientOcclusion {
+    /// Rays cast from each vertex
+    pub samples: usize,
+    /// Geometry further than this from a vertex doesn't occlude it
+    pub radius: f64,
+    /// How dark a fully occluded vertex gets, from 0 to 1
+    pub strength: f32,
+}
+
+/// Faces of one mesh that may block rays, with their corner indices
+struct Blocker {
+    triangles: Vec<([Vec3; 3], [usize; 3])>,
+    /// Faces, center and radius of each chunk of the mesh
+    chunks: Vec<(Vec<usize>, Vec3, f64)>,
+}
+
+impl Blocker {
+    fn new(mesh: &Mesh) -> Blocker {
+        let triangles = mesh.mesh.faces.borrow().iter().map(|face| {
+            (face.get_points(), face.indices())
+        }).collect();
+        let bounds = mesh.mesh.bounds();
+        let chunks = bounds.chunks.iter().map(|chunk| {
+            (chunk.faces.clone(), chunk.sphere.center, chunk.sphere.radius)
+        }).collect();
+        Blocker { triangles: triangles, chunks: chunks }
+    }
+}
+
+impl AmbientOcclusion {
+    pub fn new() -> AmbientOcclusion {
+        AmbientOcclusion {
+            samples: 32,
+            radius: 60.0,
+            strength: 0.8,
+        }
+    }
+
+    pub fn samples(mut self, samples: usize) -> AmbientOcclusion {
+        self.samples = samples;
+        self
+    }
+
+    pub fn radius(mut self, radius: f64) -> AmbientOcclusion {
+        self.radius = radius;
+        self
+    }
+
+    pub fn strength(mut self, strength: f32) -> AmbientOcclusion {
+        self.strength = strength;
+        self
+    }
+
+    /// Directions over the hemisphere around +z, spread on a spiral and
+    /// cosine weighted so rays near the surface count for less
+    fn directions(&self) -> Vec<Vec3> {
+        let golden = PI * (3.0 - 5.0f64.sqrt());
+        (0..self.samples).map(|i| {
+            let u = (i as f64 + 0.5) / self.samples as f64;
+            let r = u.sqrt();
+            let phi = i as f64 * golden;
+            [r * phi.cos(), r * phi.sin(), (1.0 - u).sqrt()]
+        }).collect()
+    }
+
+    /// Identifies what baking `mesh` with `others` would give, the same
+    /// only for the same settings and geometry
+    pub fn key(&self, mesh: &Mesh, others: &[&Mesh]) -> u64 {
+        let mut hasher = Hasher::new();
+        hasher.write(self.samples as u64);
+        hasher.write(self.radius.to_bits());
+        hasher.write(self.strength.to_bits() as u64);
+        hasher.write_mesh(mesh);
+        for other in others.iter().filter(|other| !same_mesh(other, mesh)) {
+            hasher.write_mesh(other);
+        }
+        hasher.0
+    }
+
+    /// Bake the occlusion of `mesh` by itself and by `others`, storing
+    /// it in the mesh's vertices
+    pub fn bake(&self, mesh: &Mesh, others: &[&Mesh]) {
+        let mut blockers = vec![Blocker::new(mesh)];
+        for other in others.iter() {
+            if !same_mesh(other, mesh) {
+                blockers.push(Blocker::new(other));
+            }
+        }
+
+        let positions: Vec<Vec3> = mesh.mesh.vertices.borrow().iter()
+            .map(|v| v.position())
+            .collect();
+
+        // Face normals may be wound either way, line them up with the
+        // first one seen at each vertex before adding them up
+        let mut normals = vec![[0.0; 3]; positions.len()];
+        for face in mesh.mesh.faces.borrow().iter() {
+            let normal = face.normal();
+            for &v in face.indices().iter() {
+                let normal = if vec3_dot(normals[v], normal) < 0.0 {
+                    vec3_scale(normal, -1.0)
+                } else {
+                    normal
+                };
+                normals[v] = vec3_add(normals[v], normal);
+            }
+        }
+
+        let directions = self.directions();
+        let occlusion: Vec<f32> = positions.iter().enumerate().map(|(v, &r)| {
+            if vec3_len(normals[v]) < 1e-9 {
+                return 1.0;
+            }
+            let open = self.openness(v, r, vec3_normalized(normals[v]), &directions, &blockers);
+            1.0 - self.strength * (1.0 - open)
+        }).collect();
+        mesh.set_occlusion(&occlusion);
+    }
+
+    /// Fraction of rays from vertex `v` that escape, on whichever side of
+    /// the surface is more open since that is the side it is seen from
+    fn openness(
+        &self,
+        v: usize,
+        r: Vec3,
+        normal: Vec3,
+        directions: &[Vec3],
+        blockers: &[Blocker]
+    ) -> f32 {
+        let helper = if normal[1].abs() < 0.9 { [0.0, 1.0, 0.0] } else { [1.0, 0.0, 0.0] };
+        let tangent = vec3_normalized(vec3_cross(helper, normal));
+        let bitangent = vec3_cross(normal, tangent);
+
+        // Only chunks within reach can block any of the rays
+        let mut nearby = vec![];
+        for (b, blocker) in blockers.iter().enumerate() {
+            for chunk in blocker.chunks.iter() {
+                if vec3_len(vec3_sub(chunk.1, r)) <= self.radius + chunk.2 {
+                    nearby.push((b, &chunk.0));
+                }
+            }
+        }
+
+        let mut best = 0;
+        for &side in [1.0, -1.0].iter() {
+            let n = vec3_scale(normal, side);
+            let origin = vec3_add(r, vec3_scale(n, RAY_OFFSET));
+            let mut open = 0;
+            for d in directions.iter() {
+                let dir = vec3_add(vec3_scale(n, d[2]), vec3_add(
+                    vec3_scale(tangent, d[0]),
+                    vec3_scale(bitangent, d[1] * side),
+                ));
+                let blocked = nearby.iter().any(|&(b, faces)| {
+                    faces.iter().any(|&f| {
+                        let (points, indices) = blockers[b].triangles[f];
+                        if b == 0 && indices.iter().any(|&i| i == v) {
+                            return false;
+                        }
+                        match ray_triangle(origin, dir, points) {
+                            Some(t) => t > 0.0 && t < self.radius,
+                            None => false,
+                        }
+                    })
+                });
+                if !blocked {
+                    open += 1;
+                }
+            }
+            best = best.max(open);
+        }
+        best as f32 / directions.len().max(1) as f32
+    }
+
+    /// Load the occlusion of `mesh` from `dir` if it was baked there
+    /// before from the same geometry and settings, otherwise bake it and
+    /// save it there. Returns whether it was baked.
+    pub fn bake_cached<P>(&self, mesh: &Mesh, others: &[&Mesh], dir: P) -> io::Result<bool>
+        where P: AsRef<Path>
+    {
+        let key = self.key(mesh, others);
+        let path = dir.as_ref().join(format!("{:016x}.ao", key));
+        if load(mesh, &path, key).is_ok() {
+            return Ok(false);
+        }
+        self.bake(mesh, others);
+        try!(fs::create_dir_all(&dir));
+        try!(save(mesh, &path, key));
+        Ok(true)
+    }
+}
+
+fn same_mesh(a: &Mesh, b: &Mesh) -> bool {
+    &*a.mesh as *const _ == &*b.mesh as *const _
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Save the baked occlusion of a mesh, one value per vertex, under the
+/// key it was baked with
+pub fn save<P>(mesh: &Mesh, path: P, key: u64) -> io::Result<()> where P: AsRef<Path> {
+    let occlusion = mesh.occlusion();
+    let mut file = try!(File::create(path));
+    try!(writeln!(file, "{}", HEADER));
+    try!(writeln!(file, "{:016x}", key));
+    try!(writeln!(file, "{}", occlusion.len()));
+    for o in occlusion.iter() {
+        try!(writeln!(file, "{}", o));
+    }
+    Ok(())
+}
+
+/// Load occlusion saved by `save` into a mesh, if it was saved under
+/// the same key
+pub fn load<P>(mesh: &Mesh, path: P, key: u64) -> io::Result<()> where P: AsRef<Path> {
+    let file = BufReader::new(try!(File::open(path)));
+    let mut lines = file.lines();
+
+    match lines.next() {
+        Some(line) => {
+            let line = try!(line);
+            if line.trim() != HEADER {
+                return Err(invalid(format!("unknown occlusion header {:?}", line)));
+            }
+        },
+        None => return Err(invalid("empty occlusion file".to_string())),
+    }
+
+    match lines.next() {
+        Some(line) => {
+            let line = try!(line);
+            if u64::from_str_radix(line.trim(), 16).ok() != Some(key) {
+                return Err(invalid(format!(
+                    "line 2: occlusion was baked from other geometry or settings ({})", line)));
+            }
+        },
+        None => return Err(invalid("missing key".to_string())),
+    }
+
+    let count = match lines.next() {
+        Some(line) => {
+            let line = try!(line);
+            try!(line.trim().parse::<usize>().map_err(|e| {
+                invalid(format!("line 3: bad vertex count {:?}: {}", line, e))
+            }))
+        },
+        None => return Err(invalid("missing vertex count".to_string())),
+    };
+    let vertices = mesh.mesh.vertices.borrow().len();
+    if count != vertices {
+        return Err(invalid(format!(
+            "occlusion is for {} vertices but the mesh has {}", count, vertices)));
+    }
+
+    let mut occlusion = Vec::with_capacity(count);
+    for (i, line) in lines.enumerate() {
+        let line = try!(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let o = try!(line.trim().parse::<f32>().map_err(|e| {
+            invalid(format!("line {}: bad occlusion {:?}: {}", i + 4, line, e))
+        }));
+        occlusion.push(o);
+    }
+    if occlusion.len() != count {
+        return Err(invalid(format!(
+            "expected {} occlusion values, found {}", count, occlusion.len())));
+    }
+    mesh.set_occlusion(&occlusion);
+    Ok(())
+}
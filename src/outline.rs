@@ -0,0 +1,135 @@
+//! Silhouette and crease outlines
+//!
+//! After the faces are drawn, edges where a mesh turns away from the
+//! camera or folds sharply are drawn over them as thin polygons, depth
+//! tested so hidden edges stay hidden. Only the software path draws
+//! them, the painter's path has no depth test and would draw the edges
+//! of hidden geometry over nearer faces.
+
+use camera::Camera;
+use fog::Fog;
+use render::Blend;
+use render::Canvas;
+use types::Color;
+use vecmath::vec3_add;
+use vecmath::vec3_dot;
+use vecmath::vec3_len;
+use vecmath::vec3_scale;
+use vecmath::vec3_sub;
+use world::WorldObject;
+
+/// How much closer than its faces an outline is drawn, so the depth
+/// test doesn't hide it behind them
+const OUTLINE_DEPTH_BIAS: f64 = 0.99;
+
+/// Settings for the outline pass
+#[derive(Debug,Clone,Copy)]
+pub struct Outlines {
+    pub color: Color,
+    /// Width of the lines in pixels
+    pub width: f64,
+    /// Edges whose faces meet at a larger angle than this, in radians,
+    /// are drawn as creases
+    pub crease_angle: f64,
+}
+
+impl Outlines {
+    pub fn new() -> Outlines {
+        Outlines {
+            color: [0.0, 0.0, 0.0, 1.0],
+            width: 1.5,
+            crease_angle: 0.8,
+        }
+    }
+
+    pub fn color(mut self, color: Color) -> Outlines {
+        self.color = color;
+        self
+    }
+
+    pub fn width(mut self, width: f64) -> Outlines {
+        self.width = width;
+        self
+    }
+
+    pub fn crease_angle(mut self, crease_angle: f64) -> Outlines {
+        self.crease_angle = crease_angle;
+        self
+    }
+
+    /// Draw the outlines of every mesh the camera can see
+    pub fn draw<C>(&self, objects: &[WorldObject], camera: &Camera, fog: &Fog, canvas: &mut C)
+        where C: Canvas
+    {
+        let frustum = camera.frustum();
+        let crease = self.crease_angle.cos();
+        let mut normals = vec![];
+        for object in objects.iter() {
+            for mesh in object.meshes.iter() {
+                {
+                    let bounds = mesh.mesh.bounds();
+                    if !frustum.visible(&bounds.aabb, &bounds.sphere) {
+                        continue;
+                    }
+                }
+                mesh.project(camera);
+                let projected = mesh.projected.borrow();
+                let vertices = mesh.mesh.vertices.borrow();
+                let faces = mesh.mesh.faces.borrow();
+                normals.clear();
+                for face in faces.iter() {
+                    normals.push(face.normal());
+                }
+
+                for edge in mesh.mesh.edges().iter() {
+                    let a = vertices[edge.vertices[0]].position();
+                    let b = vertices[edge.vertices[1]].position();
+                    let dist = vec3_len(vec3_sub(vec3_scale(vec3_add(a, b), 0.5), camera.r));
+                    if dist >= camera.far {
+                        continue;
+                    }
+
+                    if edge.shared {
+                        let n1 = normals[edge.faces[0]];
+                        let n2 = if edge.consistent {
+                            normals[edge.faces[1]]
+                        } else {
+                            vec3_scale(normals[edge.faces[1]], -1.0)
+                        };
+                        let eye = vec3_sub(camera.r, a);
+                        let silhouette = vec3_dot(n1, eye) * vec3_dot(n2, eye) < 0.0;
+                        if !silhouette && vec3_dot(n1, n2) >= crease {
+                            continue;
+                        }
+                    }
+
+                    let p = projected[edge.vertices[0]];
+                    let q = projected[edge.vertices[1]];
+                    self.line(p, q, fog.apply(self.color, dist), canvas);
+                }
+            }
+        }
+    }
+
+    /// Draw a line between two projected points as a thin quad
+    fn line<C>(&self, p: [f64; 3], q: [f64; 3], color: Color, canvas: &mut C)
+        where C: Canvas
+    {
+        if p[0].is_nan() || q[0].is_nan() {
+            return;
+        }
+        let (dx, dy) = (q[0] - p[0], q[1] - p[1]);
+        let len = (dx * dx + dy * dy).sqrt();
+        if len < 1e-9 {
+            return;
+        }
+        let (nx, ny) = (-dy / len * self.width / 2.0, dx / len * self.width / 2.0);
+        let (pz, qz) = (p[2] * OUTLINE_DEPTH_BIAS, q[2] * OUTLINE_DEPTH_BIAS);
+        canvas.polygon(&[
+            [p[0] + nx, p[1] + ny, pz],
+            [q[0] + nx, q[1] + ny, qz],
+            [q[0] - nx, q[1] - ny, qz],
+            [p[0] - nx, p[1] - ny, pz],
+        ], color, Blend::Alpha);
+    }
+}
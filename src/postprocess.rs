@@ -0,0 +1,203 @@
+//! Retro post-processing of software rendered frames
+//!
+//! Frames can be rendered at a fraction of the window resolution and
+//! scaled up with square pixels, and their colors snapped to a fixed
+//! palette with optional ordered dithering.
+
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::path::Path;
+
+use raster::Framebuffer;
+use types::Color;
+
+/// Bits per channel of the color lookup table
+const LOOKUP_BITS: usize = 5;
+
+/// 4x4 Bayer threshold matrix
+const BAYER: [[f32; 4]; 4] = [
+    [ 0.0,  8.0,  2.0, 10.0],
+    [12.0,  4.0, 14.0,  6.0],
+    [ 3.0, 11.0,  1.0,  9.0],
+    [15.0,  7.0, 13.0,  5.0],
+];
+
+/// A fixed set of colors frames are snapped to
+#[derive(Debug,Clone)]
+pub struct Palette {
+    pub colors: Vec<Color>,
+    /// Index of the nearest palette color for every color quantized to
+    /// `LOOKUP_BITS` per channel
+    lookup: Vec<u16>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parse `rrggbb` with or without a leading `#`
+fn parse_hex(s: &str) -> Option<Color> {
+    let s = s.trim_start_matches('#');
+    if s.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).ok().map(|c| c as f32 / 255.0);
+    match (channel(0), channel(2), channel(4)) {
+        (Some(r), Some(g), Some(b)) => Some([r, g, b, 1.0]),
+        _ => None,
+    }
+}
+
+/// Parse the `r g b` at the start of a GIMP palette line
+fn parse_gpl(s: &str) -> Option<Color> {
+    let mut channels = s.split_whitespace().map(|c| c.parse::<u8>().ok());
+    match (channels.next(), channels.next(), channels.next()) {
+        (Some(Some(r)), Some(Some(g)), Some(Some(b))) => {
+            Some([r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, 1.0])
+        },
+        _ => None,
+    }
+}
+
+impl Palette {
+    /// A palette of the given colors, panics if there are none or more
+    /// than 65535
+    pub fn new(colors: Vec<Color>) -> Palette {
+        assert!(!colors.is_empty(), "a palette needs at least one color");
+        assert!(colors.len() <= u16::max_value() as usize,
+                "a palette has at most {} colors", u16::max_value());
+        let mut palette = Palette { colors: colors, lookup: vec![] };
+        palette.build_lookup();
+        palette
+    }
+
+    /// Load a GIMP `.gpl` palette, or a file with one `#rrggbb` hex
+    /// color per line
+    pub fn load<P>(path: P) -> io::Result<Palette> where P: AsRef<Path> {
+        let file = BufReader::new(try!(File::open(path)));
+        let mut colors = vec![];
+        let mut gpl = false;
+        for (i, line) in file.lines().enumerate() {
+            let line = try!(line);
+            let line = line.trim();
+            if i == 0 && line == "GIMP Palette" {
+                gpl = true;
+                continue;
+            }
+            if line.is_empty() || line.starts_with("//") ||
+               (gpl && (line.starts_with('#') || line.contains(':'))) {
+                continue;
+            }
+            let color = if gpl { parse_gpl(line) } else { parse_hex(line) };
+            match color {
+                Some(color) => colors.push(color),
+                None => return Err(invalid(format!("line {}: bad color {:?}", i + 1, line))),
+            }
+        }
+        if colors.is_empty() {
+            return Err(invalid("palette has no colors".to_string()));
+        }
+        if colors.len() > u16::max_value() as usize {
+            return Err(invalid(format!("palette has too many colors, {}", colors.len())));
+        }
+        Ok(Palette::new(colors))
+    }
+
+    fn build_lookup(&mut self) {
+        let levels = 1 << LOOKUP_BITS;
+        let step = 1.0 / (levels - 1) as f32;
+        self.lookup.clear();
+        for r in 0..levels {
+            for g in 0..levels {
+                for b in 0..levels {
+                    let c = [r as f32 * step, g as f32 * step, b as f32 * step];
+                    self.lookup.push(self.search(c) as u16);
+                }
+            }
+        }
+    }
+
+    /// Index of the palette color closest to `c`
+    fn search(&self, c: [f32; 3]) -> usize {
+        let mut best = (0, ::std::f32::INFINITY);
+        for (i, p) in self.colors.iter().enumerate() {
+            let (dr, dg, db) = (p[0] - c[0], p[1] - c[1], p[2] - c[2]);
+            // Weighted toward green, which the eye is most sensitive to
+            let d = 0.3 * dr * dr + 0.59 * dg * dg + 0.11 * db * db;
+            if d < best.1 {
+                best = (i, d);
+            }
+        }
+        best.0
+    }
+
+    /// The palette color closest to `c`, keeping the alpha of `c`
+    #[inline(always)]
+    pub fn nearest(&self, c: Color) -> Color {
+        let max = ((1 << LOOKUP_BITS) - 1) as f32;
+        let q = |x: f32| (x.max(0.0).min(1.0) * max + 0.5) as usize;
+        let i = (q(c[0]) << (2 * LOOKUP_BITS)) | (q(c[1]) << LOOKUP_BITS) | q(c[2]);
+        let p = self.colors[self.lookup[i] as usize];
+        [p[0], p[1], p[2], c[3]]
+    }
+
+    /// How far apart the palette's colors are on average along one
+    /// channel, how far dithering spreads colors to mix neighbours
+    fn spread(&self) -> f32 {
+        1.0 / (self.colors.len() as f32).cbrt()
+    }
+}
+
+/// Post-processing applied to frames from the software render path
+#[derive(Debug,Clone)]
+pub struct PostProcess {
+    /// Snap colors to this palette
+    pub palette: Option<Palette>,
+    /// Whether to mix neighbouring palette colors with a Bayer pattern
+    pub dither: bool,
+    /// Size in window pixels of each rendered pixel, 1 for full resolution
+    pub pixel_size: usize,
+}
+
+impl PostProcess {
+    pub fn new() -> PostProcess {
+        PostProcess {
+            palette: None,
+            dither: false,
+            pixel_size: 1,
+        }
+    }
+
+    pub fn palette(mut self, palette: Palette) -> PostProcess {
+        self.palette = Some(palette);
+        self
+    }
+
+    pub fn dither(mut self, dither: bool) -> PostProcess {
+        self.dither = dither;
+        self
+    }
+
+    pub fn pixel_size(mut self, pixel_size: usize) -> PostProcess {
+        self.pixel_size = pixel_size.max(1);
+        self
+    }
+
+    /// Apply the palette to a finished frame
+    pub fn apply(&self, framebuffer: &mut Framebuffer) {
+        let palette = match self.palette {
+            Some(ref palette) => palette,
+            None => return,
+        };
+        let spread = if self.dither { palette.spread() } else { 0.0 };
+        let width = framebuffer.width;
+        for (i, c) in framebuffer.color.iter_mut().enumerate() {
+            let (x, y) = (i % width, i / width);
+            let t = (BAYER[y % 4][x % 4] + 0.5) / 16.0 - 0.5;
+            let d = t * spread;
+            *c = palette.nearest([c[0] + d, c[1] + d, c[2] + d, c[3]]);
+        }
+    }
+}
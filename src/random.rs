@@ -0,0 +1,55 @@
+//! Random numbers for gameplay
+//!
+//! A xorshift64* generator, small and fast, whose whole state is one
+//! number so save games can restore it and replay the same rolls.
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Any seed of 0 would get stuck at 0
+const DEFAULT_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random { state: if seed == 0 { DEFAULT_SEED } else { seed } }
+    }
+
+    /// Seeded from the clock, different every run
+    pub fn from_time() -> Random {
+        let seed = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() ^ ((d.subsec_nanos() as u64) << 32),
+            Err(_) => DEFAULT_SEED,
+        };
+        Random::new(seed)
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: u64) {
+        *self = Random::new(state);
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// From 0 up to but not including 1
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// From `low` up to but not including `high`
+    pub fn range(&mut self, low: f64, high: f64) -> f64 {
+        low + (high - low) * self.next_f64()
+    }
+}
@@ -0,0 +1,289 @@
+//! Software rasterizer with a depth buffer
+//!
+//! Polygons are filled pixel by pixel into a framebuffer in memory,
+//! which gets drawn to the window as runs of same colored rectangles.
+
+use std::f64::INFINITY;
+
+use graphics;
+use graphics::Graphics;
+use graphics::default_draw_state;
+use graphics::math::Matrix2d;
+use render::Blend;
+use render::Canvas;
+use shadow::ShadowMap;
+use types::Color;
+use types::Vec3;
+
+/// Color and depth of every pixel of a software rendered frame
+#[derive(Debug)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub color: Vec<Color>,
+    /// `1 / depth` of the nearest opaque surface at each pixel, 0 where
+    /// nothing opaque was drawn
+    pub depth: Vec<f32>,
+    /// Weighted sum of premultiplied translucent colors, for weighted
+    /// blended order independent transparency
+    accum: Vec<[f32; 4]>,
+    /// Product of `1 - alpha` of the translucent surfaces at each pixel
+    revealage: Vec<f32>,
+    /// Whether anything was accumulated since the last clear
+    oit: bool,
+}
+
+/// Signed area of the parallelogram spanned by `a -> b` and `a -> c`
+#[inline(always)]
+fn edge(a: [f64; 3], b: [f64; 3], c: [f64; 2]) -> f64 {
+    (c[0] - a[0]) * (b[1] - a[1]) - (c[1] - a[1]) * (b[0] - a[0])
+}
+
+/// Weight of a translucent fragment in weighted blended OIT, from
+/// McGuire and Bavoil's depth based weight function. Depths are scaled
+/// down to the range the constants were chosen for.
+#[inline(always)]
+fn oit_weight(z: f32, alpha: f32) -> f32 {
+    let z = z / 10.0;
+    let w = 10.0 / (1e-5 + (z / 5.0).powi(2) + (z / 200.0).powi(6));
+    alpha * w.max(1e-2).min(3e3)
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        let mut framebuffer = Framebuffer {
+            width: 0,
+            height: 0,
+            color: vec![],
+            depth: vec![],
+            accum: vec![],
+            revealage: vec![],
+            oit: false,
+        };
+        framebuffer.resize(width, height);
+        framebuffer
+    }
+
+    /// Change the resolution, the contents are undefined until cleared
+    pub fn resize(&mut self, width: usize, height: usize) {
+        if width == self.width && height == self.height {
+            return;
+        }
+        let n = width * height;
+        self.width = width;
+        self.height = height;
+        self.color.resize(n, [0.0; 4]);
+        self.depth.resize(n, 0.0);
+        self.accum.resize(n, [0.0; 4]);
+        self.revealage.resize(n, 1.0);
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        for c in self.color.iter_mut() {
+            *c = color;
+        }
+        for d in self.depth.iter_mut() {
+            *d = 0.0;
+        }
+        if self.oit {
+            for a in self.accum.iter_mut() {
+                *a = [0.0; 4];
+            }
+            for r in self.revealage.iter_mut() {
+                *r = 1.0;
+            }
+            self.oit = false;
+        }
+    }
+
+    /// Fill a triangle given in screen x, y and depth in front of the
+    /// camera. Pixels behind the opaque surface already drawn are
+    /// skipped, only opaque triangles write depth.
+    pub fn fill_triangle(&mut self, p: [[f64; 3]; 3], color: Color, blend: Blend) {
+        self.fill(p, blend, |_| color)
+    }
+
+    /// Fill a triangle colored `lit` where the primary light reaches the
+    /// world position under a pixel and `shadowed` where it doesn't,
+    /// `world` holds the world positions of the corners
+    pub fn fill_shadowed(
+        &mut self,
+        p: [[f64; 3]; 3],
+        world: [Vec3; 3],
+        lit: Color,
+        shadowed: Color,
+        blend: Blend,
+        shadow: &ShadowMap
+    ) {
+        self.fill(p, blend, |b| {
+            let mut r = [0.0; 3];
+            for i in 0..3 {
+                r[i] = b[0] * world[0][i] + b[1] * world[1][i] + b[2] * world[2][i];
+            }
+            let l = shadow.lit(r);
+            [
+                shadowed[0] + (lit[0] - shadowed[0]) * l,
+                shadowed[1] + (lit[1] - shadowed[1]) * l,
+                shadowed[2] + (lit[2] - shadowed[2]) * l,
+                lit[3],
+            ]
+        })
+    }
+
+    /// Fill a triangle, asking `color_at` for the color of each pixel
+    /// given the perspective correct barycentric coordinates of its center
+    fn fill<F>(&mut self, p: [[f64; 3]; 3], blend: Blend, mut color_at: F)
+        where F: FnMut([f64; 3]) -> Color
+    {
+        let area = edge(p[0], p[1], p[2]);
+        if area == 0.0 || area.is_nan() {
+            return;
+        }
+
+        let (mut min_x, mut min_y) = (INFINITY, INFINITY);
+        let (mut max_x, mut max_y) = (-INFINITY, -INFINITY);
+        for q in p.iter() {
+            min_x = min_x.min(q[0]);
+            min_y = min_y.min(q[1]);
+            max_x = max_x.max(q[0]);
+            max_y = max_y.max(q[1]);
+        }
+        let x0 = min_x.floor().max(0.0) as usize;
+        let y0 = min_y.floor().max(0.0) as usize;
+        let x1 = max_x.ceil().max(0.0).min(self.width as f64) as usize;
+        let y1 = max_y.ceil().max(0.0).min(self.height as f64) as usize;
+
+        // 1/z interpolates linearly in screen space
+        let inv_z = [1.0 / p[0][2], 1.0 / p[1][2], 1.0 / p[2][2]];
+
+        if blend == Blend::WeightedOit {
+            self.oit = true;
+        }
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let s = [x as f64 + 0.5, y as f64 + 0.5];
+                let w0 = edge(p[1], p[2], s) / area;
+                let w1 = edge(p[2], p[0], s) / area;
+                let w2 = edge(p[0], p[1], s) / area;
+                if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                    continue;
+                }
+
+                let iz64 = w0 * inv_z[0] + w1 * inv_z[1] + w2 * inv_z[2];
+                let iz = iz64 as f32;
+                let i = y * self.width + x;
+                if iz <= self.depth[i] {
+                    continue;
+                }
+                let color = color_at([
+                    w0 * inv_z[0] / iz64,
+                    w1 * inv_z[1] / iz64,
+                    w2 * inv_z[2] / iz64,
+                ]);
+
+                match blend {
+                    Blend::Opaque => {
+                        self.color[i] = color;
+                        self.depth[i] = iz;
+                    },
+                    Blend::Alpha => {
+                        let a = color[3];
+                        let dst = self.color[i];
+                        self.color[i] = [
+                            color[0] * a + dst[0] * (1.0 - a),
+                            color[1] * a + dst[1] * (1.0 - a),
+                            color[2] * a + dst[2] * (1.0 - a),
+                            dst[3],
+                        ];
+                    },
+                    Blend::WeightedOit => {
+                        let a = color[3];
+                        let w = oit_weight(1.0 / iz, a);
+                        let acc = &mut self.accum[i];
+                        acc[0] += color[0] * a * w;
+                        acc[1] += color[1] * a * w;
+                        acc[2] += color[2] * a * w;
+                        acc[3] += a * w;
+                        self.revealage[i] *= 1.0 - a;
+                    },
+                }
+            }
+        }
+    }
+
+    /// Composite accumulated order independent transparency over the
+    /// opaque image
+    pub fn resolve(&mut self) {
+        if !self.oit {
+            return;
+        }
+        for i in 0..self.color.len() {
+            let r = self.revealage[i];
+            if r >= 1.0 {
+                continue;
+            }
+            let acc = self.accum[i];
+            let total = acc[3].max(1e-5);
+            let dst = self.color[i];
+            self.color[i] = [
+                acc[0] / total * (1.0 - r) + dst[0] * r,
+                acc[1] / total * (1.0 - r) + dst[1] * r,
+                acc[2] / total * (1.0 - r) + dst[2] * r,
+                dst[3],
+            ];
+        }
+    }
+
+    /// Draw the frame stretched over `rect`, one rectangle per run of
+    /// same colored pixels in a row
+    pub fn draw<G>(&self, rect: [f64; 4], transform: Matrix2d, g: &mut G)
+        where G: Graphics
+    {
+        let px = rect[2] / self.width as f64;
+        let py = rect[3] / self.height as f64;
+        let ds = default_draw_state();
+        for y in 0..self.height {
+            let row = &self.color[y * self.width..(y + 1) * self.width];
+            let mut x0 = 0;
+            while x0 < row.len() {
+                let color = row[x0];
+                let mut x1 = x0 + 1;
+                while x1 < row.len() && row[x1] == color {
+                    x1 += 1;
+                }
+                graphics::Rectangle::new(color).draw(
+                    [rect[0] + x0 as f64 * px, rect[1] + y as f64 * py,
+                     (x1 - x0) as f64 * px, py],
+                    ds, transform, g);
+                x0 = x1;
+            }
+        }
+    }
+}
+
+impl Canvas for Framebuffer {
+    /// Fill a convex polygon as a fan of triangles
+    fn polygon(&mut self, points: &[[f64; 3]], color: Color, blend: Blend) {
+        for i in 1..points.len().saturating_sub(1) {
+            self.fill_triangle([points[0], points[i], points[i + 1]], color, blend);
+        }
+    }
+
+    fn shadowed_polygon(
+        &mut self,
+        points: &[[f64; 3]],
+        world: &[Vec3],
+        lit: Color,
+        shadowed: Color,
+        blend: Blend,
+        shadow: &ShadowMap
+    ) {
+        for i in 1..points.len().saturating_sub(1) {
+            self.fill_shadowed(
+                [points[0], points[i], points[i + 1]],
+                [world[0], world[i], world[i + 1]],
+                lit, shadowed, blend, shadow);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Reloading a scene while the game runs
+//!
+//! The watcher polls the modification times of a scene file and the OBJ
+//! files its meshes come from. When the scene changes it is read again
+//! and the world brought in line with it: objects are matched by name,
+//! or by order among the unnamed ones, and keep their handles,
+//! components and behaviours while their transforms, parents and meshes
+//! are updated. The camera stays where it is. A scene that fails to load
+//! is reported and the world is left as it was.
+//!
+//! Add the watcher with `World::frame_system` rather than as a tick
+//! system, so files are still watched while the game is paused.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use clock;
+use ecs::System;
+use scene;
+use scene::MeshSource;
+use scene::Scene;
+use scene::SceneObject;
+use scene::SceneSource;
+use world::Handle;
+use world::World;
+
+/// Real seconds between checks of the files
+const INTERVAL: f64 = 0.5;
+
+/// How an object in the old scene is found in the new one
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+enum Key {
+    Named(String),
+    /// The nth unnamed object
+    Unnamed(usize),
+}
+
+fn keys(scene: &Scene) -> Vec<Key> {
+    let mut unnamed = 0;
+    scene.objects.iter().map(|object| match object.name {
+        Some(ref name) => Key::Named(name.clone()),
+        None => {
+            unnamed += 1;
+            Key::Unnamed(unnamed - 1)
+        },
+    }).collect()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Files an OBJ mesh of the object is read from
+fn asset(scene: &Scene, object: &SceneObject) -> Option<PathBuf> {
+    match object.mesh {
+        Some(MeshSource::Obj { ref path }) => Some(scene.dir.join(path)),
+        _ => None,
+    }
+}
+
+/// Keeps a world in line with a scene file, a system
+pub struct SceneWatcher {
+    path: PathBuf,
+    /// The last scene that loaded
+    scene: Scene,
+    /// The object built for each object of the scene
+    handles: Vec<Handle>,
+    /// Modification times of the scene and its assets when last checked
+    times: HashMap<PathBuf, Option<SystemTime>>,
+    last_check: Instant,
+}
+
+impl SceneWatcher {
+    /// Watch the file `scene` was loaded from, `handles` are the objects
+    /// `Scene::apply` returned for it
+    pub fn new<P>(path: P, scene: Scene, handles: Vec<Handle>) -> SceneWatcher
+        where P: AsRef<Path>
+    {
+        let mut watcher = SceneWatcher {
+            path: path.as_ref().to_path_buf(),
+            scene: scene,
+            handles: handles,
+            times: HashMap::new(),
+            last_check: Instant::now(),
+        };
+        watcher.times = watcher.current_times();
+        watcher
+    }
+
+    fn current_times(&self) -> HashMap<PathBuf, Option<SystemTime>> {
+        let mut times = HashMap::new();
+        times.insert(self.path.clone(), modified(&self.path));
+        for object in self.scene.objects.iter() {
+            if let Some(path) = asset(&self.scene, object) {
+                let time = modified(&path);
+                times.insert(path, time);
+            }
+        }
+        times
+    }
+
+    /// Check the files now, reloading whatever changed. Returns whether
+    /// anything was reloaded.
+    pub fn check(&mut self, world: &mut World) -> io::Result<bool> {
+        let times = self.current_times();
+        let changed: HashSet<PathBuf> = times.iter()
+            .filter(|&(path, time)| self.times.get(path) != Some(time))
+            .map(|(path, _)| path.clone())
+            .collect();
+        // Errors are only reported once, until the files change again
+        self.times = times;
+        if changed.is_empty() {
+            return Ok(false);
+        }
+
+        // Changed meshes are loaded again rather than taken from the cache
+        for path in changed.iter() {
+            world.assets.forget(&path.to_string_lossy());
+        }
+
+        let scene = if changed.contains(&self.path) {
+            try!(scene::load(&self.path))
+        } else {
+            self.scene.clone()
+        };
+        let result = self.reconcile(world, scene, &changed);
+        self.times = self.current_times();
+        result.map(|_| true)
+    }
+
+    /// Bring the world in line with `scene`, rebuilding meshes whose
+    /// source changed or whose file is in `changed`
+    fn reconcile(&mut self, world: &mut World, scene: Scene, changed: &HashSet<PathBuf>)
+                 -> io::Result<()>
+    {
+        if let Some(ref camera) = scene.camera {
+            world.camera.far = camera.far;
+        }
+        if let Some(fog) = scene.fog {
+            world.renderer.fog = fog;
+        }
+        if !scene.lights.is_empty() {
+            world.lights = scene.lights.clone();
+        }
+        if let Some(ref sky) = scene.sky {
+            let mut sky = sky.clone();
+            if let Some(ref old) = world.sky {
+                sky.time = old.time;
+                sky.paused = old.paused;
+            }
+            world.sky = Some(sky);
+        }
+        world.apply_sky();
+
+        let mut old: HashMap<Key, (Handle, usize)> = keys(&self.scene).into_iter()
+            .zip(self.handles.iter().cloned().enumerate())
+            .map(|(key, (i, handle))| (key, (handle, i)))
+            .collect();
+        let mut handles = vec![];
+        let mut named: HashMap<String, Handle> = HashMap::new();
+        let mut errors = vec![];
+
+        for (object, key) in scene.objects.iter().zip(keys(&scene).into_iter()) {
+            let parent = object.parent.as_ref().and_then(|p| named.get(p).cloned());
+            let kept = match old.remove(&key) {
+                Some((handle, i)) if world.get(handle).is_some() => Some((handle, i)),
+                _ => None,
+            };
+            let source = scene.source(object);
+
+            let handle = match kept {
+                Some((handle, i)) => {
+                    // Objects moved by gameplay stay put unless the file
+                    // moves them
+                    let moved = self.scene.objects[i].transform() != object.transform();
+                    if world.get(handle).unwrap().parent() != parent {
+                        world.reparent(handle, parent);
+                        world.set_transform(handle, object.transform());
+                    } else if moved {
+                        world.set_transform(handle, object.transform());
+                    }
+                    world.get_mut(handle).unwrap().tags = object.tags.clone();
+
+                    let rebuild = world.components.get::<SceneSource>(handle) != Some(&source) ||
+                        asset(&scene, object).map_or(false, |path| changed.contains(&path));
+                    if rebuild {
+                        match scene.build_object(object, &mut world.assets) {
+                            Ok(built) => {
+                                world.get_mut(handle).unwrap().meshes = built.meshes;
+                                // Placed by the next transform update
+                                let local = *world.get(handle).unwrap().local();
+                                world.set_transform(handle, local);
+                                world.components.insert(handle, source);
+                            },
+                            // The old mesh stays until the file is fixed
+                            Err(e) => errors.push(e.to_string()),
+                        }
+                    }
+                    handle
+                },
+                None => {
+                    let (built, source) = match scene.build_object(object, &mut world.assets) {
+                        Ok(built) => (built, source),
+                        Err(e) => {
+                            errors.push(e.to_string());
+                            let meshless = SceneObject { mesh: None, ..object.clone() };
+                            (scene.build_object(&meshless, &mut world.assets).unwrap(),
+                             SceneSource { mesh: None, material: object.material })
+                        },
+                    };
+                    let handle = match parent {
+                        Some(parent) => world.add_child(parent, built).unwrap(),
+                        None => world.object(built),
+                    };
+                    world.components.insert(handle, source);
+                    handle
+                },
+            };
+            if let Some(ref name) = object.name {
+                named.insert(name.clone(), handle);
+            }
+            handles.push(handle);
+        }
+
+        // Objects no longer in the scene, their kept children were
+        // moved to their new parents above
+        for (_, (handle, _)) in old.into_iter() {
+            world.remove(handle);
+        }
+        world.rebuild_bsp();
+
+        self.scene = scene;
+        self.handles = handles;
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, errors.join("\n")))
+        }
+    }
+}
+
+impl System for SceneWatcher {
+    fn run(&mut self, world: &mut World, _dt: f64) {
+        // Real time, so files are checked as often however fast the game
+        // runs
+        if clock::seconds(self.last_check.elapsed()) < INTERVAL {
+            return;
+        }
+        self.last_check = Instant::now();
+        match self.check(world) {
+            Ok(true) => info!("Reloaded {}", self.path.display()),
+            Ok(false) => {},
+            Err(e) => warn!("Could not reload {}: {}", self.path.display(), e),
+        }
+    }
+}
@@ -0,0 +1,514 @@
+//! Turning the faces of a world into shaded polygons on a canvas
+//!
+//! The renderer culls, shades and orders faces and hands them to a
+//! `Canvas`, either the window drawing with the painter's algorithm or a
+//! software framebuffer with a depth buffer.
+
+use std::cell::RefCell;
+
+use bsp::BspPolygon;
+use bsp::BspTree;
+use camera::Camera;
+use depth::DepthQueue;
+use depth::FaceRef;
+use fog::Fog;
+use graphics;
+use graphics::Graphics;
+use graphics::default_draw_state;
+use graphics::math::Matrix2d;
+use lights::LightSource;
+use mesh::Mesh;
+use mesh::Shading;
+use outline::Outlines;
+use postprocess::PostProcess;
+use shadow::ShadowMap;
+use shadow::planar_shadows;
+use types::Color;
+use types::Vec3;
+use vecmath::vec3_add;
+use vecmath::vec3_len;
+use vecmath::vec3_scale;
+use vecmath::vec3_sub;
+use world::WorldObject;
+
+/// Width and height of the shadow map in texels
+const SHADOW_MAP_SIZE: usize = 1024;
+
+/// How much closer flattened shadows sort than the face they lie on
+const SHADOW_SORT_BIAS: f64 = 1.0;
+
+/// Where frames are drawn
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum RenderPath {
+    /// Draw faces back to front straight to the window
+    Painter,
+    /// Rasterize faces into a framebuffer with a depth buffer, then
+    /// draw the framebuffer to the window
+    Software,
+}
+
+/// How faces are put in back to front order for the painter's path
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum FaceOrder {
+    /// Sort faces by the distance to their centroid, fast but wrong for
+    /// long or intersecting faces
+    Sorted,
+    /// Walk a BSP tree of the static meshes with the dynamic ones
+    /// inserted every frame, exact for the static meshes from any camera
+    /// position but skipping frustum culling of them
+    Bsp,
+}
+
+/// How translucent faces are combined in the software path
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Transparency {
+    /// Blend translucent faces back to front over the opaque ones
+    Sorted,
+    /// Weighted blended order independent transparency, no sorting but
+    /// only an approximation where translucent faces overlap
+    WeightedOit,
+}
+
+/// How a polygon combines with what is already on the canvas
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Blend {
+    Opaque,
+    Alpha,
+    WeightedOit,
+}
+
+/// Something the renderer can draw polygons on
+pub trait Canvas {
+    /// Fill a convex polygon, the points are screen x, y and depth in
+    /// front of the camera
+    fn polygon(&mut self, points: &[[f64; 3]], color: Color, blend: Blend);
+
+    /// Fill a convex polygon colored `lit` where the primary light reaches
+    /// it and `shadowed` where the shadow map says it is blocked, `world`
+    /// holds the world positions of the points. Canvases that can't look
+    /// up shadows per pixel draw the polygon fully lit.
+    fn shadowed_polygon(
+        &mut self,
+        points: &[[f64; 3]],
+        _world: &[Vec3],
+        lit: Color,
+        _shadowed: Color,
+        blend: Blend,
+        _shadow: &ShadowMap
+    ) {
+        self.polygon(points, lit, blend)
+    }
+}
+
+/// Canvas drawing straight to a piston graphics backend, depth is
+/// ignored so polygons must arrive back to front
+pub struct GlCanvas<'a, G: 'a> {
+    g: &'a mut G,
+    transform: Matrix2d,
+    /// Left, top, width and height polygons are clipped to
+    clip: Option<[f64; 4]>,
+    points: Vec<[f64; 2]>,
+    scratch: Vec<[f64; 2]>,
+}
+
+impl<'a, G> GlCanvas<'a, G> where G: Graphics {
+    pub fn new(g: &'a mut G, transform: Matrix2d) -> GlCanvas<'a, G> {
+        GlCanvas { g: g, transform: transform, clip: None, points: vec![], scratch: vec![] }
+    }
+
+    /// Only draw inside a rectangle of left, top, width and height
+    pub fn clip(mut self, rect: [f64; 4]) -> GlCanvas<'a, G> {
+        self.clip = Some(rect);
+        self
+    }
+}
+
+/// Clip a convex polygon to one side of a line, `inside` gives the
+/// signed distance of a point inside the line
+fn clip_edge<F>(points: &[[f64; 2]], out: &mut Vec<[f64; 2]>, inside: F)
+    where F: Fn([f64; 2]) -> f64
+{
+    out.clear();
+    for i in 0..points.len() {
+        let a = points[i];
+        let b = points[(i + 1) % points.len()];
+        let (da, db) = (inside(a), inside(b));
+        if da >= 0.0 {
+            out.push(a);
+        }
+        if (da >= 0.0) != (db >= 0.0) {
+            let t = da / (da - db);
+            out.push([a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t]);
+        }
+    }
+}
+
+/// Sutherland-Hodgman clipping of a convex polygon to a rectangle
+fn clip_polygon(points: &mut Vec<[f64; 2]>, scratch: &mut Vec<[f64; 2]>, rect: [f64; 4]) {
+    let (x0, y0, x1, y1) = (rect[0], rect[1], rect[0] + rect[2], rect[1] + rect[3]);
+    clip_edge(points, scratch, |p| p[0] - x0);
+    clip_edge(scratch, points, |p| x1 - p[0]);
+    clip_edge(points, scratch, |p| p[1] - y0);
+    clip_edge(scratch, points, |p| y1 - p[1]);
+}
+
+impl<'a, G> Canvas for GlCanvas<'a, G> where G: Graphics {
+    fn polygon(&mut self, points: &[[f64; 3]], color: Color, _blend: Blend) {
+        self.points.clear();
+        for p in points.iter() {
+            self.points.push([p[0], p[1]]);
+        }
+        if let Some(rect) = self.clip {
+            clip_polygon(&mut self.points, &mut self.scratch, rect);
+            if self.points.len() < 3 {
+                return;
+            }
+        }
+        graphics::Polygon::new(color)
+            .draw(&self.points, default_draw_state(), self.transform, &mut *self.g);
+    }
+}
+
+/// Something queued for drawing in the painter's order
+#[derive(Debug,Clone,Copy)]
+pub enum DrawItem {
+    Face(FaceRef),
+    /// Index of a flattened shadow in `Renderer::planar_shadows`
+    Shadow(usize),
+}
+
+/// Settings and reusable buffers for drawing a world
+pub struct Renderer {
+    pub path: RenderPath,
+    pub face_order: FaceOrder,
+    pub transparency: Transparency,
+    pub fog: Fog,
+    /// Whether meshes cast shadows from the primary light
+    pub shadows: bool,
+    /// How much of the light a shadow takes away, from 0 to 1
+    pub shadow_darkness: f32,
+    /// BSP tree of the static meshes, built on the first frame drawn
+    /// with `FaceOrder::Bsp`
+    pub bsp: Option<BspTree>,
+    pub shading: Shading,
+    /// Silhouette and crease lines drawn over the faces, in the
+    /// software path only
+    pub outlines: Option<Outlines>,
+    /// Palette, dithering and pixelation of software rendered frames
+    pub post: PostProcess,
+    /// Created the first time shadows are drawn in the software path
+    shadow_map: Option<ShadowMap>,
+    planar_shadows: Vec<BspPolygon>,
+    /// Opaque faces and shadows, and translucent faces too in the
+    /// painter's path
+    opaque: DepthQueue<DrawItem>,
+    /// Translucent faces of the software path
+    translucent: DepthQueue<DrawItem>,
+    points: RefCell<Vec<[f64; 3]>>,
+}
+
+/// Everything needed to shade faces in one frame
+struct Frame<'a> {
+    objects: &'a [WorldObject],
+    camera: &'a Camera,
+    lights: &'a Vec<LightSource>,
+    fog: &'a Fog,
+    /// Shadow map to check pixels against in the software path
+    shadow_map: Option<&'a ShadowMap>,
+    shadow_darkness: f32,
+    shading: Shading,
+}
+
+/// Look up the mesh a `FaceRef` belongs to
+fn face_mesh<'a>(objects: &'a [WorldObject], face: FaceRef) -> &'a Mesh {
+    &objects[face.object as usize].meshes[face.mesh as usize]
+}
+
+fn is_translucent(objects: &[WorldObject], face: FaceRef) -> bool {
+    face_mesh(objects, face).mesh.faces.borrow()[face.face as usize].is_translucent()
+}
+
+/// Build a BSP tree out of the faces of every static mesh
+fn build_bsp(objects: &[WorldObject]) -> BspTree {
+    let mut polygons = vec![];
+    for (o, object) in objects.iter().enumerate() {
+        for (m, mesh) in object.meshes.iter().enumerate() {
+            if !mesh.static_geometry {
+                continue;
+            }
+            for (i, face) in mesh.mesh.faces.borrow().iter().enumerate() {
+                polygons.push(BspPolygon {
+                    points: face.get_points().to_vec(),
+                    face: FaceRef { object: o as u32, mesh: m as u32, face: i as u32 },
+                    shadow: false,
+                });
+            }
+        }
+    }
+    BspTree::new(polygons)
+}
+
+/// Scale the color of a face down, leaving alpha alone
+#[inline(always)]
+fn darken(color: Color, amount: f32) -> Color {
+    let k = 1.0 - amount;
+    [color[0] * k, color[1] * k, color[2] * k, color[3]]
+}
+
+/// Distance from `r` to the center of a polygon
+fn polygon_distance(points: &[Vec3], r: Vec3) -> f64 {
+    let mut center = [0.0; 3];
+    for &p in points.iter() {
+        center = vec3_add(center, p);
+    }
+    let center = vec3_scale(center, 1.0 / points.len() as f64);
+    vec3_len(vec3_sub(center, r))
+}
+
+impl Renderer {
+    pub fn new(fog: Fog) -> Renderer {
+        Renderer {
+            path: RenderPath::Painter,
+            face_order: FaceOrder::Sorted,
+            transparency: Transparency::Sorted,
+            fog: fog,
+            shadows: false,
+            shadow_darkness: 0.5,
+            bsp: None,
+            shading: Shading::Smooth,
+            outlines: None,
+            post: PostProcess::new(),
+            shadow_map: None,
+            planar_shadows: vec![],
+            opaque: DepthQueue::new(),
+            translucent: DepthQueue::new(),
+            points: RefCell::new(vec![]),
+        }
+    }
+
+    /// Draw the objects as seen by the camera
+    pub fn render<C>(
+        &mut self,
+        objects: &[WorldObject],
+        camera: &Camera,
+        lights: &Vec<LightSource>,
+        canvas: &mut C
+    ) where C: Canvas
+    {
+        let use_bsp = self.path == RenderPath::Painter && self.face_order == FaceOrder::Bsp;
+        if use_bsp && self.bsp.is_none() {
+            self.bsp = Some(build_bsp(objects));
+        }
+
+        // The software path looks shadows up per pixel, the painter's
+        // path draws flattened shadows along with the faces
+        let shadows = self.shadows && !lights.is_empty();
+        let software_shadows = shadows && self.path == RenderPath::Software;
+        if software_shadows {
+            let shadow_map = self.shadow_map.get_or_insert_with(|| ShadowMap::new(SHADOW_MAP_SIZE));
+            shadow_map.render(objects, &lights[0]);
+        }
+        if shadows && self.path == RenderPath::Painter {
+            planar_shadows(objects, &lights[0], &mut self.planar_shadows);
+        } else {
+            self.planar_shadows.clear();
+        }
+
+        self.queue(objects, camera, use_bsp);
+
+        let frame = Frame {
+            objects: objects,
+            camera: camera,
+            lights: lights,
+            fog: &self.fog,
+            shadow_map: if software_shadows { self.shadow_map.as_ref() } else { None },
+            shadow_darkness: self.shadow_darkness,
+            shading: self.shading,
+        };
+
+        if use_bsp {
+            if let Some(ref tree) = self.bsp {
+                draw_bsp(&frame, tree, &mut self.points.borrow_mut(), canvas);
+            }
+        } else {
+            self.draw_queues(&frame, canvas);
+        }
+
+        // Outlines need the depth test to hide the edges of hidden faces
+        if let (Some(ref outlines), RenderPath::Software) = (self.outlines, self.path) {
+            outlines.draw(objects, camera, &self.fog, canvas);
+        }
+    }
+
+    /// Draw the sorted queues. The software path draws opaque faces first
+    /// and translucent ones over them, the painter's path has no depth
+    /// test so everything is in the one back to front queue.
+    fn draw_queues<C>(&self, frame: &Frame, canvas: &mut C) where C: Canvas {
+        let translucent_blend = match (self.path, self.transparency) {
+            (RenderPath::Software, Transparency::WeightedOit) => Blend::WeightedOit,
+            _ => Blend::Alpha,
+        };
+        for (item, dist) in self.opaque.iter() {
+            let blend = match item {
+                DrawItem::Face(face_ref) if is_translucent(frame.objects, face_ref) => Blend::Alpha,
+                _ => Blend::Opaque,
+            };
+            self.draw_item(frame, item, dist, blend, canvas);
+        }
+        for (item, dist) in self.translucent.iter() {
+            self.draw_item(frame, item, dist, translucent_blend, canvas);
+        }
+    }
+
+    /// Collect the faces the camera might see, skipping meshes and chunks
+    /// of meshes outside of the frustum. With a BSP tree the static
+    /// meshes are already in the tree and the dynamic ones are inserted.
+    fn queue(&mut self, objects: &[WorldObject], camera: &Camera, use_bsp: bool) {
+        let frustum = camera.frustum();
+        self.opaque.clear();
+        self.translucent.clear();
+        if let Some(ref mut tree) = self.bsp {
+            tree.clear_dynamic();
+        }
+
+        for (o, object) in objects.iter().enumerate() {
+            for (m, mesh) in object.meshes.iter().enumerate() {
+                if use_bsp && mesh.static_geometry {
+                    continue;
+                }
+                let bounds = mesh.mesh.bounds();
+                if !frustum.visible(&bounds.aabb, &bounds.sphere) {
+                    continue;
+                }
+                mesh.project(camera);
+                let faces = mesh.mesh.faces.borrow();
+                let chunked = bounds.chunks.len() > 1;
+                for chunk in bounds.chunks.iter() {
+                    if chunked && !frustum.visible(&chunk.aabb, &chunk.sphere) {
+                        continue;
+                    }
+                    for &i in chunk.faces.iter() {
+                        let d = faces[i].distance(camera.r);
+                        if d >= camera.far {
+                            continue;
+                        }
+                        let face_ref = FaceRef {
+                            object: o as u32,
+                            mesh: m as u32,
+                            face: i as u32,
+                        };
+                        if use_bsp {
+                            if let Some(ref mut tree) = self.bsp {
+                                tree.insert(BspPolygon {
+                                    points: faces[i].get_points().to_vec(),
+                                    face: face_ref,
+                                    shadow: false,
+                                });
+                            }
+                        } else if faces[i].is_translucent() && self.path == RenderPath::Software {
+                            self.translucent.push(DrawItem::Face(face_ref), d);
+                        } else {
+                            self.opaque.push(DrawItem::Face(face_ref), d);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Shadows go in with the opaque faces, pulled a little toward
+        // the camera so they sort after the face they lie on
+        for (i, shadow) in self.planar_shadows.iter().enumerate() {
+            let d = polygon_distance(&shadow.points, camera.r);
+            if d >= camera.far {
+                continue;
+            }
+            if use_bsp {
+                if let Some(ref mut tree) = self.bsp {
+                    tree.insert(shadow.clone());
+                }
+            } else {
+                self.opaque.push(DrawItem::Shadow(i), d - SHADOW_SORT_BIAS);
+            }
+        }
+
+        // Sort back to front based on distance from the camera
+        self.opaque.sort();
+        self.translucent.sort();
+    }
+
+    fn draw_item<C>(&self, frame: &Frame, item: DrawItem, dist: f64, blend: Blend, canvas: &mut C)
+        where C: Canvas
+    {
+        match item {
+            DrawItem::Face(face_ref) => draw_face(frame, face_ref, dist, blend, canvas),
+            DrawItem::Shadow(i) => {
+                let mut points = self.points.borrow_mut();
+                points.clear();
+                for &r in self.planar_shadows[i].points.iter() {
+                    points.push(frame.camera.project(r));
+                }
+                draw_shadow(frame, &points[..], dist, canvas);
+            },
+        }
+    }
+}
+
+/// Draw a BSP tree back to front, translucent faces come out in the
+/// right order along with everything else
+fn draw_bsp<C>(frame: &Frame, tree: &BspTree, points: &mut Vec<[f64; 3]>, canvas: &mut C)
+    where C: Canvas
+{
+    let camera = frame.camera;
+    tree.traverse(camera.r, |polygon| {
+        points.clear();
+        for &r in polygon.points.iter() {
+            points.push(camera.project(r));
+        }
+        let dist = polygon_distance(&polygon.points, camera.r);
+        if dist >= camera.far || points.iter().any(|p| p[0].is_nan()) {
+            return;
+        }
+        if polygon.shadow {
+            draw_shadow(frame, &points[..], dist, canvas);
+            return;
+        }
+        let faces = face_mesh(frame.objects, polygon.face).mesh.faces.borrow();
+        let face = &faces[polygon.face.face as usize];
+        let blend = if face.is_translucent() { Blend::Alpha } else { Blend::Opaque };
+        canvas.polygon(&points[..], frame.fog.apply(face.shade(frame.lights, frame.shading), dist), blend);
+    });
+}
+
+/// Draw a flattened shadow as a dark translucent polygon
+fn draw_shadow<C>(frame: &Frame, points: &[[f64; 3]], dist: f64, canvas: &mut C)
+    where C: Canvas
+{
+    if points.iter().any(|p| p[0].is_nan()) {
+        return;
+    }
+    let color = frame.fog.apply([0.0, 0.0, 0.0, frame.shadow_darkness], dist);
+    canvas.polygon(points, color, Blend::Alpha);
+}
+
+/// Shade a queued face and draw it using the projected vertices cached
+/// on its mesh this frame
+fn draw_face<C>(frame: &Frame, face_ref: FaceRef, dist: f64, blend: Blend, canvas: &mut C)
+    where C: Canvas
+{
+    let mesh = face_mesh(frame.objects, face_ref);
+    let faces = mesh.mesh.faces.borrow();
+    let face = &faces[face_ref.face as usize];
+    let points = face.project_corners(&mesh.projected.borrow());
+    if points.iter().any(|p| p[0].is_nan()) {
+        return;
+    }
+    let shaded = face.shade(frame.lights, frame.shading);
+    let lit = frame.fog.apply(shaded, dist);
+    match frame.shadow_map {
+        Some(shadow_map) if mesh.receive_shadows => {
+            let shadowed = frame.fog.apply(darken(shaded, frame.shadow_darkness), dist);
+            canvas.shadowed_polygon(&points, &face.get_points(), lit, shadowed, blend, shadow_map);
+        },
+        _ => canvas.polygon(&points, lit, blend),
+    }
+}
@@ -0,0 +1,577 @@
+//! Save games
+//!
+//! A save holds the state that changes while playing, on top of a world
+//! the game builds in code or from a scene: the time, camera, time of
+//! day, random number generator and, for every named object, its
+//! transform, parent, tags, velocity, inventory and behaviour states.
+//! Unnamed objects can't be told apart between runs and aren't saved.
+//!
+//! Saves are written in the same TOML subset as scenes, starting with the
+//! version of the format. Older saves are upgraded one version at a time
+//! by `MIGRATIONS` before they are read, so a change to the format only
+//! needs a new migration rather than a second reader.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use behaviour::Behaviours;
+use behaviour::State;
+use ecs::Velocity;
+use inventory::Inventory;
+use math::Transform;
+use toml;
+use toml::Item;
+use toml::Position;
+use toml::Table;
+use toml::Value;
+use types::Vec3;
+use world::Handle;
+use world::World;
+
+/// Version of the format `save` writes
+pub const VERSION: u32 = 1;
+
+/// Upgrades the tables of a save from one version to the next, the first
+/// takes a version 1 save to version 2 and so on. Add one here whenever
+/// `VERSION` goes up.
+const MIGRATIONS: &'static [fn(&mut Vec<Table>)] = &[];
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn error(position: Position, message: String) -> toml::Error {
+    toml::Error::new(position, message)
+}
+
+fn numbers(values: &[f64]) -> String {
+    let values: Vec<String> = values.iter().map(|x| format!("{}", x)).collect();
+    format!("[{}]", values.join(", "))
+}
+
+fn vec3(r: Vec3) -> String {
+    numbers(&r)
+}
+
+/// Objects parents first, each with the name it is saved under
+fn named_objects(world: &World) -> Vec<(Handle, String)> {
+    let mut objects = vec![];
+    let mut stack: Vec<Handle> = world.objects.iter()
+        .filter(|o| o.parent().is_none())
+        .filter_map(|o| o.handle())
+        .rev()
+        .collect();
+    while let Some(handle) = stack.pop() {
+        if let Some(object) = world.get(handle) {
+            if let Some(ref name) = object.name {
+                // Only the object a name finds can be found again
+                if world.find(name) == Some(handle) {
+                    objects.push((handle, name.clone()));
+                }
+            }
+            stack.extend(object.children().iter().rev().cloned());
+        }
+    }
+    objects
+}
+
+/// Write the state of a world in the current format
+pub fn to_string(world: &World) -> String {
+    let mut out = String::new();
+    writeln!(out, "version = {}", VERSION).unwrap();
+    writeln!(out, "time = {}\n", world.t).unwrap();
+
+    writeln!(out, "[camera]").unwrap();
+    writeln!(out, "position = {}", vec3(world.camera.r)).unwrap();
+    writeln!(out, "rotation = {}\n", vec3(world.camera.theta)).unwrap();
+
+    if let Some(ref sky) = world.sky {
+        writeln!(out, "[sky]").unwrap();
+        writeln!(out, "time = {}", sky.time).unwrap();
+        writeln!(out, "paused = {}\n", sky.paused).unwrap();
+    }
+
+    // Numbers are f64, too small for the whole state
+    writeln!(out, "[random]").unwrap();
+    writeln!(out, "state = \"{:016x}\"\n", world.random.state()).unwrap();
+
+    for (handle, name) in named_objects(world).into_iter() {
+        let object = world.get(handle).unwrap();
+        writeln!(out, "[[object]]").unwrap();
+        writeln!(out, "name = {}", toml::quote(&name)).unwrap();
+        let parent = object.parent().and_then(|p| world.get(p)).and_then(|p| p.name.clone());
+        if let Some(parent) = parent {
+            writeln!(out, "parent = {}", toml::quote(&parent)).unwrap();
+        }
+        let tags: Vec<String> = object.tags.iter().map(|t| toml::quote(t)).collect();
+        writeln!(out, "tags = [{}]", tags.join(", ")).unwrap();
+        let local = object.local();
+        let linear: Vec<f64> = local.linear.iter().flat_map(|row| row.iter().cloned()).collect();
+        writeln!(out, "linear = {}", numbers(&linear)).unwrap();
+        writeln!(out, "translation = {}", vec3(local.translation)).unwrap();
+        if let Some(velocity) = world.components.get::<Velocity>(handle) {
+            writeln!(out, "velocity = {}", vec3(velocity.0)).unwrap();
+        }
+
+        if let Some(inventory) = world.components.get::<Inventory>(handle) {
+            writeln!(out, "\n[object.inventory]").unwrap();
+            for (item, count) in inventory.items() {
+                writeln!(out, "{} = {}", toml::quote(item), count).unwrap();
+            }
+        }
+        if let Some(behaviours) = world.components.get::<Behaviours>(handle) {
+            for state in behaviours.save().iter() {
+                writeln!(out, "\n[[object.behaviour]]").unwrap();
+                for (key, values) in state.iter() {
+                    writeln!(out, "{} = {}", toml::quote(key), numbers(values)).unwrap();
+                }
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Bring the tables of an older save up to the current version
+fn migrate(tables: &mut Vec<Table>) -> Result<(), toml::Error> {
+    upgrade(tables, VERSION, MIGRATIONS)
+}
+
+/// Bring the tables of a save up to version `current` with `migrations`,
+/// the first of which upgrades version 1
+fn upgrade(tables: &mut Vec<Table>, current: u32, migrations: &[fn(&mut Vec<Table>)])
+           -> Result<(), toml::Error>
+{
+    let (version, position) = match tables[0].get("version") {
+        Some(item) => (try!(item.as_number()), item.position),
+        None => return Err(error(tables[0].position, "not a save, no version".to_string())),
+    };
+    if version.fract() != 0.0 || version < 1.0 {
+        return Err(error(position, format!("invalid version {}", version)));
+    }
+    let version = version as u32;
+    if version > current {
+        return Err(error(position, format!("saved by a newer version of the game, \
+                                            version {} is newer than {}", version, current)));
+    }
+    for migration in migrations[(version - 1) as usize..].iter() {
+        migration(tables);
+    }
+    Ok(())
+}
+
+/// An object as saved
+struct SavedObject {
+    name: String,
+    position: Position,
+    parent: Option<String>,
+    tags: Vec<String>,
+    transform: Transform,
+    velocity: Option<Vec3>,
+    inventory: Option<Inventory>,
+    behaviours: Vec<State>,
+}
+
+fn parse_object(table: &Table) -> Result<SavedObject, toml::Error> {
+    try!(table.check_keys(&["name", "parent", "tags", "linear", "translation", "velocity"]));
+    let name = match try!(table.string("name")) {
+        Some(name) => name,
+        None => return Err(error(table.position, "an object needs a `name`".to_string())),
+    };
+    let mut transform = Transform::identity();
+    if let Some(item) = table.get("linear") {
+        let m = try!(item.as_numbers(9));
+        transform.linear = [[m[0], m[1], m[2]], [m[3], m[4], m[5]], [m[6], m[7], m[8]]];
+    }
+    transform.translation = try!(table.vec3("translation", [0.0; 3]));
+    Ok(SavedObject {
+        name: name,
+        position: table.position,
+        parent: try!(table.string("parent")),
+        tags: match table.get("tags") {
+            Some(item) => try!(item.as_strings()),
+            None => vec![],
+        },
+        transform: transform,
+        velocity: match table.get("velocity") {
+            Some(item) => Some(try!(item.as_vec3())),
+            None => None,
+        },
+        inventory: None,
+        behaviours: vec![],
+    })
+}
+
+fn parse_inventory(table: &Table) -> Result<Inventory, toml::Error> {
+    let mut inventory = Inventory::new();
+    for &(ref item, ref count) in table.entries.iter() {
+        let n = try!(count.as_number());
+        if n < 0.0 || n.fract() != 0.0 {
+            return Err(error(count.position, format!("invalid count {}", n)));
+        }
+        inventory.add(item, n as u32);
+    }
+    Ok(inventory)
+}
+
+fn parse_state(table: &Table) -> Result<State, toml::Error> {
+    let mut state = State::new();
+    for &(ref key, ref item) in table.entries.iter() {
+        let values = match item.value {
+            Value::Number(x) => vec![x],
+            _ => try!(try!(item.as_array()).iter().map(Item::as_number).collect()),
+        };
+        state.set(key, &values);
+    }
+    Ok(state)
+}
+
+/// Restore a save into a world built the way it was when saved. Nothing
+/// is changed if the save can't be read or names an object the world
+/// doesn't have.
+pub fn restore(world: &mut World, text: &str) -> Result<(), toml::Error> {
+    let mut tables = try!(toml::parse(text));
+    try!(migrate(&mut tables));
+
+    let mut time = None;
+    let mut camera = None;
+    let mut sky = None;
+    let mut random = None;
+    let mut objects: Vec<SavedObject> = vec![];
+
+    for table in tables.iter() {
+        match (table.name.as_str(), table.array) {
+            ("", false) => {
+                try!(table.check_keys(&["version", "time"]));
+                time = Some(try!(table.number("time", world.t)));
+            },
+            ("camera", false) => {
+                try!(table.check_keys(&["position", "rotation"]));
+                camera = Some((try!(table.vec3("position", world.camera.r)),
+                               try!(table.vec3("rotation", world.camera.theta))));
+            },
+            ("sky", false) => {
+                try!(table.check_keys(&["time", "paused"]));
+                sky = Some((try!(table.number("time", 0.0)), try!(table.boolean("paused", false))));
+            },
+            ("random", false) => {
+                try!(table.check_keys(&["state"]));
+                let item = match table.get("state") {
+                    Some(item) => item,
+                    None => return Err(error(table.position, "missing `state`".to_string())),
+                };
+                match u64::from_str_radix(try!(item.as_str()), 16) {
+                    Ok(state) => random = Some(state),
+                    Err(_) => return Err(error(item.position, "invalid state".to_string())),
+                }
+            },
+            ("object", true) => objects.push(try!(parse_object(table))),
+            ("object.inventory", false) | ("object.behaviour", true) => {
+                let object = match objects.last_mut() {
+                    Some(object) => object,
+                    None => {
+                        return Err(error(table.position, format!("[{}] must follow an \
+                                                                  [[object]]", table.name)));
+                    },
+                };
+                if table.array {
+                    object.behaviours.push(try!(parse_state(table)));
+                } else {
+                    object.inventory = Some(try!(parse_inventory(table)));
+                }
+            },
+            (name, _) => {
+                return Err(error(table.position, format!("unknown table [{}]", name)));
+            },
+        }
+    }
+
+    // Check every object is there and can go under its parent before
+    // changing anything
+    let mut handles: HashMap<String, Handle> = HashMap::new();
+    for object in objects.iter() {
+        if handles.contains_key(&object.name) {
+            return Err(error(object.position, format!("`{}` is saved twice", object.name)));
+        }
+        if let Some(ref parent) = object.parent {
+            if !handles.contains_key(parent) {
+                return Err(error(object.position, format!("the parent `{}` must be saved \
+                                                           before this object", parent)));
+            }
+        }
+        match world.find(&object.name) {
+            Some(handle) => { handles.insert(object.name.clone(), handle); },
+            None => {
+                return Err(error(object.position, format!("the world has no object named \
+                                                           `{}`", object.name)));
+            },
+        }
+    }
+
+    if let Some(t) = time {
+        world.t = t;
+    }
+    if let Some((r, theta)) = camera {
+        world.camera.r = r;
+        world.camera.theta = theta;
+        world.camera.update_projection();
+    }
+    if let (Some((time, paused)), Some(world_sky)) = (sky, world.sky.as_mut()) {
+        world_sky.time = time;
+        world_sky.paused = paused;
+    }
+    world.apply_sky();
+    if let Some(state) = random {
+        world.random.set_state(state);
+    }
+
+    for object in objects.into_iter() {
+        let handle = handles[&object.name];
+        let parent = object.parent.as_ref().map(|p| handles[p]);
+        let current = world.get(handle).and_then(|o| o.parent());
+        // Objects under an unnamed parent are left under it
+        let unnamed_parent = object.parent.is_none() &&
+            current.and_then(|p| world.get(p)).map_or(false, |p| p.name.is_none());
+        // Cycles were ruled out above, but don't carry on quietly if the
+        // world still refuses
+        if current != parent && !unnamed_parent && !world.reparent(handle, parent) {
+            let parent = object.parent.unwrap_or_default();
+            return Err(error(object.position, format!("could not move `{}` under `{}`",
+                                                      object.name, parent)));
+        }
+        world.set_transform(handle, object.transform);
+        if let Some(o) = world.get_mut(handle) {
+            o.tags = object.tags;
+        }
+        match object.velocity {
+            Some(v) => { world.components.insert(handle, Velocity(v)); },
+            None => { world.components.remove::<Velocity>(handle); },
+        }
+        match object.inventory {
+            Some(inventory) => { world.components.insert(handle, inventory); },
+            None => { world.components.remove::<Inventory>(handle); },
+        }
+        if let Some(behaviours) = world.components.get_mut::<Behaviours>(handle) {
+            behaviours.load(&object.behaviours);
+        }
+    }
+
+    // Named objects missing from the save were removed before saving.
+    // Saved objects were moved out from under them above, removing them
+    // first would take saved children along.
+    let saved: HashSet<Handle> = handles.values().cloned().collect();
+    for (handle, _) in named_objects(world).into_iter() {
+        if !saved.contains(&handle) {
+            world.remove(handle);
+        }
+    }
+    world.update_transforms();
+    Ok(())
+}
+
+/// Save the state of a world to a file
+pub fn save<P>(world: &World, path: P) -> io::Result<()> where P: AsRef<Path> {
+    let mut file = try!(File::create(path));
+    file.write_all(to_string(world).as_bytes())
+}
+
+/// Restore a world from a save file, see `restore`
+pub fn load<P>(world: &mut World, path: P) -> io::Result<()> where P: AsRef<Path> {
+    let path = path.as_ref();
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+    restore(world, &text).map_err(|e| invalid(format!("{}:{}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_object;
+    use super::restore;
+    use super::to_string;
+    use super::upgrade;
+    use super::MIGRATIONS;
+    use super::VERSION;
+    use behaviour::Behaviour;
+    use behaviour::Behaviours;
+    use behaviour::State;
+    use ecs::Velocity;
+    use inventory::Inventory;
+    use math::Transform;
+    use toml;
+    use toml::Item;
+    use toml::Table;
+    use toml::Value;
+    use world::World;
+    use world::WorldObject;
+
+    struct Counter {
+        count: f64,
+        last: Vec<f64>,
+    }
+
+    impl Behaviour for Counter {
+        fn save(&self, state: &mut State) {
+            state.set("count", &[self.count]);
+            state.set("last", &self.last);
+        }
+
+        fn load(&mut self, state: &State) {
+            self.count = state.number("count").unwrap_or(0.0);
+            self.last = state.get("last").map_or(vec![], |last| last.to_vec());
+        }
+    }
+
+    /// The world as the game builds it before restoring a save
+    fn world() -> World {
+        let mut world = World::new();
+        let cart = world.object(WorldObject::new().name("cart"));
+        let crate_ = world.add_child(cart, WorldObject::new().name("crate").tag("loot")).unwrap();
+        world.object(WorldObject::new().name("rock"));
+        let mut behaviours = Behaviours::new();
+        behaviours.push(Box::new(Counter { count: 0.0, last: vec![] }));
+        world.components.insert(crate_, behaviours);
+        world
+    }
+
+    #[test]
+    fn there_is_a_migration_for_every_older_version() {
+        assert_eq!(MIGRATIONS.len() as u32, VERSION - 1);
+    }
+
+    /// Version 1 called the translation `position`
+    fn rename_position(tables: &mut Vec<Table>) {
+        for table in tables.iter_mut().filter(|t| t.name == "object") {
+            for entry in table.entries.iter_mut().filter(|e| e.0 == "position") {
+                entry.0 = "translation".to_string();
+            }
+        }
+    }
+
+    /// Version 2 had no tags, objects got a "saved" one
+    fn add_tags(tables: &mut Vec<Table>) {
+        for table in tables.iter_mut().filter(|t| t.name == "object") {
+            let position = table.position;
+            let tag = Item { value: Value::String("saved".to_string()), position: position };
+            table.entries.push(("tags".to_string(),
+                                Item { value: Value::Array(vec![tag]), position: position }));
+        }
+    }
+
+    #[test]
+    fn older_saves_are_migrated() {
+        let migrations: &[fn(&mut Vec<Table>)] = &[rename_position, add_tags];
+        let v1 = "version = 1\n[[object]]\nname = \"crate\"\nposition = [1, 2, 3]\n";
+        let mut tables = toml::parse(v1).unwrap();
+        upgrade(&mut tables, 3, migrations).unwrap();
+        let object = parse_object(&tables[1]).unwrap();
+        assert_eq!(object.name, "crate");
+        assert_eq!(object.transform.translation, [1.0, 2.0, 3.0]);
+        assert_eq!(object.tags, vec!["saved"]);
+
+        // Only the migrations after the save's version run
+        let v2 = "version = 2\n[[object]]\nname = \"crate\"\ntranslation = [1, 2, 3]\n";
+        let mut tables = toml::parse(v2).unwrap();
+        upgrade(&mut tables, 3, migrations).unwrap();
+        let object = parse_object(&tables[1]).unwrap();
+        assert_eq!(object.transform.translation, [1.0, 2.0, 3.0]);
+        assert_eq!(object.tags, vec!["saved"]);
+
+        let mut current = toml::parse("version = 3\n[[object]]\nname = \"crate\"\n").unwrap();
+        upgrade(&mut current, 3, migrations).unwrap();
+        assert_eq!(parse_object(&current[1]).unwrap().tags, Vec::<String>::new());
+
+        let mut newer = toml::parse("version = 4\n").unwrap();
+        assert!(upgrade(&mut newer, 3, migrations).is_err());
+    }
+
+    #[test]
+    fn saves_that_cant_be_applied_change_nothing() {
+        let mut world = world();
+        let before = to_string(&world);
+        let twice = "version = 1\n[[object]]\nname = \"rock\"\n[[object]]\nname = \"rock\"\n";
+        assert!(restore(&mut world, twice).unwrap_err().message.contains("saved twice"));
+        let own_parent = "version = 1\n[[object]]\nname = \"rock\"\nparent = \"rock\"\n";
+        assert!(restore(&mut world, own_parent).is_err());
+        assert_eq!(to_string(&world), before);
+    }
+
+    #[test]
+    fn restoring_a_save_brings_back_the_world() {
+        // Play a little
+        let mut played = world();
+        let cart = played.find("cart").unwrap();
+        let crate_ = played.find("crate").unwrap();
+        let rock = played.find("rock").unwrap();
+        let moved = Transform::translation([1.5, -2.25, 1e-7])
+            .mul(&Transform::rotation([0.1, 0.7, -0.3]))
+            .mul(&Transform::scale(1.25));
+        played.set_transform(cart, moved);
+        played.set_transform(crate_, Transform::translation([0.0, 3.0, 0.0]));
+        played.reparent(crate_, Some(rock));
+        played.get_mut(crate_).unwrap().tags.push("opened".to_string());
+        played.components.insert(cart, Velocity([0.0, 0.0, 4.5]));
+        let mut inventory = Inventory::new();
+        inventory.add("gold", 12);
+        inventory.add("rope", 1);
+        played.components.insert(rock, inventory.clone());
+        let mut state = State::new();
+        state.set("count", &[3.0]);
+        state.set("last", &[0.5, -1.0]);
+        played.components.get_mut::<Behaviours>(crate_).unwrap().load(&[state]);
+        played.random.set_state(0x0123456789abcdef);
+        played.random.next_u64();
+        played.t = 42.5;
+        played.update_transforms();
+        let saved = to_string(&played);
+
+        let mut restored = world();
+        restore(&mut restored, &saved).unwrap();
+        assert_eq!(to_string(&restored), saved);
+
+        let cart = restored.find("cart").unwrap();
+        let crate_ = restored.find("crate").unwrap();
+        let rock = restored.find("rock").unwrap();
+        assert_eq!(*restored.get(cart).unwrap().local(), moved);
+        assert_eq!(restored.get(crate_).unwrap().parent(), Some(rock));
+        assert_eq!(restored.get(crate_).unwrap().tags, vec!["loot", "opened"]);
+        assert_eq!(restored.components.get::<Velocity>(cart).unwrap().0, [0.0, 0.0, 4.5]);
+        assert_eq!(restored.components.get::<Inventory>(rock), Some(&inventory));
+        let states = restored.components.get::<Behaviours>(crate_).unwrap().save();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].number("count"), Some(3.0));
+        assert_eq!(states[0].get("last"), Some(&[0.5, -1.0][..]));
+        assert_eq!(restored.random.state(), played.random.state());
+        assert_eq!(restored.random.next_u64(), played.random.next_u64());
+        assert_eq!(restored.t, 42.5);
+    }
+
+    #[test]
+    fn children_outlive_their_removed_parents() {
+        let mut played = world();
+        let cart = played.find("cart").unwrap();
+        let crate_ = played.find("crate").unwrap();
+        played.set_transform(cart, Transform::translation([10.0, 0.0, 0.0]));
+        played.set_transform(crate_, Transform::translation([0.0, 1.0, 0.0]));
+        played.update_transforms();
+        played.reparent(crate_, None);
+        played.remove(cart);
+        let saved = to_string(&played);
+
+        // The crate starts under the cart, which the save doesn't have
+        let mut restored = world();
+        restore(&mut restored, &saved).unwrap();
+        assert_eq!(restored.find("cart"), None);
+        let crate_ = restored.find("crate").unwrap();
+        let object = restored.get(crate_).unwrap();
+        assert_eq!(object.parent(), None);
+        assert_eq!(object.local().translation, [10.0, 1.0, 0.0]);
+        assert_eq!(object.tags, vec!["loot"]);
+        assert!(restored.components.get::<Behaviours>(crate_).is_some());
+    }
+}
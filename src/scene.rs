@@ -0,0 +1,818 @@
+//! Scenes described in text files
+//!
+//! A scene file sets up the camera, fog, sky, lights and objects of a
+//! world in a small subset of TOML. Every table is optional, and objects
+//! may name an earlier object as their parent:
+//!
+//! ```toml
+//! [camera]
+//! position = [0, -200, -250]
+//! rotation = [-0.4, 0, 0]
+//! far = 900
+//!
+//! [fog]
+//! color = [0.55, 0.65, 0.75]
+//! start = 400
+//! end = 900
+//!
+//! [sky]
+//! time = 9
+//! day_length = 300
+//!
+//! [[light]]
+//! position = [200, 100, 0]
+//!
+//! [[object]]
+//! name = "terrain"
+//!
+//! [object.mesh]
+//! kind = "terrain"
+//! size = 600
+//! resolution = 20
+//!
+//! [object.material]
+//! static = true
+//!
+//! [[object]]
+//! name = "diamond"
+//! tags = ["pickup"]
+//! position = [0, -50, 0]
+//!
+//! [object.mesh]
+//! kind = "diamond"
+//! size = 15
+//!
+//! [object.material]
+//! color = [0.1, 0.1, 0.9, 0.4]
+//!
+//! [[object]]
+//! name = "moon"
+//! parent = "diamond"
+//! position = [30, -10, 0]
+//! rotation = [0, 0.5, 0]
+//! scale = 1.5
+//!
+//! [object.mesh]
+//! kind = "obj"
+//! path = "meshes/moon.obj"
+//! ```
+//!
+//! Mesh kinds are `diamond` with a `size`, `terrain` with a `size` and
+//! `resolution`, and `obj` with a `path` relative to the scene file.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use camera::Camera;
+use fog::Fog;
+use fog::FogMode;
+use lights::LightSource;
+use assets::Assets;
+use assets::MeshData;
+use math::Transform;
+use mesh::Mesh;
+use obj;
+use sky::Sky;
+use toml;
+use toml::Position;
+use toml::Table;
+use types::Color;
+use types::Vec3;
+use world::Handle;
+use world::World;
+use world::WorldObject;
+
+/// Where an object's mesh comes from
+#[derive(Debug,Clone,PartialEq)]
+pub enum MeshSource {
+    Diamond { size: f64 },
+    Terrain { size: f64, resolution: f64 },
+    /// An OBJ file, relative to the scene file
+    Obj { path: PathBuf },
+}
+
+impl MeshSource {
+    /// Build the mesh, finding files relative to `dir`
+    pub fn build(&self, dir: &Path) -> io::Result<Mesh> {
+        match *self {
+            MeshSource::Diamond { size } => Ok(Mesh::new_diamond(size)),
+            MeshSource::Terrain { size, resolution } => {
+                let terrain = Mesh::new();
+                terrain.add_terrain(size, resolution);
+                Ok(terrain)
+            },
+            MeshSource::Obj { ref path } => obj::load(dir.join(path)),
+        }
+    }
+
+    /// An instance of the mesh, built or loaded only once for every
+    /// object with the same source
+    pub fn instance(&self, dir: &Path, assets: &mut Assets) -> io::Result<Mesh> {
+        let data = match *self {
+            MeshSource::Diamond { size } => {
+                try!(assets.mesh(&format!("diamond:{}", size), || self.build(dir)))
+            },
+            MeshSource::Terrain { size, resolution } => {
+                try!(assets.mesh(&format!("terrain:{}:{}", size, resolution), || self.build(dir)))
+            },
+            MeshSource::Obj { ref path } => try!(assets.load_mesh(dir.join(path))),
+        };
+        Ok(MeshData::instance(&data))
+    }
+}
+
+/// How an object's mesh is drawn
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Material {
+    /// Color of every face, the mesh's own colors without one
+    pub color: Option<Color>,
+    pub wireframe: bool,
+    pub static_geometry: bool,
+    pub cast_shadows: bool,
+    pub receive_shadows: bool,
+}
+
+impl Material {
+    pub fn new() -> Material {
+        Material {
+            color: None,
+            wireframe: false,
+            static_geometry: false,
+            cast_shadows: true,
+            receive_shadows: true,
+        }
+    }
+
+    pub fn apply(&self, mesh: &mut Mesh) {
+        if let Some(color) = self.color {
+            mesh.set_color(color);
+        }
+        mesh.wireframe(self.wireframe);
+        mesh.static_geometry(self.static_geometry);
+        mesh.cast_shadows(self.cast_shadows);
+        mesh.receive_shadows(self.receive_shadows);
+    }
+}
+
+/// Component remembering what an object was built from, so the scene
+/// can be written back out. OBJ paths are relative to the working
+/// directory rather than to the scene file.
+#[derive(Debug,Clone,PartialEq)]
+pub struct SceneSource {
+    pub mesh: Option<MeshSource>,
+    pub material: Material,
+}
+
+/// An object in a scene file
+#[derive(Debug,Clone)]
+pub struct SceneObject {
+    pub name: Option<String>,
+    /// Name of an earlier object in the scene
+    pub parent: Option<String>,
+    pub tags: Vec<String>,
+    pub position: Vec3,
+    pub rotation: Vec3,
+    pub scale: f64,
+    pub mesh: Option<MeshSource>,
+    pub material: Material,
+    /// Where the object starts in the file
+    pub defined_at: Position,
+}
+
+impl SceneObject {
+    pub fn transform(&self) -> Transform {
+        Transform::translation(self.position)
+            .mul(&Transform::rotation(self.rotation))
+            .mul(&Transform::scale(self.scale))
+    }
+}
+
+/// Everything a scene file describes
+#[derive(Debug,Clone)]
+pub struct Scene {
+    /// File the scene was loaded from, for error messages
+    pub path: Option<PathBuf>,
+    /// Directory mesh paths are relative to
+    pub dir: PathBuf,
+    pub camera: Option<Camera>,
+    pub fog: Option<Fog>,
+    pub sky: Option<Sky>,
+    pub lights: Vec<LightSource>,
+    pub objects: Vec<SceneObject>,
+}
+
+fn error(position: Position, message: String) -> toml::Error {
+    toml::Error::new(position, message)
+}
+
+fn parse_camera(table: &Table) -> Result<Camera, toml::Error> {
+    try!(table.check_keys(&["position", "rotation", "far"]));
+    let mut camera = Camera::default();
+    camera.r = try!(table.vec3("position", camera.r));
+    camera.theta = try!(table.vec3("rotation", camera.theta));
+    camera.far = try!(table.number("far", camera.far));
+    camera.update_projection();
+    Ok(camera)
+}
+
+fn parse_fog(table: &Table) -> Result<Fog, toml::Error> {
+    try!(table.check_keys(&["color", "start", "end", "density"]));
+    let color = match table.get("color") {
+        Some(item) => try!(item.as_color()),
+        None => [0.0, 0.0, 0.0, 1.0],
+    };
+    let fog = Fog::new(color);
+    match (table.get("start"), table.get("end"), table.get("density")) {
+        (None, None, None) => Ok(fog),
+        (_, _, None) => {
+            Ok(fog.linear(try!(table.number("start", 0.0)), try!(table.number("end", 0.0))))
+        },
+        (None, None, Some(density)) => Ok(fog.exponential(try!(density.as_number()))),
+        (_, _, Some(density)) => {
+            Err(error(density.position, "fog is either linear or exponential".to_string()))
+        },
+    }
+}
+
+fn parse_sky(table: &Table) -> Result<Sky, toml::Error> {
+    try!(table.check_keys(&["time", "day_length", "paused"]));
+    let mut sky = Sky::new();
+    sky = sky.time(try!(table.number("time", sky.time)));
+    sky = sky.day_length(try!(table.number("day_length", sky.day_length)));
+    sky.paused = try!(table.boolean("paused", false));
+    Ok(sky)
+}
+
+fn parse_light(table: &Table) -> Result<LightSource, toml::Error> {
+    try!(table.check_keys(&["position", "direction", "color", "intensity"]));
+    let mut light = match (table.get("position"), table.get("direction")) {
+        (Some(r), None) => LightSource::new(try!(r.as_vec3())),
+        (None, Some(d)) => LightSource::directional(try!(d.as_vec3())),
+        (Some(_), Some(d)) => {
+            return Err(error(d.position, "a light has a position or a direction, \
+                                          not both".to_string()));
+        },
+        (None, None) => {
+            return Err(error(table.position, "a light needs a position or a \
+                                              direction".to_string()));
+        },
+    };
+    light.color = try!(table.vec3("color", light.color));
+    light.intensity = try!(table.number("intensity", light.intensity as f64)) as f32;
+    Ok(light)
+}
+
+fn parse_object(table: &Table) -> Result<SceneObject, toml::Error> {
+    try!(table.check_keys(&["name", "parent", "tags", "position", "rotation", "scale"]));
+    Ok(SceneObject {
+        name: try!(table.string("name")),
+        parent: try!(table.string("parent")),
+        tags: match table.get("tags") {
+            Some(item) => try!(item.as_strings()),
+            None => vec![],
+        },
+        position: try!(table.vec3("position", [0.0; 3])),
+        rotation: try!(table.vec3("rotation", [0.0; 3])),
+        scale: try!(table.number("scale", 1.0)),
+        mesh: None,
+        material: Material::new(),
+        defined_at: table.position,
+    })
+}
+
+fn parse_mesh(table: &Table) -> Result<MeshSource, toml::Error> {
+    let kind = match table.get("kind") {
+        Some(item) => try!(item.as_str()).to_string(),
+        None => return Err(error(table.position, "a mesh needs a `kind`".to_string())),
+    };
+    match kind.as_str() {
+        "diamond" => {
+            try!(table.check_keys(&["kind", "size"]));
+            Ok(MeshSource::Diamond { size: try!(table.number("size", 15.0)) })
+        },
+        "terrain" => {
+            try!(table.check_keys(&["kind", "size", "resolution"]));
+            let resolution = try!(table.number("resolution", 20.0));
+            if resolution <= 0.0 {
+                let position = table.get("resolution").unwrap().position;
+                return Err(error(position, "the resolution must be positive".to_string()));
+            }
+            Ok(MeshSource::Terrain {
+                size: try!(table.number("size", 600.0)),
+                resolution: resolution,
+            })
+        },
+        "obj" => {
+            try!(table.check_keys(&["kind", "path"]));
+            match try!(table.string("path")) {
+                Some(path) => Ok(MeshSource::Obj { path: PathBuf::from(path) }),
+                None => Err(error(table.position, "an obj mesh needs a `path`".to_string())),
+            }
+        },
+        _ => {
+            let position = table.get("kind").unwrap().position;
+            Err(error(position, format!("unknown mesh kind `{}`, expected diamond, terrain \
+                                         or obj", kind)))
+        },
+    }
+}
+
+/// A material from a table of `key = value` pairs
+pub fn parse_material(table: &Table) -> Result<Material, toml::Error> {
+    try!(table.check_keys(&["color", "wireframe", "static", "cast_shadows",
+                            "receive_shadows"]));
+    let mut material = Material::new();
+    if let Some(item) = table.get("color") {
+        material.color = Some(try!(item.as_color()));
+    }
+    material.wireframe = try!(table.boolean("wireframe", material.wireframe));
+    material.static_geometry = try!(table.boolean("static", material.static_geometry));
+    material.cast_shadows = try!(table.boolean("cast_shadows", material.cast_shadows));
+    material.receive_shadows = try!(table.boolean("receive_shadows", material.receive_shadows));
+    Ok(material)
+}
+
+fn vec3(r: Vec3) -> String {
+    format!("[{}, {}, {}]", r[0], r[1], r[2])
+}
+
+fn color(c: Color) -> String {
+    format!("[{}, {}, {}, {}]", c[0], c[1], c[2], c[3])
+}
+
+impl Scene {
+    /// An empty scene with mesh paths relative to `dir`
+    pub fn new<P>(dir: P) -> Scene where P: AsRef<Path> {
+        Scene {
+            path: None,
+            dir: dir.as_ref().to_path_buf(),
+            camera: None,
+            fog: None,
+            sky: None,
+            lights: vec![],
+            objects: vec![],
+        }
+    }
+
+    /// Read a scene from text, mesh paths are relative to `dir`
+    pub fn parse<P>(text: &str, dir: P) -> Result<Scene, toml::Error> where P: AsRef<Path> {
+        let mut scene = Scene::new(dir);
+        let mut has_material = false;
+
+        for table in try!(toml::parse(text)).iter() {
+            match (table.name.as_str(), table.array) {
+                ("", false) => try!(table.check_keys(&[])),
+                ("camera", false) => scene.camera = Some(try!(parse_camera(table))),
+                ("fog", false) => scene.fog = Some(try!(parse_fog(table))),
+                ("sky", false) => scene.sky = Some(try!(parse_sky(table))),
+                ("light", true) => scene.lights.push(try!(parse_light(table))),
+                ("object", true) => {
+                    scene.objects.push(try!(parse_object(table)));
+                    has_material = false;
+                },
+                ("object.mesh", false) | ("object.material", false) => {
+                    let object = match scene.objects.last_mut() {
+                        Some(object) => object,
+                        None => {
+                            return Err(error(table.position, format!("[{}] must follow an \
+                                                                      [[object]]", table.name)));
+                        },
+                    };
+                    let twice = error(table.position, format!("[{}] is defined twice for \
+                                                               this object", table.name));
+                    if table.name == "object.mesh" {
+                        if object.mesh.is_some() {
+                            return Err(twice);
+                        }
+                        object.mesh = Some(try!(parse_mesh(table)));
+                    } else {
+                        if has_material {
+                            return Err(twice);
+                        }
+                        object.material = try!(parse_material(table));
+                        has_material = true;
+                    }
+                },
+                (name, array) => {
+                    let header = if array { format!("[[{}]]", name) } else { format!("[{}]", name) };
+                    return Err(error(table.position, format!("unknown table {}", header)));
+                },
+            }
+        }
+
+        // Parents must be named before their children
+        for (i, object) in scene.objects.iter().enumerate() {
+            if let Some(ref parent) = object.parent {
+                let earlier = scene.objects[..i].iter()
+                    .any(|o| o.name.as_ref() == Some(parent));
+                if !earlier {
+                    return Err(error(object.defined_at, format!("no object named `{}` before \
+                                                                 this one", parent)));
+                }
+            }
+        }
+        Ok(scene)
+    }
+
+    /// Describe a live world, objects built from scene files keep their
+    /// mesh sources but meshes built in code are left out. Mesh paths are
+    /// relative to the working directory, see `rebase`.
+    pub fn from_world(world: &World) -> Scene {
+        let mut scene = Scene::new(".");
+        scene.camera = Some(world.camera.clone());
+        scene.fog = Some(world.renderer.fog);
+        scene.sky = world.sky.clone();
+        scene.lights = world.lights.clone();
+
+        // Parents go before their children and need a name to be
+        // referred to by
+        let mut names: HashMap<Handle, String> = HashMap::new();
+        let mut stack: Vec<Handle> = world.objects.iter()
+            .filter(|o| o.parent().is_none())
+            .filter_map(|o| o.handle())
+            .rev()
+            .collect();
+        while let Some(handle) = stack.pop() {
+            let object = match world.get(handle) {
+                Some(object) => object,
+                None => continue,
+            };
+            let mut name = object.name.clone();
+            if name.is_none() && !object.children().is_empty() {
+                name = Some(format!("object-{}", scene.objects.len()));
+            }
+            if let Some(ref name) = name {
+                names.insert(handle, name.clone());
+            }
+            let source = world.components.get::<SceneSource>(handle);
+            let (rotation, scale) = object.local().decompose();
+            let mut material = source.map_or(Material::new(), |s| s.material);
+            if let Some(mesh) = object.meshes.first() {
+                material.wireframe = mesh.wireframe;
+                material.static_geometry = mesh.static_geometry;
+                material.cast_shadows = mesh.cast_shadows;
+                material.receive_shadows = mesh.receive_shadows;
+            }
+            scene.objects.push(SceneObject {
+                name: name,
+                parent: object.parent().and_then(|p| names.get(&p).cloned()),
+                tags: object.tags.clone(),
+                position: object.local().translation,
+                rotation: rotation,
+                scale: scale,
+                mesh: source.and_then(|s| s.mesh.clone()),
+                material: material,
+                defined_at: Position { line: 0, column: 0 },
+            });
+            stack.extend(object.children().iter().rev().cloned());
+        }
+        scene
+    }
+
+    /// Make mesh paths relative to `dir` rather than to the scene's own
+    /// directory, for writing the scene there. Absolute paths are kept.
+    pub fn rebase<P>(&mut self, dir: P) where P: AsRef<Path> {
+        let dir = dir.as_ref();
+        for object in self.objects.iter_mut() {
+            if let Some(MeshSource::Obj { ref mut path }) = object.mesh {
+                if !path.is_absolute() {
+                    *path = relative_path(&self.dir.join(&*path), dir);
+                }
+            }
+        }
+        self.dir = dir.to_path_buf();
+    }
+
+    /// What an object is built from, with its OBJ path relative to the
+    /// working directory
+    pub fn source(&self, object: &SceneObject) -> SceneSource {
+        let mesh = match object.mesh {
+            Some(MeshSource::Obj { ref path }) => {
+                Some(MeshSource::Obj { path: self.dir.join(path) })
+            },
+            ref mesh => mesh.clone(),
+        };
+        SceneSource { mesh: mesh, material: object.material }
+    }
+
+    /// An error at a position in the scene file
+    fn error_at(&self, position: Position, message: String) -> io::Error {
+        let message = match self.path {
+            Some(ref path) => format!("{}:{}: {}", path.display(), position, message),
+            None => format!("{}: {}", position, message),
+        };
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+
+    /// Build one object, without its parent
+    pub fn build_object(&self, object: &SceneObject, assets: &mut Assets)
+                        -> io::Result<WorldObject>
+    {
+        let mut world_object = WorldObject::new().transform(object.transform());
+        if let Some(ref name) = object.name {
+            world_object = world_object.name(name);
+        }
+        for tag in object.tags.iter() {
+            world_object = world_object.tag(tag);
+        }
+        if let Some(ref source) = object.mesh {
+            let mut mesh = try!(source.instance(&self.dir, assets).map_err(|e| {
+                self.error_at(object.defined_at, format!("could not build the mesh: {}", e))
+            }));
+            // Objects with the same material share it
+            let key = format!("material:{:?}", object.material);
+            let material = assets.material(&key, object.material);
+            material.apply(&mut mesh);
+            mesh.material = Some(material);
+            world_object = world_object.mesh(mesh);
+        }
+        Ok(world_object)
+    }
+
+    /// Set the world's camera, fog, sky and lights to the scene's and
+    /// add its objects, returning their handles in scene order
+    pub fn apply(&self, world: &mut World) -> io::Result<Vec<Handle>> {
+        if let Some(ref camera) = self.camera {
+            world.camera = camera.clone();
+        }
+        if let Some(fog) = self.fog {
+            world.renderer.fog = fog;
+        }
+        if !self.lights.is_empty() {
+            world.lights = self.lights.clone();
+        }
+        if let Some(ref sky) = self.sky {
+            world.sky = Some(sky.clone());
+            world.apply_sky();
+        }
+
+        let mut handles = vec![];
+        let mut named: HashMap<&str, Handle> = HashMap::new();
+        for object in self.objects.iter() {
+            let world_object = try!(self.build_object(object, &mut world.assets));
+            let parent = object.parent.as_ref().and_then(|p| named.get(p.as_str()).cloned());
+            let handle = match parent {
+                Some(parent) => world.add_child(parent, world_object).unwrap(),
+                None => world.object(world_object),
+            };
+            world.components.insert(handle, self.source(object));
+            if let Some(ref name) = object.name {
+                named.insert(name.as_str(), handle);
+            }
+            handles.push(handle);
+        }
+        world.rebuild_bsp();
+        Ok(handles)
+    }
+
+    /// A world with just what the scene describes
+    pub fn build(&self) -> io::Result<World> {
+        let mut world = World::new();
+        try!(self.apply(&mut world));
+        Ok(world)
+    }
+
+    /// Write the scene in the format `parse` reads
+    pub fn to_toml(&self) -> String {
+        let mut out = String::new();
+        if let Some(ref camera) = self.camera {
+            writeln!(out, "[camera]").unwrap();
+            writeln!(out, "position = {}", vec3(camera.r)).unwrap();
+            writeln!(out, "rotation = {}", vec3(camera.theta)).unwrap();
+            writeln!(out, "far = {}\n", camera.far).unwrap();
+        }
+        if let Some(ref fog) = self.fog {
+            writeln!(out, "[fog]").unwrap();
+            writeln!(out, "color = {}", color(fog.color)).unwrap();
+            match fog.mode {
+                FogMode::None => {},
+                FogMode::Linear { start, end } => {
+                    writeln!(out, "start = {}\nend = {}", start, end).unwrap();
+                },
+                FogMode::Exponential { density } => {
+                    writeln!(out, "density = {}", density).unwrap();
+                },
+            }
+            out.push('\n');
+        }
+        if let Some(ref sky) = self.sky {
+            writeln!(out, "[sky]").unwrap();
+            writeln!(out, "time = {}", sky.time).unwrap();
+            writeln!(out, "day_length = {}", sky.day_length).unwrap();
+            writeln!(out, "paused = {}\n", sky.paused).unwrap();
+        }
+        for light in self.lights.iter() {
+            writeln!(out, "[[light]]").unwrap();
+            if light.point_source {
+                writeln!(out, "position = {}", vec3(light.r)).unwrap();
+            } else {
+                writeln!(out, "direction = {}", vec3(light.direction)).unwrap();
+            }
+            writeln!(out, "color = {}", vec3(light.color)).unwrap();
+            writeln!(out, "intensity = {}\n", light.intensity).unwrap();
+        }
+        for object in self.objects.iter() {
+            writeln!(out, "[[object]]").unwrap();
+            if let Some(ref name) = object.name {
+                writeln!(out, "name = {}", toml::quote(name)).unwrap();
+            }
+            if let Some(ref parent) = object.parent {
+                writeln!(out, "parent = {}", toml::quote(parent)).unwrap();
+            }
+            if !object.tags.is_empty() {
+                let tags: Vec<String> = object.tags.iter().map(|t| toml::quote(t)).collect();
+                writeln!(out, "tags = [{}]", tags.join(", ")).unwrap();
+            }
+            writeln!(out, "position = {}", vec3(object.position)).unwrap();
+            writeln!(out, "rotation = {}", vec3(object.rotation)).unwrap();
+            writeln!(out, "scale = {}", object.scale).unwrap();
+            match object.mesh {
+                Some(MeshSource::Diamond { size }) => {
+                    writeln!(out, "\n[object.mesh]\nkind = \"diamond\"\nsize = {}", size).unwrap();
+                },
+                Some(MeshSource::Terrain { size, resolution }) => {
+                    writeln!(out, "\n[object.mesh]\nkind = \"terrain\"\nsize = {}\n\
+                                   resolution = {}", size, resolution).unwrap();
+                },
+                Some(MeshSource::Obj { ref path }) => {
+                    writeln!(out, "\n[object.mesh]\nkind = \"obj\"\npath = {}",
+                             toml::quote(&path.to_string_lossy())).unwrap();
+                },
+                None => {},
+            }
+            let material = object.material;
+            if material != Material::new() {
+                writeln!(out, "\n[object.material]").unwrap();
+                if let Some(c) = material.color {
+                    writeln!(out, "color = {}", color(c)).unwrap();
+                }
+                writeln!(out, "wireframe = {}", material.wireframe).unwrap();
+                writeln!(out, "static = {}", material.static_geometry).unwrap();
+                writeln!(out, "cast_shadows = {}", material.cast_shadows).unwrap();
+                writeln!(out, "receive_shadows = {}", material.receive_shadows).unwrap();
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Read a scene file
+pub fn load<P>(path: P) -> io::Result<Scene> where P: AsRef<Path> {
+    let path = path.as_ref();
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut scene = try!(Scene::parse(&text, dir).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}", path.display(), e))
+    }));
+    scene.path = Some(path.to_path_buf());
+    Ok(scene)
+}
+
+/// `path` as seen from `dir`, either may be relative to the working
+/// directory. Absolute when they share no root.
+fn relative_path(path: &Path, dir: &Path) -> PathBuf {
+    let cwd = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+    let path = cwd.join(path);
+    let dir = cwd.join(dir);
+    let path_parts: Vec<Component> = path.components().collect();
+    let dir_parts: Vec<Component> = dir.components().collect();
+    let common = path_parts.iter().zip(dir_parts.iter()).take_while(|&(a, b)| a == b).count();
+    if common == 0 {
+        return path;
+    }
+    let mut relative = PathBuf::new();
+    for _ in common..dir_parts.len() {
+        relative.push("..");
+    }
+    for part in path_parts[common..].iter() {
+        relative.push(part.as_os_str());
+    }
+    relative
+}
+
+/// Write a live world out as a scene file, with mesh paths relative to
+/// the file
+pub fn save<P>(world: &World, path: P) -> io::Result<()> where P: AsRef<Path> {
+    let path = path.as_ref();
+    let mut scene = Scene::from_world(world);
+    scene.rebase(path.parent().unwrap_or(Path::new(".")));
+    let mut file = try!(File::create(path));
+    file.write_all(scene.to_toml().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    use super::Material;
+    use super::MeshSource;
+    use super::Scene;
+
+    const SCENE: &'static str = r#"
+[camera]
+position = [0, -200, -250]
+rotation = [-0.4, 0, 0]
+far = 900
+
+[fog]
+color = [0.55, 0.65, 0.75]
+start = 400
+end = 900
+
+[[light]]
+position = [200, 100, 0]
+
+[[object]]
+name = "terrain"
+
+[object.mesh]
+kind = "terrain"
+size = 600
+resolution = 20
+
+[object.material]
+static = true
+
+[[object]]
+name = "diamond"
+tags = ["pickup", "shiny"]
+position = [0, -50, 0]
+
+[object.mesh]
+kind = "diamond"
+size = 15
+
+[object.material]
+color = [0.1, 0.1, 0.9, 0.4]
+
+[[object]]
+parent = "diamond"
+position = [30, -10, 0]
+rotation = [0, 0.5, 0]
+scale = 1.5
+
+[object.mesh]
+kind = "diamond"
+size = 5
+"#;
+
+    fn close(a: [f64; 3], b: [f64; 3]) -> bool {
+        a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 1e-9)
+    }
+
+    #[test]
+    fn worlds_read_back_as_they_were_written() {
+        let scene = Scene::parse(SCENE, ".").unwrap();
+        let world = scene.build().unwrap();
+        let written = Scene::from_world(&world).to_toml();
+        let read = Scene::parse(&written, ".").unwrap();
+        assert_eq!(read.to_toml(), written);
+
+        let camera = read.camera.as_ref().unwrap();
+        assert!(close(camera.r, [0.0, -200.0, -250.0]));
+        assert_eq!(camera.far, 900.0);
+        assert_eq!(read.lights.len(), 1);
+        assert_eq!(read.objects.len(), scene.objects.len());
+        for (read, original) in read.objects.iter().zip(scene.objects.iter()) {
+            assert!(original.name.is_none() || read.name == original.name);
+            assert_eq!(read.parent, original.parent);
+            assert_eq!(read.tags, original.tags);
+            assert!(close(read.position, original.position));
+            assert!(close(read.rotation, original.rotation));
+            assert!((read.scale - original.scale).abs() < 1e-9);
+            assert_eq!(read.mesh, original.mesh);
+            assert_eq!(read.material, original.material);
+        }
+    }
+
+    #[test]
+    fn mesh_paths_follow_the_scene_when_moved() {
+        let text = "[[object]]\n[object.mesh]\nkind = \"obj\"\npath = \"meshes/moon.obj\"\n";
+        let mut scene = Scene::parse(text, "scenes").unwrap();
+        assert_eq!(scene.source(&scene.objects[0]).mesh,
+                   Some(MeshSource::Obj { path: PathBuf::from("scenes/meshes/moon.obj") }));
+        assert_eq!(scene.source(&scene.objects[0]).material, Material::new());
+
+        scene.rebase("saves/slot1");
+        assert_eq!(scene.dir, Path::new("saves/slot1"));
+        assert_eq!(scene.objects[0].mesh,
+                   Some(MeshSource::Obj { path: PathBuf::from("../../scenes/meshes/moon.obj") }));
+
+        let text = "[[object]]\n[object.mesh]\nkind = \"obj\"\npath = \"/models/moon.obj\"\n";
+        let mut scene = Scene::parse(text, "scenes").unwrap();
+        scene.rebase("saves");
+        assert_eq!(scene.objects[0].mesh,
+                   Some(MeshSource::Obj { path: PathBuf::from("/models/moon.obj") }));
+    }
+}
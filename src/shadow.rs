@@ -0,0 +1,346 @@
+//! Shadows cast from the primary light
+//!
+//! The software path renders a shadow map, the distance to the nearest
+//! caster as seen from the light, and checks every pixel against it.
+//! The painter's path has nothing to compare depths with, so the shadows
+//! of moving meshes are flattened onto the face beneath them and drawn
+//! as dark translucent polygons instead.
+
+use std::f64::INFINITY;
+
+use bounds::Aabb;
+use bsp::BspPolygon;
+use depth::FaceRef;
+use lights::LightSource;
+use math::ray_triangle;
+use types::Vec3;
+use vecmath::vec3_add;
+use vecmath::vec3_cross;
+use vecmath::vec3_dot;
+use vecmath::vec3_len;
+use vecmath::vec3_normalized;
+use vecmath::vec3_scale;
+use vecmath::vec3_sub;
+use world::WorldObject;
+
+/// Widest the shadow map of a point light may open, as the slope
+/// `x / z` of its edges
+const MAX_SLOPE: f64 = 2.0;
+
+/// How far flattened shadows float above the face they fall on
+const PLANAR_OFFSET: f64 = 0.5;
+
+/// Coordinate frame looking from the light at the scene
+#[derive(Debug,Clone,Copy)]
+struct LightView {
+    origin: Vec3,
+    forward: Vec3,
+    right: Vec3,
+    up: Vec3,
+    /// Point lights see the scene in perspective, directional lights
+    /// straight on
+    perspective: bool,
+}
+
+impl LightView {
+    fn new(light: &LightSource, scene: &Aabb) -> LightView {
+        let center = scene.center();
+        let radius = vec3_len(vec3_sub(scene.max, scene.min)) / 2.0;
+        let (origin, forward) = if light.point_source {
+            (light.r, vec3_normalized(vec3_sub(center, light.r)))
+        } else {
+            let forward = light.ray(center);
+            (vec3_sub(center, vec3_scale(forward, radius * 2.0)), forward)
+        };
+        // Any vector that isn't parallel to forward gives a basis
+        let helper = if forward[1].abs() < 0.9 { [0.0, 1.0, 0.0] } else { [1.0, 0.0, 0.0] };
+        let right = vec3_normalized(vec3_cross(helper, forward));
+        LightView {
+            origin: origin,
+            forward: forward,
+            right: right,
+            up: vec3_cross(forward, right),
+            perspective: light.point_source,
+        }
+    }
+
+    /// Position across the light's view and distance along it
+    #[inline(always)]
+    fn project(&self, r: Vec3) -> [f64; 3] {
+        let d = vec3_sub(r, self.origin);
+        let z = vec3_dot(d, self.forward);
+        let x = vec3_dot(d, self.right);
+        let y = vec3_dot(d, self.up);
+        if self.perspective {
+            [x / z, y / z, z]
+        } else {
+            [x, y, z]
+        }
+    }
+}
+
+/// Depth of the nearest shadow caster as seen from the primary light
+#[derive(Debug)]
+pub struct ShadowMap {
+    size: usize,
+    depth: Vec<f32>,
+    /// `None` until rendered, or when nothing casts or receives shadows
+    view: Option<LightView>,
+    offset: [f64; 2],
+    scale: [f64; 2],
+}
+
+impl ShadowMap {
+    pub fn new(size: usize) -> ShadowMap {
+        ShadowMap {
+            size: size,
+            depth: vec![0.0; size * size],
+            view: None,
+            offset: [0.0; 2],
+            scale: [1.0; 2],
+        }
+    }
+
+    /// Render the casting meshes as seen from `light`, fitting the map
+    /// around every mesh that casts or receives shadows
+    pub fn render(&mut self, objects: &[WorldObject], light: &LightSource) {
+        let mut scene = Aabb::empty();
+        for object in objects.iter() {
+            for mesh in object.meshes.iter() {
+                if !mesh.cast_shadows && !mesh.receive_shadows {
+                    continue;
+                }
+                let bounds = mesh.mesh.bounds();
+                if !bounds.aabb.is_empty() {
+                    scene.grow(bounds.aabb.min);
+                    scene.grow(bounds.aabb.max);
+                }
+            }
+        }
+        if scene.is_empty() {
+            self.view = None;
+            return;
+        }
+
+        let view = LightView::new(light, &scene);
+        let (mut min, mut max) = ([INFINITY; 2], [-INFINITY; 2]);
+        for corner in scene.corners().iter() {
+            let p = view.project(*corner);
+            if p[2] <= 0.0 {
+                // Part of the scene is behind a point light
+                min = [-MAX_SLOPE; 2];
+                max = [MAX_SLOPE; 2];
+                break;
+            }
+            for i in 0..2 {
+                min[i] = min[i].min(p[i]);
+                max[i] = max[i].max(p[i]);
+            }
+        }
+        if view.perspective {
+            for i in 0..2 {
+                min[i] = min[i].max(-MAX_SLOPE);
+                max[i] = max[i].min(MAX_SLOPE);
+            }
+        }
+        let size = self.size as f64;
+        self.offset = min;
+        self.scale = [
+            size / (max[0] - min[0]).max(1e-9),
+            size / (max[1] - min[1]).max(1e-9),
+        ];
+
+        for d in self.depth.iter_mut() {
+            *d = ::std::f32::INFINITY;
+        }
+        for object in objects.iter() {
+            for mesh in object.meshes.iter() {
+                if !mesh.cast_shadows {
+                    continue;
+                }
+                for face in mesh.mesh.faces.borrow().iter() {
+                    let p = face.get_points();
+                    let q = [
+                        self.to_map(&view, p[0]),
+                        self.to_map(&view, p[1]),
+                        self.to_map(&view, p[2]),
+                    ];
+                    if q.iter().any(|q| q[2] <= 0.0) {
+                        continue;
+                    }
+                    self.fill(q, view.perspective);
+                }
+            }
+        }
+        self.view = Some(view);
+    }
+
+    #[inline(always)]
+    fn to_map(&self, view: &LightView, r: Vec3) -> [f64; 3] {
+        let p = view.project(r);
+        [
+            (p[0] - self.offset[0]) * self.scale[0],
+            (p[1] - self.offset[1]) * self.scale[1],
+            p[2],
+        ]
+    }
+
+    /// Write the nearest depth of a triangle in map coordinates
+    fn fill(&mut self, p: [[f64; 3]; 3], perspective: bool) {
+        let area = (p[1][0] - p[0][0]) * (p[2][1] - p[0][1]) -
+                   (p[1][1] - p[0][1]) * (p[2][0] - p[0][0]);
+        if area == 0.0 || area.is_nan() {
+            return;
+        }
+        let size = self.size as f64;
+        let x0 = p.iter().fold(INFINITY, |m, q| m.min(q[0])).floor().max(0.0) as usize;
+        let y0 = p.iter().fold(INFINITY, |m, q| m.min(q[1])).floor().max(0.0) as usize;
+        let x1 = p.iter().fold(-INFINITY, |m, q| m.max(q[0])).ceil().max(0.0).min(size) as usize;
+        let y1 = p.iter().fold(-INFINITY, |m, q| m.max(q[1])).ceil().max(0.0).min(size) as usize;
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let (sx, sy) = (x as f64 + 0.5, y as f64 + 0.5);
+                let mut w = [0.0; 3];
+                for i in 0..3 {
+                    let a = p[(i + 1) % 3];
+                    let b = p[(i + 2) % 3];
+                    w[i] = ((b[0] - a[0]) * (sy - a[1]) - (b[1] - a[1]) * (sx - a[0])) / area;
+                }
+                if w[0] < 0.0 || w[1] < 0.0 || w[2] < 0.0 {
+                    continue;
+                }
+                // Depth is linear across the map for directional lights,
+                // its reciprocal is for point lights
+                let z = if perspective {
+                    1.0 / (w[0] / p[0][2] + w[1] / p[1][2] + w[2] / p[2][2])
+                } else {
+                    w[0] * p[0][2] + w[1] * p[1][2] + w[2] * p[2][2]
+                };
+                let z = z as f32;
+                let i = y * self.size + x;
+                if z < self.depth[i] {
+                    self.depth[i] = z;
+                }
+            }
+        }
+    }
+
+    /// How much of the light reaches `r`, from 0 in full shadow to 1,
+    /// averaged over the neighbouring texels to soften the edges
+    pub fn lit(&self, r: Vec3) -> f32 {
+        let view = match self.view {
+            Some(ref view) => view,
+            None => return 1.0,
+        };
+        let p = self.to_map(view, r);
+        if p[2] <= 0.0 {
+            return 1.0;
+        }
+
+        // Texels cover more of the world further from a point light
+        let texel = (if view.perspective { p[2] } else { 1.0 }) / self.scale[0].min(self.scale[1]);
+        let bias = (1.0 + 1.5 * texel) as f32;
+        let z = p[2] as f32 - bias;
+
+        let (cx, cy) = (p[0].floor() as isize, p[1].floor() as isize);
+        let size = self.size as isize;
+        let mut lit = 0;
+        for dy in -1..2 {
+            for dx in -1..2 {
+                let (x, y) = (cx + dx, cy + dy);
+                if x < 0 || y < 0 || x >= size || y >= size ||
+                   z <= self.depth[(y * size + x) as usize] {
+                    lit += 1;
+                }
+            }
+        }
+        lit as f32 / 9.0
+    }
+}
+
+/// Flatten the shadows of moving meshes onto the plane of the face
+/// beneath their center, replacing the contents of `out`
+pub fn planar_shadows(objects: &[WorldObject], light: &LightSource, out: &mut Vec<BspPolygon>) {
+    out.clear();
+    for (o, object) in objects.iter().enumerate() {
+        for (m, caster) in object.meshes.iter().enumerate() {
+            if !caster.cast_shadows || caster.static_geometry {
+                continue;
+            }
+            let center = {
+                let bounds = caster.mesh.bounds();
+                if bounds.aabb.is_empty() {
+                    continue;
+                }
+                bounds.sphere.center
+            };
+            let (normal, d) = match receiving_plane(objects, (o, m), center, light.ray(center)) {
+                Some(plane) => plane,
+                None => continue,
+            };
+
+            for (i, face) in caster.mesh.faces.borrow().iter().enumerate() {
+                // Only the faces toward the light, for a closed convex mesh
+                // their shadows cover the whole shadow exactly once
+                let centroid = face.centroid();
+                let mut outward = face.normal();
+                if vec3_dot(outward, vec3_sub(centroid, center)) < 0.0 {
+                    outward = vec3_scale(outward, -1.0);
+                }
+                if !(vec3_dot(outward, light.toward(centroid)) > 0.0) {
+                    continue;
+                }
+
+                let mut points = Vec::with_capacity(3);
+                for &r in face.get_points().iter() {
+                    let ray = light.ray(r);
+                    let along = vec3_dot(normal, ray);
+                    if along.abs() < 1e-9 {
+                        break;
+                    }
+                    let t = (d - vec3_dot(normal, r)) / along;
+                    let flat = vec3_add(r, vec3_scale(ray, t));
+                    points.push(vec3_add(flat, vec3_scale(normal, PLANAR_OFFSET)));
+                }
+                if points.len() == 3 {
+                    out.push(BspPolygon {
+                        points: points,
+                        face: FaceRef { object: o as u32, mesh: m as u32, face: i as u32 },
+                        shadow: true,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Plane of the nearest shadow receiving face hit by a ray, with its
+/// normal facing back along the ray
+fn receiving_plane(
+    objects: &[WorldObject],
+    caster: (usize, usize),
+    origin: Vec3,
+    ray: Vec3
+) -> Option<(Vec3, f64)> {
+    let mut nearest: Option<(f64, Vec3, Vec3)> = None;
+    for (o, object) in objects.iter().enumerate() {
+        for (m, mesh) in object.meshes.iter().enumerate() {
+            if !mesh.receive_shadows || (o, m) == caster {
+                continue;
+            }
+            for face in mesh.mesh.faces.borrow().iter() {
+                let points = face.get_points();
+                if let Some(t) = ray_triangle(origin, ray, points) {
+                    if nearest.map_or(true, |n| t < n.0) {
+                        nearest = Some((t, face.normal(), points[0]));
+                    }
+                }
+            }
+        }
+    }
+    nearest.map(|(_, normal, r)| {
+        let normal = if vec3_dot(normal, ray) > 0.0 { vec3_scale(normal, -1.0) } else { normal };
+        (normal, vec3_dot(normal, r))
+    })
+}
@@ -0,0 +1,231 @@
+//! Sky gradient, sun, moon and the time of day
+//!
+//! The sky keeps a clock that moves the sun and moon around the world.
+//! As the sun rises and sets the sky colors, the primary light and the
+//! fog blend between night, twilight and day settings.
+
+use std::f64::consts::PI;
+
+use camera::Camera;
+use fog::Fog;
+use lights::LightSource;
+use render::Blend;
+use render::Canvas;
+use types::Color;
+use types::Vec3;
+use vecmath::vec3_add;
+use vecmath::vec3_normalized;
+use vecmath::vec3_scale;
+
+/// Number of horizontal bands the sky gradient is drawn with
+const SKY_BANDS: usize = 32;
+
+/// Depth the sky is drawn at, behind everything else
+const SKY_DEPTH: f64 = 1e9;
+
+/// Number of sides of the polygons drawn for the sun and moon
+const DISC_SIDES: usize = 16;
+
+/// Sky and light settings for one sun elevation
+#[derive(Debug,Clone,Copy)]
+pub struct SkyColors {
+    /// Color straight up
+    pub zenith: Color,
+    /// Color at the horizon, also used for the fog
+    pub horizon: Color,
+    /// Color of the primary light
+    pub light: Vec3,
+    pub intensity: f32,
+}
+
+/// The sky and the time of day
+#[derive(Debug,Clone)]
+pub struct Sky {
+    /// Hours since midnight, from 0 to 24
+    pub time: f64,
+    /// Real seconds a whole day takes
+    pub day_length: f64,
+    pub paused: bool,
+    /// How far the path of the sun leans away from straight overhead
+    pub tilt: f64,
+    pub night: SkyColors,
+    pub twilight: SkyColors,
+    pub day: SkyColors,
+    pub sun_color: Color,
+    pub moon_color: Color,
+    /// Radius of the sun and moon as a fraction of the screen height
+    pub disc_size: f64,
+}
+
+#[inline(always)]
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn lerp_color(a: Color, b: Color, t: f32) -> Color {
+    [lerp(a[0], b[0], t), lerp(a[1], b[1], t), lerp(a[2], b[2], t), lerp(a[3], b[3], t)]
+}
+
+fn lerp_colors(a: &SkyColors, b: &SkyColors, t: f32) -> SkyColors {
+    let t64 = t as f64;
+    SkyColors {
+        zenith: lerp_color(a.zenith, b.zenith, t),
+        horizon: lerp_color(a.horizon, b.horizon, t),
+        light: [
+            a.light[0] + (b.light[0] - a.light[0]) * t64,
+            a.light[1] + (b.light[1] - a.light[1]) * t64,
+            a.light[2] + (b.light[2] - a.light[2]) * t64,
+        ],
+        intensity: lerp(a.intensity, b.intensity, t),
+    }
+}
+
+/// Where `x` is between `a` and `b`, clamped to 0 and 1
+#[inline(always)]
+fn between(x: f64, a: f64, b: f64) -> f32 {
+    ((x - a) / (b - a)).max(0.0).min(1.0) as f32
+}
+
+impl Sky {
+    pub fn new() -> Sky {
+        Sky {
+            time: 8.0,
+            day_length: 240.0,
+            paused: false,
+            tilt: 0.3,
+            night: SkyColors {
+                zenith: [0.01, 0.01, 0.05, 1.0],
+                horizon: [0.05, 0.06, 0.12, 1.0],
+                light: [0.4, 0.45, 0.7],
+                intensity: 0.35,
+            },
+            twilight: SkyColors {
+                zenith: [0.2, 0.25, 0.5, 1.0],
+                horizon: [0.9, 0.5, 0.3, 1.0],
+                light: [1.0, 0.6, 0.4],
+                intensity: 0.7,
+            },
+            day: SkyColors {
+                zenith: [0.25, 0.45, 0.85, 1.0],
+                horizon: [0.65, 0.75, 0.9, 1.0],
+                light: [1.0, 1.0, 0.95],
+                intensity: 1.0,
+            },
+            sun_color: [1.0, 0.95, 0.7, 1.0],
+            moon_color: [0.85, 0.88, 0.95, 1.0],
+            disc_size: 0.04,
+        }
+    }
+
+    /// Set the time of day in hours since midnight
+    pub fn time(mut self, hours: f64) -> Sky {
+        self.time = hours % 24.0;
+        self
+    }
+
+    /// Set how many real seconds a whole day takes
+    pub fn day_length(mut self, seconds: f64) -> Sky {
+        self.day_length = seconds;
+        self
+    }
+
+    /// Move the clock forward by `dt` real seconds unless paused
+    pub fn advance(&mut self, dt: f64) {
+        if self.paused || self.day_length <= 0.0 {
+            return;
+        }
+        self.time = (self.time + dt * 24.0 / self.day_length) % 24.0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    /// Unit vector toward the sun, which rises at 6:00 and sets at 18:00
+    pub fn sun_direction(&self) -> Vec3 {
+        let angle = (self.time - 6.0) / 24.0 * 2.0 * PI;
+        // Up is -y in the world
+        vec3_normalized([angle.cos(), -angle.sin(), self.tilt])
+    }
+
+    /// Unit vector toward the moon, always opposite the sun
+    pub fn moon_direction(&self) -> Vec3 {
+        vec3_scale(self.sun_direction(), -1.0)
+    }
+
+    /// How high the sun is, from -1 straight down to 1 straight up
+    pub fn sun_elevation(&self) -> f64 {
+        -self.sun_direction()[1]
+    }
+
+    /// Sky and light settings for the current time of day
+    pub fn colors(&self) -> SkyColors {
+        let e = self.sun_elevation();
+        if e < 0.1 {
+            lerp_colors(&self.night, &self.twilight, between(e, -0.2, 0.1))
+        } else {
+            lerp_colors(&self.twilight, &self.day, between(e, 0.1, 0.4))
+        }
+    }
+
+    /// Point the primary light at the sun, or at the moon once the sun
+    /// is down, and fade the fog into the horizon
+    pub fn apply(&self, light: &mut LightSource, fog: &mut Fog) {
+        let colors = self.colors();
+        let direction = if self.sun_elevation() > -0.05 {
+            self.sun_direction()
+        } else {
+            self.moon_direction()
+        };
+        light.point_source = false;
+        light.direction = direction;
+        light.r = vec3_scale(direction, 1e6);
+        light.color = colors.light;
+        light.intensity = colors.intensity;
+        fog.color = colors.horizon;
+    }
+
+    /// Color of the sky along a world direction
+    fn color_at(&self, colors: &SkyColors, direction: Vec3) -> Color {
+        let e = -vec3_normalized(direction)[1];
+        lerp_color(colors.horizon, colors.zenith, e.max(0.0).sqrt() as f32)
+    }
+
+    /// Draw the sky gradient, sun and moon behind everything else
+    pub fn draw<C>(&self, camera: &Camera, canvas: &mut C) where C: Canvas {
+        let colors = self.colors();
+        let (w, h) = (camera.width, camera.height);
+        let band = h / SKY_BANDS as f64;
+        for i in 0..SKY_BANDS {
+            let y = i as f64 * band;
+            let color = self.color_at(&colors, camera.unproject([w / 2.0, y + band / 2.0]));
+            canvas.polygon(&[
+                [0.0, y, SKY_DEPTH],
+                [w, y, SKY_DEPTH],
+                [w, y + band, SKY_DEPTH],
+                [0.0, y + band, SKY_DEPTH],
+            ], color, Blend::Alpha);
+        }
+
+        let moon = self.moon_color;
+        let sun = self.sun_color;
+        self.draw_disc(camera, self.moon_direction(), moon, canvas);
+        self.draw_disc(camera, self.sun_direction(), sun, canvas);
+    }
+
+    fn draw_disc<C>(&self, camera: &Camera, direction: Vec3, color: Color, canvas: &mut C)
+        where C: Canvas
+    {
+        let center = camera.project(vec3_add(camera.r, vec3_scale(direction, SKY_DEPTH)));
+        if center[0].is_nan() {
+            return;
+        }
+        let radius = self.disc_size * camera.height;
+        let mut points = Vec::with_capacity(DISC_SIDES);
+        for i in 0..DISC_SIDES {
+            let a = i as f64 / DISC_SIDES as f64 * 2.0 * PI;
+            points.push([center[0] + radius * a.cos(), center[1] + radius * a.sin(), SKY_DEPTH]);
+        }
+        canvas.polygon(&points, color, Blend::Alpha);
+    }
+}
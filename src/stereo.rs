@@ -0,0 +1,90 @@
+//! Stereo rendering from two eyes
+//!
+//! The scene is rendered once from each eye, either side of the camera,
+//! and the two frames are combined into a red/cyan anaglyph or placed
+//! next to each other for cardboard style viewers.
+
+use std::mem;
+
+use camera::Camera;
+use raster::Framebuffer;
+use vecmath::vec3_add;
+use vecmath::vec3_scale;
+
+/// How the two eyes are combined into one frame
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum StereoMode {
+    /// Red from the left eye, green and blue from the right eye
+    Anaglyph,
+    /// Left eye in the left half of the frame, right eye in the right
+    SideBySide,
+}
+
+/// Settings for stereo rendering
+#[derive(Debug,Clone,Copy)]
+pub struct Stereo {
+    pub mode: StereoMode,
+    /// Distance between the eyes
+    pub interocular: f64,
+    /// Distance from the camera at which both eyes see a point in the
+    /// same place, nearer things pop out of the screen
+    pub convergence: f64,
+}
+
+impl Stereo {
+    pub fn new(mode: StereoMode) -> Stereo {
+        Stereo {
+            mode: mode,
+            interocular: 4.0,
+            convergence: 250.0,
+        }
+    }
+
+    pub fn interocular(mut self, interocular: f64) -> Stereo {
+        self.interocular = interocular;
+        self
+    }
+
+    pub fn convergence(mut self, convergence: f64) -> Stereo {
+        self.convergence = convergence;
+        self
+    }
+
+    /// The camera for one eye, `side` is -1 for the left eye and 1 for
+    /// the right. The image is shifted so the eyes converge instead of
+    /// turning them, which would add vertical parallax.
+    pub fn eye(&self, camera: &Camera, side: f64) -> Camera {
+        let mut eye = camera.clone();
+        let offset = side * self.interocular / 2.0;
+        // The first row of the projection is the camera's right in the world
+        eye.r = vec3_add(camera.r, vec3_scale(camera.projection[0], offset));
+        eye.shift = camera.shift + camera.pixels_across(offset, self.convergence.max(1e-9));
+        eye
+    }
+}
+
+/// Combine the left eye into the right eye's frame as a red/cyan anaglyph
+pub fn anaglyph(left: &Framebuffer, right: &mut Framebuffer) {
+    for (r, l) in right.color.iter_mut().zip(left.color.iter()) {
+        r[0] = l[0];
+    }
+}
+
+/// Put the left and right eyes next to each other in the right eye's
+/// frame, widening it to `width`
+pub fn side_by_side(left: &Framebuffer, right: &mut Framebuffer, width: usize) {
+    let (w, h) = (right.width, right.height);
+    let right_color = mem::replace(&mut right.color, vec![]);
+    right.resize(width, h);
+    right.color.resize(width * h, [0.0; 4]);
+    for y in 0..h {
+        for x in 0..width {
+            let c = if x < w {
+                left.color[y * w + x]
+            } else {
+                right_color[y * w + (x - w).min(w - 1)]
+            };
+            right.color[y * width + x] = c;
+        }
+    }
+}
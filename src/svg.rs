@@ -0,0 +1,76 @@
+//! Exporting frames as SVG vector art
+//!
+//! The painter's path hands the canvas flat polygons back to front, so
+//! writing each one out as an SVG `<polygon>` in the order it arrives
+//! gives the frame exactly, at any resolution.
+
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use render::Blend;
+use render::Canvas;
+use types::Color;
+
+/// Canvas collecting polygons into an SVG document
+#[derive(Debug)]
+pub struct SvgCanvas {
+    width: f64,
+    height: f64,
+    body: String,
+}
+
+/// An SVG color from a color channel between 0 and 1
+#[inline(always)]
+fn channel(c: f32) -> u8 {
+    (c.max(0.0).min(1.0) * 255.0 + 0.5) as u8
+}
+
+impl SvgCanvas {
+    pub fn new(width: f64, height: f64) -> SvgCanvas {
+        SvgCanvas { width: width, height: height, body: String::new() }
+    }
+
+    /// Fill the whole canvas with one color
+    pub fn background(&mut self, color: Color) {
+        let (w, h) = (self.width, self.height);
+        self.polygon(&[[0.0, 0.0, 0.0], [w, 0.0, 0.0], [w, h, 0.0], [0.0, h, 0.0]],
+                     color, Blend::Opaque);
+    }
+
+    /// The finished SVG document
+    pub fn to_svg(&self) -> String {
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+             viewBox=\"0 0 {w} {h}\">\n{body}</svg>\n",
+            w = self.width, h = self.height, body = self.body)
+    }
+
+    pub fn save<P>(&self, path: P) -> io::Result<()> where P: AsRef<Path> {
+        let mut file = try!(File::create(path));
+        file.write_all(self.to_svg().as_bytes())
+    }
+}
+
+impl Canvas for SvgCanvas {
+    fn polygon(&mut self, points: &[[f64; 3]], color: Color, _blend: Blend) {
+        if points.len() < 3 || points.iter().any(|p| p[0].is_nan() || p[1].is_nan()) {
+            return;
+        }
+        self.body.push_str("<polygon points=\"");
+        for (i, p) in points.iter().enumerate() {
+            if i > 0 {
+                self.body.push(' ');
+            }
+            write!(self.body, "{:.2},{:.2}", p[0], p[1]).unwrap();
+        }
+        write!(self.body, "\" fill=\"#{:02x}{:02x}{:02x}\"",
+               channel(color[0]), channel(color[1]), channel(color[2])).unwrap();
+        if color[3] < 1.0 {
+            write!(self.body, " fill-opacity=\"{:.3}\"", color[3].max(0.0)).unwrap();
+        }
+        self.body.push_str("/>\n");
+    }
+}
@@ -0,0 +1,286 @@
+//! Running a world in a terminal
+//!
+//! Frames go through the software rasterizer and are printed with ANSI
+//! truecolor escapes, two pixels to a character cell: an upper half block
+//! colored with the top pixel over a background of the bottom one. Keys
+//! are read from the terminal in raw mode, so this works over SSH with
+//! no display.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use behaviour::Event;
+use clock::seconds;
+use input;
+use piston::input::Button;
+use piston::input::Key;
+use raster::Framebuffer;
+use types::Color;
+use world::World;
+
+/// Seconds between frames
+const FRAME_TIME: f64 = 1.0 / 30.0;
+
+/// Seconds between checks of the terminal size
+const RESIZE_CHECK: f64 = 1.0;
+
+/// Seconds a key stays held after the terminal first sent it, longer
+/// than the usual delay before key repeat starts
+const FIRST_HOLD: f64 = 0.7;
+
+/// Seconds a key stays held after a repeat, longer than the gap between
+/// key repeats
+const REPEAT_HOLD: f64 = 0.15;
+
+/// Seconds to wait for the rest of an escape sequence split between
+/// reads before taking a lone ESC as the Escape key
+const ESCAPE_WAIT: f64 = 0.05;
+
+/// Upper half block, the top pixel is the foreground color
+const HALF_BLOCK: char = '\u{2580}';
+
+/// Puts the terminal in raw mode on an alternate screen, restoring it
+/// when dropped
+struct RawMode {
+    saved: String,
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = try!(Command::new("stty").args(args).stdin(Stdio::inherit()).output());
+    if !output.status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("stty {} failed", args.join(" "))));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let saved = try!(stty(&["-g"]));
+        try!(stty(&["raw", "-echo"]));
+        print!("\x1b[?1049h\x1b[?25l");
+        try!(io::stdout().flush());
+        Ok(RawMode { saved: saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+/// Columns and rows of the terminal
+fn terminal_size() -> io::Result<(usize, usize)> {
+    let size = try!(stty(&["size"]));
+    let mut parts = size.split_whitespace().map(|p| p.parse::<usize>().ok());
+    match (parts.next(), parts.next()) {
+        (Some(Some(rows)), Some(Some(cols))) => Ok((cols.max(1), rows.max(1))),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                format!("unexpected terminal size {:?}", size))),
+    }
+}
+
+/// Read bytes from the terminal on another thread so the frame loop
+/// never blocks on input
+fn spawn_input() -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in io::stdin().bytes() {
+            match byte {
+                Ok(b) => if tx.send(b).is_err() { break },
+                Err(_) => break,
+            }
+        }
+    });
+    rx
+}
+
+/// Press the buttons for the keys typed since the last frame, false
+/// once the user asks to quit. A terminal sends key repeats but never
+/// releases, so each key is held for a moment after it was last sent,
+/// long enough the first time for the repeats to start.
+fn handle_keys(world: &mut World, bytes: &[u8], held: &mut HashMap<Button, f64>) -> bool {
+    let mut tap = |world: &mut World, button: Button| {
+        let hold = if held.contains_key(&button) { REPEAT_HOLD } else { FIRST_HOLD };
+        world.press(button);
+        held.insert(button, hold);
+    };
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            // Arrow keys arrive as ESC [ A to D
+            0x1b if i + 2 < bytes.len() && bytes[i + 1] == b'[' => {
+                let key = match bytes[i + 2] {
+                    b'A' => Some(Key::Up),
+                    b'B' => Some(Key::Down),
+                    b'C' => Some(Key::Right),
+                    b'D' => Some(Key::Left),
+                    _ => None,
+                };
+                if let Some(key) = key {
+                    tap(world, Button::Keyboard(key));
+                }
+                i += 3;
+                continue;
+            },
+            // Escape on its own, q or ctrl-c
+            0x1b | b'q' | 3 => return false,
+            b => {
+                let name = match b {
+                    b' ' => "space".to_string(),
+                    b'\r' => "return".to_string(),
+                    b'\t' => "tab".to_string(),
+                    127 => "backspace".to_string(),
+                    _ => (b.to_ascii_lowercase() as char).to_string(),
+                };
+                for button in input::buttons(&name).into_iter() {
+                    tap(world, button);
+                }
+                world.broadcast(Event::Key((b as char).to_string()));
+            },
+        }
+        i += 1;
+    }
+    true
+}
+
+/// How many bytes at the end may be the start of an arrow key sequence
+/// whose rest hasn't been read yet
+fn partial_escape(bytes: &[u8]) -> usize {
+    let n = bytes.len();
+    if n >= 2 && bytes[n - 2] == 0x1b && bytes[n - 1] == b'[' {
+        2
+    } else if n >= 1 && bytes[n - 1] == 0x1b {
+        1
+    } else {
+        0
+    }
+}
+
+/// Release the keys not sent again for long enough
+fn release_keys(world: &mut World, dt: f64, held: &mut HashMap<Button, f64>) {
+    let mut expired = vec![];
+    for (&button, left) in held.iter_mut() {
+        *left -= dt;
+        if *left <= 0.0 {
+            expired.push(button);
+        }
+    }
+    for button in expired.into_iter() {
+        held.remove(&button);
+        world.release(button);
+    }
+}
+
+#[inline(always)]
+fn rgb(c: Color) -> (u8, u8, u8) {
+    let q = |x: f32| (x.max(0.0).min(1.0) * 255.0 + 0.5) as u8;
+    (q(c[0]), q(c[1]), q(c[2]))
+}
+
+/// Write a frame as rows of half blocks, only changing colors where
+/// they differ from the previous cell
+fn draw(framebuffer: &Framebuffer, out: &mut String) {
+    use std::fmt::Write;
+
+    out.clear();
+    let w = framebuffer.width;
+    for row in 0..framebuffer.height / 2 {
+        write!(out, "\x1b[{};1H", row + 1).unwrap();
+        let mut last = None;
+        for x in 0..w {
+            let top = rgb(framebuffer.color[2 * row * w + x]);
+            let bottom = rgb(framebuffer.color[(2 * row + 1) * w + x]);
+            if last != Some((top, bottom)) {
+                write!(out, "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m",
+                       top.0, top.1, top.2, bottom.0, bottom.1, bottom.2).unwrap();
+                last = Some((top, bottom));
+            }
+            out.push(HALF_BLOCK);
+        }
+    }
+    out.push_str("\x1b[0m");
+}
+
+/// Show the world in the terminal until q or escape is pressed
+pub fn run(mut world: World) -> io::Result<()> {
+    let (mut cols, mut rows) = try!(terminal_size());
+    let _raw = try!(RawMode::enable());
+    let keys = spawn_input();
+
+    let mut out = String::new();
+    let mut bytes = vec![];
+    let mut held = HashMap::new();
+    let mut last = Instant::now();
+    let mut since_resize = 0.0;
+    let mut escape_since = None;
+
+    loop {
+        while let Ok(b) = keys.try_recv() {
+            bytes.push(b);
+        }
+        // Hold back what may be the start of an escape sequence until
+        // the rest arrives or it has waited long enough to be a key
+        let partial = partial_escape(&bytes);
+        if partial == 0 {
+            escape_since = None;
+        } else if escape_since.is_none() {
+            escape_since = Some(Instant::now());
+        }
+        let waiting = match escape_since {
+            Some(since) => seconds(since.elapsed()) < ESCAPE_WAIT,
+            None => false,
+        };
+        let complete = if waiting { bytes.len() - partial } else { bytes.len() };
+        if !handle_keys(&mut world, &bytes[..complete], &mut held) {
+            break;
+        }
+        bytes.drain(..complete);
+
+        let now = Instant::now();
+        let dt = seconds(now.duration_since(last));
+        last = now;
+        world.advance(dt);
+        release_keys(&mut world, dt, &mut held);
+
+        since_resize += dt;
+        if since_resize >= RESIZE_CHECK {
+            since_resize = 0.0;
+            let size = try!(terminal_size());
+            if size != (cols, rows) {
+                cols = size.0;
+                rows = size.1;
+                print!("\x1b[2J");
+            }
+        }
+
+        world.rasterize(cols, rows * 2);
+        draw(&world.framebuffer, &mut out);
+        {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            try!(stdout.write_all(out.as_bytes()));
+            try!(stdout.flush());
+        }
+
+        let spent = seconds(now.elapsed());
+        if spent < FRAME_TIME {
+            let wait = ((FRAME_TIME - spent) * 1e9) as u32;
+            thread::sleep(Duration::new(0, wait));
+        }
+    }
+    Ok(())
+}
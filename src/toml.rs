@@ -0,0 +1,506 @@
+//! A reader for the small subset of TOML used by scene and config files
+//!
+//! Comments, `[table]` and `[[array]]` headers with dotted names, and
+//! `key = value` pairs where a value is a string, a number, a boolean or
+//! an array of values, which may span lines. Tables are kept in file
+//! order and every value knows where it was, so callers can point at the
+//! line and column of a bad value.
+
+use std::fmt;
+
+use types::Color;
+use types::Vec3;
+
+/// Line and column in a file, both from 1
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// What went wrong and where
+#[derive(Debug,Clone,PartialEq)]
+pub struct Error {
+    pub position: Position,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(position: Position, message: String) -> Error {
+        Error { position: position, message: message }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.position, self.message)
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f64),
+    Bool(bool),
+    Array(Vec<Item>),
+}
+
+/// A value and where it starts
+#[derive(Debug,Clone,PartialEq)]
+pub struct Item {
+    pub value: Value,
+    pub position: Position,
+}
+
+impl Item {
+    fn expected<T>(&self, what: &str) -> Result<T, Error> {
+        let found = match self.value {
+            Value::String(_) => "a string",
+            Value::Number(_) => "a number",
+            Value::Bool(_) => "a boolean",
+            Value::Array(_) => "an array",
+        };
+        Err(Error::new(self.position, format!("expected {}, found {}", what, found)))
+    }
+
+    pub fn as_str(&self) -> Result<&str, Error> {
+        match self.value {
+            Value::String(ref s) => Ok(s),
+            _ => self.expected("a string"),
+        }
+    }
+
+    pub fn as_number(&self) -> Result<f64, Error> {
+        match self.value {
+            Value::Number(x) => Ok(x),
+            _ => self.expected("a number"),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool, Error> {
+        match self.value {
+            Value::Bool(b) => Ok(b),
+            _ => self.expected("a boolean"),
+        }
+    }
+
+    pub fn as_array(&self) -> Result<&[Item], Error> {
+        match self.value {
+            Value::Array(ref items) => Ok(items),
+            _ => self.expected("an array"),
+        }
+    }
+
+    /// An array of exactly `n` numbers
+    pub fn as_numbers(&self, n: usize) -> Result<Vec<f64>, Error> {
+        let items = try!(self.as_array());
+        if items.len() != n {
+            return Err(Error::new(self.position,
+                                  format!("expected {} numbers, found {}", n, items.len())));
+        }
+        items.iter().map(|item| item.as_number()).collect()
+    }
+
+    pub fn as_vec3(&self) -> Result<Vec3, Error> {
+        let x = try!(self.as_numbers(3));
+        Ok([x[0], x[1], x[2]])
+    }
+
+    /// Red, green and blue with an optional alpha
+    pub fn as_color(&self) -> Result<Color, Error> {
+        let n = try!(self.as_array()).len();
+        let x = try!(self.as_numbers(if n == 4 { 4 } else { 3 }));
+        Ok([x[0] as f32, x[1] as f32, x[2] as f32, if n == 4 { x[3] as f32 } else { 1.0 }])
+    }
+
+    pub fn as_strings(&self) -> Result<Vec<String>, Error> {
+        let items = try!(self.as_array());
+        items.iter().map(|item| item.as_str().map(|s| s.to_string())).collect()
+    }
+}
+
+/// The pairs under one header, the pairs before any header are in a
+/// table named ""
+#[derive(Debug,Clone,PartialEq)]
+pub struct Table {
+    /// Dotted name in the header
+    pub name: String,
+    /// Whether the header was `[[name]]`
+    pub array: bool,
+    pub position: Position,
+    pub entries: Vec<(String, Item)>,
+}
+
+impl Table {
+    pub fn get(&self, key: &str) -> Option<&Item> {
+        self.entries.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref item)| item)
+    }
+
+    /// Fail on the first key not in `known`, catching typos
+    pub fn check_keys(&self, known: &[&str]) -> Result<(), Error> {
+        for &(ref key, ref item) in self.entries.iter() {
+            if !known.contains(&key.as_str()) {
+                return Err(Error::new(item.position,
+                                      format!("unknown key `{}` in [{}]", key, self.name)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn number(&self, key: &str, default: f64) -> Result<f64, Error> {
+        self.get(key).map_or(Ok(default), |item| item.as_number())
+    }
+
+    pub fn boolean(&self, key: &str, default: bool) -> Result<bool, Error> {
+        self.get(key).map_or(Ok(default), |item| item.as_bool())
+    }
+
+    pub fn vec3(&self, key: &str, default: Vec3) -> Result<Vec3, Error> {
+        self.get(key).map_or(Ok(default), |item| item.as_vec3())
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<String>, Error> {
+        match self.get(key) {
+            Some(item) => item.as_str().map(|s| Some(s.to_string())),
+            None => Ok(None),
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    i: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).cloned()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek();
+        if let Some(c) = c {
+            self.i += 1;
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        c
+    }
+
+    fn position(&self) -> Position {
+        Position { line: self.line, column: self.column }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, Error> {
+        Err(Error::new(self.position(), message))
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        match self.peek() {
+            Some(d) if d == c => {
+                self.bump();
+                Ok(())
+            },
+            Some(d) => self.error(format!("expected `{}`, found `{}`", c, d)),
+            None => self.error(format!("expected `{}`, found the end of the file", c)),
+        }
+    }
+
+    /// Skip spaces and tabs
+    fn skip_space(&mut self) {
+        while self.peek() == Some(' ') || self.peek() == Some('\t') {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while self.peek().map_or(false, |c| c != '\n') {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip whitespace, newlines and comments
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') | Some('\r') | Some('\n') => { self.bump(); },
+                Some('#') => self.skip_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    /// Nothing but a comment may follow on the line
+    fn end_of_line(&mut self) -> Result<(), Error> {
+        self.skip_space();
+        self.skip_comment();
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') if self.chars.get(self.i + 1) == Some(&'\n') => Ok(()),
+            Some(c) => self.error(format!("unexpected `{}` after the value", c)),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, Error> {
+        if self.peek() == Some('"') {
+            return self.string();
+        }
+        let mut key = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' || c == '-' {
+                key.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        if key.is_empty() {
+            match self.peek() {
+                Some(c) => self.error(format!("expected a key, found `{}`", c)),
+                None => self.error("expected a key".to_string()),
+            }
+        } else {
+            Ok(key)
+        }
+    }
+
+    /// A header name, keys joined by dots
+    fn name(&mut self) -> Result<String, Error> {
+        let mut name = try!(self.key());
+        loop {
+            self.skip_space();
+            if self.peek() != Some('.') {
+                return Ok(name);
+            }
+            self.bump();
+            self.skip_space();
+            name.push('.');
+            name.push_str(&try!(self.key()));
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let start = self.position();
+        try!(self.expect('"'));
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.bump() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some(c) => return self.error(format!("unknown escape `\\{}`", c)),
+                        None => return Err(Error::new(start, "unterminated string".to_string())),
+                    };
+                    s.push(c);
+                },
+                Some('\n') | None => {
+                    return Err(Error::new(start, "unterminated string".to_string()));
+                },
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Item, Error> {
+        let position = self.position();
+        let value = match self.peek() {
+            Some('"') => Value::String(try!(self.string())),
+            Some('[') => {
+                self.bump();
+                let mut items = vec![];
+                loop {
+                    self.skip_blank();
+                    if self.peek() == Some(']') {
+                        self.bump();
+                        break;
+                    }
+                    items.push(try!(self.value()));
+                    self.skip_blank();
+                    match self.peek() {
+                        Some(',') => { self.bump(); },
+                        Some(']') => {},
+                        _ => return self.error("expected `,` or `]` in the array".to_string()),
+                    }
+                }
+                Value::Array(items)
+            },
+            Some(c) if c.is_alphabetic() => {
+                let word = try!(self.key());
+                match word.as_str() {
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    // Strings must be quoted
+                    _ => return Err(Error::new(position, format!("unexpected `{}`, strings \
+                                                                  need quotes", word))),
+                }
+            },
+            Some(_) => {
+                let mut number = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_digit(10) || "+-.eE_".contains(c) {
+                        number.push(c);
+                        self.bump();
+                    } else {
+                        break;
+                    }
+                }
+                match number.replace("_", "").parse::<f64>() {
+                    Ok(x) if !number.is_empty() => Value::Number(x),
+                    _ => return Err(Error::new(position, format!("invalid value `{}`", number))),
+                }
+            },
+            None => return self.error("expected a value".to_string()),
+        };
+        Ok(Item { value: value, position: position })
+    }
+}
+
+/// Read the tables of a file in order
+pub fn parse(text: &str) -> Result<Vec<Table>, Error> {
+    let mut parser = Parser { chars: text.chars().collect(), i: 0, line: 1, column: 1 };
+    let mut tables = vec![Table {
+        name: String::new(),
+        array: false,
+        position: parser.position(),
+        entries: vec![],
+    }];
+
+    loop {
+        parser.skip_blank();
+        let position = parser.position();
+        match parser.peek() {
+            None => break,
+            Some('[') => {
+                parser.bump();
+                let array = parser.peek() == Some('[');
+                if array {
+                    parser.bump();
+                }
+                parser.skip_space();
+                let name = try!(parser.name());
+                try!(parser.expect(']'));
+                if array {
+                    try!(parser.expect(']'));
+                }
+                try!(parser.end_of_line());
+                // Dotted tables belong to the latest array entry and may
+                // repeat for each one
+                if !array && !name.contains('.') &&
+                    tables.iter().any(|t| !t.array && t.name == name) {
+                    return Err(Error::new(position, format!("[{}] is defined twice", name)));
+                }
+                tables.push(Table { name: name, array: array, position: position, entries: vec![] });
+            },
+            Some(_) => {
+                let key = try!(parser.key());
+                parser.skip_space();
+                try!(parser.expect('='));
+                parser.skip_space();
+                let item = try!(parser.value());
+                try!(parser.end_of_line());
+                let table = tables.last_mut().unwrap();
+                if table.get(&key).is_some() {
+                    return Err(Error::new(position, format!("`{}` is set twice", key)));
+                }
+                table.entries.push((key, item));
+            },
+        }
+    }
+    Ok(tables)
+}
+
+/// A string quoted and escaped so `parse` reads it back
+pub fn quote(s: &str) -> String {
+    let escaped = s.replace("\\", "\\\\").replace("\"", "\\\"")
+        .replace("\n", "\\n").replace("\t", "\\t").replace("\r", "\\r");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use super::quote;
+    use super::Position;
+    use super::Value;
+
+    fn error_at(text: &str) -> (usize, usize, String) {
+        let e = parse(text).unwrap_err();
+        (e.position.line, e.position.column, e.message)
+    }
+
+    #[test]
+    fn errors_point_at_the_bad_spot() {
+        let (line, column, _) = error_at("a = 1\nb = ?\n");
+        assert_eq!((line, column), (2, 5));
+        let (line, column, _) = error_at("[table]\n  key 1\n");
+        assert_eq!((line, column), (2, 7));
+        // At the opening quote rather than where the line ran out
+        let (line, column, message) = error_at("a = \"open\nb = 1\n");
+        assert_eq!((line, column, message.as_str()), (1, 5, "unterminated string"));
+    }
+
+    #[test]
+    fn values_know_where_they_are() {
+        let tables = parse("# comment\n[camera]\nfar = 100\n").unwrap();
+        let camera = &tables[1];
+        assert_eq!(camera.position, Position { line: 2, column: 1 });
+        assert_eq!(camera.get("far").unwrap().position, Position { line: 3, column: 7 });
+    }
+
+    #[test]
+    fn duplicate_keys_are_errors() {
+        let (line, column, message) = error_at("a = 1\nb = 2\na = 3\n");
+        assert_eq!((line, column), (3, 1));
+        assert!(message.contains("`a` is set twice"));
+        // The same key in different tables is fine
+        assert!(parse("[x]\na = 1\n[y]\na = 2\n").is_ok());
+    }
+
+    #[test]
+    fn duplicate_tables_are_errors() {
+        let (line, column, message) = error_at("[fog]\n[sky]\n[fog]\n");
+        assert_eq!((line, column), (3, 1));
+        assert!(message.contains("[fog] is defined twice"));
+        // Array tables and their dotted tables repeat
+        let tables = parse("[[object]]\n[object.mesh]\n[[object]]\n[object.mesh]\n").unwrap();
+        assert_eq!(tables.len(), 5);
+        assert!(tables[1].array && tables[3].array);
+    }
+
+    #[test]
+    fn arrays_span_lines() {
+        let text = "points = [\n  [1, 2, 3],  # first\n\n  [4, 5, 6],\n]\nafter = true\n";
+        let tables = parse(text).unwrap();
+        let points = tables[0].get("points").unwrap().as_array().unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[1].as_numbers(3).unwrap(), vec![4.0, 5.0, 6.0]);
+        assert_eq!(points[1].position, Position { line: 4, column: 3 });
+        assert_eq!(tables[0].get("after").unwrap().value, Value::Bool(true));
+    }
+
+    #[test]
+    fn quoted_strings_read_back() {
+        let s = "a \"quoted\" path\\with\ttabs\nand lines";
+        let tables = parse(&format!("s = {}\n", quote(s))).unwrap();
+        assert_eq!(tables[0].get("s").unwrap().as_str().unwrap(), s);
+    }
+}
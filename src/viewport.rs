@@ -0,0 +1,88 @@
+//! Extra cameras drawn into parts of the window
+//!
+//! Besides the main camera a world can have any number of viewports,
+//! each with its own camera and rectangle of the window, for split
+//! screen, a rear view mirror or a minimap. Viewports are drawn in
+//! order over the main view.
+
+use std::f64::consts::PI;
+
+use camera::Camera;
+
+/// How a viewport's camera moves with the main camera
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Follow {
+    /// The camera is moved on its own
+    Fixed,
+    /// From the main camera's position, looking back
+    Behind,
+    /// This far above the main camera looking straight down, turned
+    /// with the main camera's heading
+    Above(f64),
+}
+
+/// A camera and the part of the window it is drawn into
+#[derive(Debug,Clone)]
+pub struct Viewport {
+    pub camera: Camera,
+    /// Left, top, width and height as fractions of the window
+    pub rect: [f64; 4],
+    pub follow: Follow,
+}
+
+/// A rectangle given as fractions of the window in pixels
+pub fn pixels(rect: [f64; 4], width: f64, height: f64) -> [f64; 4] {
+    let x0 = (rect[0] * width).floor();
+    let y0 = (rect[1] * height).floor();
+    let x1 = ((rect[0] + rect[2]) * width).floor();
+    let y1 = ((rect[1] + rect[3]) * height).floor();
+    [x0, y0, (x1 - x0).max(1.0), (y1 - y0).max(1.0)]
+}
+
+impl Viewport {
+    pub fn new(rect: [f64; 4]) -> Viewport {
+        Viewport {
+            camera: Camera::default(),
+            rect: rect,
+            follow: Follow::Fixed,
+        }
+    }
+
+    pub fn camera(mut self, camera: Camera) -> Viewport {
+        self.camera = camera;
+        self
+    }
+
+    pub fn follow(mut self, follow: Follow) -> Viewport {
+        self.follow = follow;
+        self
+    }
+
+    /// A small rear view mirror at the top middle of the window
+    pub fn rear_view() -> Viewport {
+        Viewport::new([0.35, 0.02, 0.3, 0.15]).follow(Follow::Behind)
+    }
+
+    /// A top down minimap in the top right corner of the window
+    pub fn minimap() -> Viewport {
+        let mut viewport = Viewport::new([0.74, 0.02, 0.24, 0.24]).follow(Follow::Above(500.0));
+        viewport.camera.far = 1500.0;
+        viewport
+    }
+
+    /// Move the camera along with the main camera
+    pub fn follow_camera(&mut self, main: &Camera) {
+        match self.follow {
+            Follow::Fixed => {},
+            Follow::Behind => {
+                self.camera.r = main.r;
+                self.camera.theta = [main.theta[0], main.theta[1] + PI, main.theta[2]];
+            },
+            Follow::Above(height) => {
+                // Up is -y in the world
+                self.camera.r = [main.r[0], main.r[1] - height, main.r[2]];
+                self.camera.theta = [-PI / 2.0, main.theta[1], 0.0];
+            },
+        }
+    }
+}
@@ -1,175 +1,897 @@
 //! Module managing a world of mesh and actor objects
 
-use std::cell::RefCell;
-
 pub use float::One;
 pub use float::Zero;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::time::Instant;
+use assets::Assets;
+use behaviour::Behaviour;
+use behaviour::BehaviourSystem;
+use behaviour::Behaviours;
+use behaviour::Event;
 use camera::Camera;
+use clock;
+use clock::Clock;
+use ecs::CollisionSystem;
+use ecs::Components;
+use ecs::LightSystem;
+use ecs::MovementSystem;
+use ecs::ScriptSystem;
+use ecs::System;
+use fog::Fog;
 use glutin_window::GlutinWindow as Window;
+use graphics;
+use graphics::Transformed;
+use graphics::default_draw_state;
+use input::Bindings;
+use input::Input;
 use lights::LightSource;
+use math::Transform;
 use mesh::Mesh;
-use mesh::Face;
+use mesh::Shading;
+use occlusion::AmbientOcclusion;
+use outline::Outlines;
 use opengl_graphics::GlGraphics;
 use opengl_graphics::OpenGL;
 use piston::event_loop::*;
 use piston::input::*;
 use piston::window::WindowSettings;
-use std::cmp::Ordering;
+use postprocess::PostProcess;
+use random::Random;
+use raster::Framebuffer;
+use render::FaceOrder;
+use render::GlCanvas;
+use render::RenderPath;
+use render::Renderer;
+use render::Transparency;
+use save;
+use sky::Sky;
+use stereo;
+use stereo::Stereo;
+use stereo::StereoMode;
+use svg::SvgCanvas;
 use types::Vec3;
+use viewport;
+use viewport::Viewport;
 
 const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
 
+/// Where the quicksave action saves the game and quickload loads it from
+const QUICKSAVE: &'static str = "quicksave.sav";
+
+/// Units per second the camera moves with its axes held
+const CAMERA_SPEED: f64 = 200.0;
+
+/// Radians per second the camera turns with its axes held
+const CAMERA_TURN: f64 = 1.0;
+
+/// A stable reference to an object in a world, it stays valid while
+/// other objects are added and removed and never refers to a different
+/// object once its own is removed
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct Handle {
+    index: u32,
+    generation: u32,
+}
+
+/// Where the object a handle refers to is stored
+#[derive(Debug,Clone,Copy)]
+struct Slot {
+    generation: u32,
+    /// Index into `World::objects`, `None` once the object is removed
+    object: Option<usize>,
+}
+
+/// A node of the scene graph, with meshes placed by its transform
 #[derive(Debug)]
 pub struct WorldObject {
     pub meshes: Vec<Mesh>,
-    pub r: Vec3,
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    /// Transform relative to the parent, or to the world without one
+    local: Transform,
+    /// Transform to the world, cached until the object or one of its
+    /// ancestors moves
+    world: Transform,
+    /// Transform to the world as of the tick before last, `None` until
+    /// the object has been through a tick
+    previous: Option<Transform>,
+    /// Transform the meshes were last placed with
+    placed: Transform,
+    dirty: bool,
+    handle: Option<Handle>,
+    parent: Option<Handle>,
+    children: Vec<Handle>,
 }
 
 impl WorldObject {
     pub fn new() -> WorldObject {
-        WorldObject { meshes: vec![], r: [0.0; 3] }
+        WorldObject {
+            meshes: vec![],
+            name: None,
+            tags: vec![],
+            local: Transform::identity(),
+            world: Transform::identity(),
+            previous: None,
+            placed: Transform::identity(),
+            dirty: true,
+            handle: None,
+            parent: None,
+            children: vec![],
+        }
     }
 
     pub fn mesh(mut self, mesh: Mesh) -> WorldObject {
         self.meshes.push(mesh);
         self
     }
+
+    pub fn name(mut self, name: &str) -> WorldObject {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> WorldObject {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    pub fn transform(mut self, transform: Transform) -> WorldObject {
+        self.local = transform;
+        self
+    }
+
+    pub fn position(mut self, r: Vec3) -> WorldObject {
+        self.local.translation = r;
+        self
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    /// The handle of the object once it is in a world
+    pub fn handle(&self) -> Option<Handle> {
+        self.handle
+    }
+
+    pub fn local(&self) -> &Transform {
+        &self.local
+    }
+
+    /// Transform to the world as of the last `World::update_transforms`
+    pub fn world(&self) -> &Transform {
+        &self.world
+    }
+
+    pub fn parent(&self) -> Option<Handle> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[Handle] {
+        &self.children
+    }
 }
 
 
 pub struct World {
+    /// Every object, in no particular order, use handles to keep track
+    /// of one
     pub objects: Vec<WorldObject>,
-    pub gl: GlGraphics,
-    pub window: Window,
+    slots: Vec<Slot>,
+    /// Slots of removed objects, reused by the next objects added
+    free: Vec<usize>,
+    names: HashMap<String, Handle>,
     pub t: f64,
+    /// Turns real time into fixed simulation ticks
+    pub clock: Clock,
+    /// Random numbers for gameplay, kept in save games
+    pub random: Random,
+    /// Meshes loaded once and shared by the objects
+    pub assets: Assets,
+    /// Buttons held and the actions and axes they are bound to
+    pub input: Input,
     pub camera: Camera,
     pub lights: Vec<LightSource>,
-    pub triangles: RefCell<Vec<DepthTriangle>>,
-}
-
-
-pub struct DepthTriangle {
-    face: Face,
-    dist: f64,
+    pub renderer: Renderer,
+    /// Frame drawn into by the software render path
+    pub framebuffer: Framebuffer,
+    /// Sky and time of day driving the primary light and the fog, the
+    /// background is the plain fog color without one
+    pub sky: Option<Sky>,
+    /// Render from two eyes. Only the software path can, so it is used
+    /// while this is set whatever `renderer.path` says.
+    pub stereo: Option<Stereo>,
+    /// Whether stereo overriding another render path was reported
+    stereo_override_warned: bool,
+    /// The left eye's frame while the right eye is rendered
+    eye_frame: Framebuffer,
+    /// Left, top, width and height of the main camera's view as
+    /// fractions of the window
+    pub camera_rect: [f64; 4],
+    /// More cameras drawn over the main view
+    pub viewports: Vec<Viewport>,
+    /// Components of objects beyond their transform and meshes
+    pub components: Components,
+    /// Run in order on every update
+    systems: Vec<Box<System>>,
+    /// Run once per frame with real time, outside the ticks, so they
+    /// keep running while the game is paused
+    frame_systems: Vec<Box<System>>,
+    /// Events for behaviours and the object each is for, `None` for
+    /// every object
+    events: Vec<(Option<Handle>, Event)>,
 }
 
 
 impl World {
+    /// Create an empty world, nothing is shown until it is run in a
+    /// window or a terminal
     pub fn new() -> World {
-        let opengl = OpenGL::V3_2;
-
-        // Create an Glutin window.
-        let window: Window = WindowSettings::new("Esparia", [800, 800])
-            .opengl(opengl)
-            .exit_on_esc(true)
-            .build()
-            .unwrap();
-
         let light = LightSource::new([200.0, 100.0, 0.0]);
 
         World {
-            gl: GlGraphics::new(opengl),
             objects: vec![],
+            slots: vec![],
+            free: vec![],
+            names: HashMap::new(),
             t: 0.0,
-            window: window,
+            clock: Clock::new(),
+            random: Random::from_time(),
+            assets: Assets::new(),
+            input: Input::new(Bindings::default()),
             camera: Camera::default(),
             lights: vec![light],
-            triangles: RefCell::new(vec![]),
+            renderer: Renderer::new(Fog::new(BLACK)),
+            framebuffer: Framebuffer::new(0, 0),
+            sky: None,
+            stereo: None,
+            stereo_override_warned: false,
+            eye_frame: Framebuffer::new(0, 0),
+            camera_rect: [0.0, 0.0, 1.0, 1.0],
+            viewports: vec![],
+            components: Components::new(),
+            systems: vec![],
+            frame_systems: vec![],
+            events: vec![],
         }
-
+        .system(ScriptSystem)
+        .system(BehaviourSystem)
+        .system(MovementSystem)
+        .system(LightSystem)
+        .system(CollisionSystem)
     }
 
-    pub fn object(mut self, object: WorldObject) -> World {
+    /// Add an object at the top of the scene graph
+    pub fn object(&mut self, mut object: WorldObject) -> Handle {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                self.slots.push(Slot { generation: 0, object: None });
+                self.slots.len() - 1
+            },
+        };
+        let handle = Handle { index: slot as u32, generation: self.slots[slot].generation };
+        self.slots[slot].object = Some(self.objects.len());
+        if let Some(ref name) = object.name {
+            self.names.insert(name.clone(), handle);
+        }
+        object.handle = Some(handle);
         self.objects.push(object);
+        handle
+    }
+
+    /// Add an object as a child of `parent`, its transform is relative
+    /// to the parent's. Returns `None` if `parent` was removed.
+    pub fn add_child(&mut self, parent: Handle, mut object: WorldObject) -> Option<Handle> {
+        let p = match self.index(parent) {
+            Some(p) => p,
+            None => return None,
+        };
+        object.parent = Some(parent);
+        let child = self.object(object);
+        self.objects[p].children.push(child);
+        Some(child)
+    }
+
+    /// Index into `objects` of the object a handle refers to
+    fn index(&self, handle: Handle) -> Option<usize> {
+        match self.slots.get(handle.index as usize) {
+            Some(slot) if slot.generation == handle.generation => slot.object,
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&WorldObject> {
+        match self.index(handle) {
+            Some(i) => Some(&self.objects[i]),
+            None => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: Handle) -> Option<&mut WorldObject> {
+        match self.index(handle) {
+            Some(i) => Some(&mut self.objects[i]),
+            None => None,
+        }
+    }
+
+    /// The object last added with this name
+    pub fn find(&self, name: &str) -> Option<Handle> {
+        self.names.get(name).cloned().and_then(|h| self.index(h).map(|_| h))
+    }
+
+    /// Every object with this tag
+    pub fn tagged(&self, tag: &str) -> Vec<Handle> {
+        self.objects.iter()
+            .filter(|object| object.has_tag(tag))
+            .filter_map(|object| object.handle)
+            .collect()
+    }
+
+    /// Remove an object along with its children, other handles stay
+    /// valid. Returns the removed object, or `None` if it was already.
+    pub fn remove(&mut self, handle: Handle) -> Option<WorldObject> {
+        let i = match self.index(handle) {
+            Some(i) => i,
+            None => return None,
+        };
+        let children = self.objects[i].children.clone();
+        for child in children.into_iter() {
+            self.remove(child);
+        }
+        if let Some(parent) = self.objects[i].parent {
+            if let Some(p) = self.index(parent) {
+                self.objects[p].children.retain(|&c| c != handle);
+            }
+        }
+
+        // Removing a child may have moved this object
+        let i = self.index(handle).unwrap();
+        let mut object = self.objects.swap_remove(i);
+        if i < self.objects.len() {
+            if let Some(moved) = self.objects[i].handle {
+                self.slots[moved.index as usize].object = Some(i);
+            }
+        }
+        let slot = &mut self.slots[handle.index as usize];
+        slot.object = None;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index as usize);
+        if let Some(ref name) = object.name {
+            if self.names.get(name) == Some(&handle) {
+                self.names.remove(name);
+            }
+        }
+
+        self.components.remove_entity(handle);
+
+        // The BSP tree refers to faces by object index
+        self.renderer.bsp = None;
+        object.handle = None;
+        object.parent = None;
+        object.children.clear();
+        Some(object)
+    }
+
+    /// Replace an object's transform relative to its parent
+    pub fn set_transform(&mut self, object: Handle, transform: Transform) {
+        if let Some(i) = self.index(object) {
+            self.objects[i].local = transform;
+            self.objects[i].dirty = true;
+        }
+    }
+
+    /// Move an object relative to its parent
+    pub fn translate_object(&mut self, object: Handle, r: Vec3) {
+        if let Some(i) = self.index(object) {
+            let local = Transform::translation(r).mul(&self.objects[i].local);
+            self.set_transform(object, local);
+        }
+    }
+
+    /// Whether `ancestor` is `object` or one of its parents
+    fn is_ancestor(&self, ancestor: Handle, object: Handle) -> bool {
+        let mut next = Some(object);
+        while let Some(o) = next {
+            if o == ancestor {
+                return true;
+            }
+            next = self.get(o).and_then(|o| o.parent);
+        }
+        false
+    }
+
+    /// Move an object under a new parent, or to the top of the scene
+    /// graph, keeping where it is in the world. Returns false and does
+    /// nothing if either object was removed or the object would become
+    /// its own ancestor.
+    pub fn reparent(&mut self, object: Handle, parent: Option<Handle>) -> bool {
+        let i = match self.index(object) {
+            Some(i) => i,
+            None => return false,
+        };
+        if let Some(p) = parent {
+            if self.index(p).is_none() || self.is_ancestor(object, p) {
+                return false;
+            }
+        }
+        self.update_transforms();
+
+        if let Some(old) = self.objects[i].parent {
+            if let Some(o) = self.index(old) {
+                self.objects[o].children.retain(|&c| c != object);
+            }
+        }
+        let world = self.objects[i].world;
+        let local = match parent.and_then(|p| self.index(p)) {
+            Some(p) => {
+                self.objects[p].children.push(object);
+                self.objects[p].world.inverse().mul(&world)
+            },
+            None => world,
+        };
+        self.objects[i].parent = parent;
+        self.set_transform(object, local);
+        true
+    }
+
+    /// Recompute the world transforms of moved objects and their
+    /// descendants, placing their meshes
+    pub fn update_transforms(&mut self) {
+        let mut stack: Vec<(usize, bool)> = (0..self.objects.len())
+            .filter(|&i| self.objects[i].parent.is_none())
+            .map(|i| (i, false))
+            .collect();
+        while let Some((i, parent_moved)) = stack.pop() {
+            let moved = parent_moved || self.objects[i].dirty;
+            if moved {
+                let parent = match self.objects[i].parent.and_then(|p| self.index(p)) {
+                    Some(p) => self.objects[p].world,
+                    None => Transform::identity(),
+                };
+                let object = &mut self.objects[i];
+                object.world = parent.mul(&object.local);
+                object.dirty = false;
+                object.placed = object.world;
+                for mesh in object.meshes.iter_mut() {
+                    mesh.place(&object.world);
+                }
+            }
+            for &child in self.objects[i].children.iter() {
+                if let Some(c) = self.index(child) {
+                    stack.push((c, moved));
+                }
+            }
+        }
+    }
+
+    /// Attach a behaviour to an object, started on the next update
+    pub fn add_behaviour<B>(&mut self, object: Handle, behaviour: B) where B: Behaviour + 'static {
+        if self.index(object).is_none() {
+            return;
+        }
+        let storage = self.components.storage_mut::<Behaviours>();
+        if !storage.contains(object) {
+            storage.insert(object, Behaviours::new());
+        }
+        storage.get_mut(object).unwrap().push(Box::new(behaviour));
+    }
+
+    /// Send an event to an object's behaviours, delivered on the next
+    /// update. Scripts run before behaviours, so their events arrive in
+    /// the update they were sent in.
+    pub fn send(&mut self, object: Handle, event: Event) {
+        self.events.push((Some(object), event));
+    }
+
+    /// Send an event to every behaviour, delivered on the next update
+    pub fn broadcast(&mut self, event: Event) {
+        self.events.push((None, event));
+    }
+
+    /// Take the events sent since they were last delivered
+    pub fn take_events(&mut self) -> Vec<(Option<Handle>, Event)> {
+        mem::replace(&mut self.events, vec![])
+    }
+
+    /// Add a system, run on every update after the ones added before
+    pub fn system<S>(mut self, system: S) -> World where S: System + 'static {
+        self.systems.push(Box::new(system));
+        self
+    }
+
+    /// Add a system run once per frame with the real seconds since the
+    /// last one, even while the game is paused or slowed down
+    pub fn frame_system<S>(mut self, system: S) -> World where S: System + 'static {
+        self.frame_systems.push(Box::new(system));
+        self
+    }
+
+    /// Run every system once
+    fn run_systems(&mut self, dt: f64) {
+        let mut systems = mem::replace(&mut self.systems, vec![]);
+        for system in systems.iter_mut() {
+            system.run(self, dt);
+        }
+        // Keep systems added while running
+        systems.extend(self.systems.drain(..));
+        self.systems = systems;
+    }
+
+    /// Run every frame system once
+    fn run_frame_systems(&mut self, dt: f64) {
+        let mut systems = mem::replace(&mut self.frame_systems, vec![]);
+        for system in systems.iter_mut() {
+            system.run(self, dt);
+        }
+        systems.extend(self.frame_systems.drain(..));
+        self.frame_systems = systems;
+    }
+
+    /// Replace the lights, the first one is the primary light that
+    /// shading and shadows are based on
+    pub fn lights(mut self, lights: Vec<LightSource>) -> World {
+        self.lights = lights;
+        self
+    }
+
+    /// Set whether meshes cast shadows from the primary light
+    pub fn shadows(mut self, shadows: bool) -> World {
+        self.renderer.shadows = shadows;
+        self
+    }
+
+    /// Set how far from the camera faces are still drawn
+    pub fn draw_distance(mut self, far: f64) -> World {
+        self.camera.far = far;
+        self
+    }
+
+    /// Set whether frames are drawn straight to the window or through
+    /// the software rasterizer
+    pub fn render_path(mut self, path: RenderPath) -> World {
+        self.renderer.path = path;
+        self
+    }
+
+    /// Set how faces are ordered for the painter's path
+    pub fn face_order(mut self, face_order: FaceOrder) -> World {
+        self.renderer.face_order = face_order;
+        self
+    }
+
+    /// Set how translucent faces are blended in the software path
+    pub fn transparency(mut self, transparency: Transparency) -> World {
+        self.renderer.transparency = transparency;
         self
     }
 
-    fn render(&mut self, args: &RenderArgs) {
+    /// Set how faces are lit, smoothly or in flat toon bands
+    pub fn shading(mut self, shading: Shading) -> World {
+        self.renderer.shading = shading;
+        self
+    }
+
+    /// Draw silhouette and crease lines over the faces, only with the
+    /// software render path
+    pub fn outlines(mut self, outlines: Outlines) -> World {
+        self.renderer.outlines = Some(outlines);
+        self
+    }
+
+    /// Set the post-processing of frames, which implies the software
+    /// render path
+    pub fn post_process(mut self, post: PostProcess) -> World {
+        self.renderer.post = post;
+        self.renderer.path = RenderPath::Software;
+        self
+    }
+
+    /// Render from two eyes, on the software path even if another one is
+    /// chosen later
+    pub fn stereo(mut self, stereo: Stereo) -> World {
+        self.stereo = Some(stereo);
+        self.renderer.path = RenderPath::Software;
+        self
+    }
+
+    /// Set the part of the window the main camera is drawn into
+    pub fn camera_rect(mut self, rect: [f64; 4]) -> World {
+        self.camera_rect = rect;
+        self
+    }
+
+    /// Add a camera drawn into its own part of the window
+    pub fn viewport(mut self, viewport: Viewport) -> World {
+        self.viewports.push(viewport);
+        self
+    }
+
+    /// Split the window between the main camera on the left and
+    /// `camera` on the right
+    pub fn split_screen(self, camera: Camera) -> World {
+        self.camera_rect([0.0, 0.0, 0.5, 1.0])
+            .viewport(Viewport::new([0.5, 0.0, 0.5, 1.0]).camera(camera))
+    }
+
+    /// Throw away the BSP tree so it is rebuilt from the static meshes,
+    /// needed after static geometry is added or changed
+    pub fn rebuild_bsp(&mut self) {
+        self.renderer.bsp = None;
+    }
+
+    /// Bake ambient occlusion into every static mesh, occluded by all
+    /// of the static geometry in the world
+    pub fn bake_occlusion(&self, ao: &AmbientOcclusion) {
+        let statics: Vec<&Mesh> = self.objects.iter()
+            .flat_map(|object| object.meshes.iter())
+            .filter(|mesh| mesh.static_geometry)
+            .collect();
+        for mesh in statics.iter() {
+            ao.bake(mesh, &statics);
+        }
+    }
+
+    /// Bake ambient occlusion like `bake_occlusion`, loading each mesh's
+    /// result from `dir` when it was baked there before from the same
+    /// geometry and settings and saving it there otherwise
+    pub fn bake_occlusion_cached<P>(&self, ao: &AmbientOcclusion, dir: P) -> io::Result<()>
+        where P: AsRef<Path>
+    {
+        let statics: Vec<&Mesh> = self.objects.iter()
+            .flat_map(|object| object.meshes.iter())
+            .filter(|mesh| mesh.static_geometry)
+            .collect();
+        for mesh in statics.iter() {
+            if try!(ao.bake_cached(mesh, &statics, &dir)) {
+                info!("Baked ambient occlusion into {}", dir.as_ref().display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the fog faces fade into, its color is also the background
+    pub fn fog(mut self, fog: Fog) -> World {
+        self.renderer.fog = fog;
+        self
+    }
+
+    /// Set the sky, which takes over the primary light and the fog color
+    pub fn sky(mut self, sky: Sky) -> World {
+        self.sky = Some(sky);
+        self.apply_sky();
+        self
+    }
+
+    /// Set which buttons trigger each action and move each axis
+    pub fn bindings(mut self, bindings: Bindings) -> World {
+        self.input.bindings = bindings;
+        self
+    }
+
+    /// Stop or restart the time of day
+    pub fn toggle_time(&mut self) {
+        if let Some(ref mut sky) = self.sky {
+            sky.toggle_pause();
+        }
+    }
+
+    /// Set the primary light and the fog from the sky
+    pub fn apply_sky(&mut self) {
+        if let Some(ref sky) = self.sky {
+            if self.lights.is_empty() {
+                self.lights.push(LightSource::directional([0.0, -1.0, 0.0]));
+            }
+            sky.apply(&mut self.lights[0], &mut self.renderer.fog);
+        }
+    }
+
+    /// Write the current view as an SVG with one polygon per visible
+    /// face, drawn back to front like the painter's path
+    pub fn export_svg<P>(&mut self, path: P) -> io::Result<()> where P: AsRef<Path> {
+        self.update_transforms();
+        let mut canvas = SvgCanvas::new(self.camera.width, self.camera.height);
+        canvas.background(self.renderer.fog.color);
+        if let Some(ref sky) = self.sky {
+            sky.draw(&self.camera, &mut canvas);
+        }
+        let render_path = self.renderer.path;
+        self.renderer.path = RenderPath::Painter;
+        self.renderer.render(&self.objects, &self.camera, &self.lights, &mut canvas);
+        self.renderer.path = render_path;
+        canvas.save(path)
+    }
+
+    /// Render a frame into the software framebuffer at `width` by
+    /// `height` pixels, whichever render path is set
+    pub fn rasterize(&mut self, width: usize, height: usize) {
+        self.update_transforms();
+        let stereo = match self.stereo {
+            Some(stereo) => stereo,
+            None => {
+                self.rasterize_view(width, height);
+                self.renderer.post.apply(&mut self.framebuffer);
+                return;
+            },
+        };
+
+        let eye_width = match stereo.mode {
+            StereoMode::Anaglyph => width,
+            StereoMode::SideBySide => (width / 2).max(1),
+        };
+        self.camera.width = eye_width as f64;
+        self.camera.height = height as f64;
+        self.camera.update_projection();
+        let center = self.camera.clone();
+
+        self.camera = stereo.eye(&center, -1.0);
+        self.rasterize_view(eye_width, height);
+        mem::swap(&mut self.framebuffer, &mut self.eye_frame);
+        self.camera = stereo.eye(&center, 1.0);
+        self.rasterize_view(eye_width, height);
+        self.camera = center;
+
+        match stereo.mode {
+            StereoMode::Anaglyph => stereo::anaglyph(&self.eye_frame, &mut self.framebuffer),
+            StereoMode::SideBySide => {
+                stereo::side_by_side(&self.eye_frame, &mut self.framebuffer, width)
+            },
+        }
+        self.renderer.post.apply(&mut self.framebuffer);
+    }
+
+    /// Render the camera's view into the framebuffer
+    fn rasterize_view(&mut self, width: usize, height: usize) {
+        self.camera.width = width as f64;
+        self.camera.height = height as f64;
+        self.camera.update_projection();
+
+        let background = self.renderer.fog.color;
+        self.framebuffer.resize(width, height);
+        self.framebuffer.clear(background);
+        if let Some(ref sky) = self.sky {
+            sky.draw(&self.camera, &mut self.framebuffer);
+        }
+        let render_path = self.renderer.path;
+        self.renderer.path = RenderPath::Software;
+        self.renderer.render(&self.objects, &self.camera, &self.lights, &mut self.framebuffer);
+        self.renderer.path = render_path;
+        self.framebuffer.resolve();
+    }
+
+    fn render(&mut self, gl: &mut GlGraphics, args: &RenderArgs) {
         use graphics::clear;
-        use graphics::Transformed;
+
+        self.update_transforms();
+
+        let (width, height) = (args.width as f64, args.height as f64);
+        let background = self.renderer.fog.color;
+        gl.draw(args.viewport(), |_, gl| clear(background, gl));
+
+        for viewport in self.viewports.iter_mut() {
+            viewport.follow_camera(&self.camera);
+        }
+
+        let main = viewport::pixels(self.camera_rect, width, height);
+        self.render_view(gl, args, main);
+
+        // Each viewport is drawn over the main view with its camera
+        // swapped in as the main one
+        for i in 0..self.viewports.len() {
+            let rect = viewport::pixels(self.viewports[i].rect, width, height);
+            mem::swap(&mut self.camera, &mut self.viewports[i].camera);
+            self.render_view(gl, args, rect);
+            mem::swap(&mut self.camera, &mut self.viewports[i].camera);
+        }
+    }
+
+    /// Draw the camera's view into a rectangle of the window
+    fn render_view(&mut self, gl: &mut GlGraphics, args: &RenderArgs, rect: [f64; 4]) {
+        if self.stereo.is_some() && self.renderer.path != RenderPath::Software &&
+            !self.stereo_override_warned {
+            warn!("Stereo needs the software render path, using it instead of {:?}",
+                  self.renderer.path);
+            self.stereo_override_warned = true;
+        }
+        if self.renderer.path == RenderPath::Software || self.stereo.is_some() {
+            // Render at a fraction of the window's resolution, drawing
+            // the frame scaled up gives square pixels
+            let pixel = self.renderer.post.pixel_size.max(1);
+            let (width, height) = (rect[2] as usize, rect[3] as usize);
+            self.rasterize((width / pixel).max(1), (height / pixel).max(1));
+            self.camera.width = rect[2];
+            self.camera.height = rect[3];
+            self.camera.update_projection();
+
+            let framebuffer = &self.framebuffer;
+            gl.draw(args.viewport(), |c, gl| {
+                framebuffer.draw(rect, c.transform, gl);
+            });
+            return;
+        }
 
         let lights = &self.lights;
         let objects = &self.objects;
         let camera = &mut self.camera;
-        let triangles = &mut self.triangles;
+        let renderer = &mut self.renderer;
+        let sky = &self.sky;
 
-        camera.width = args.width as f64;
-        camera.height = args.height as f64;
+        camera.width = rect[2];
+        camera.height = rect[3];
         camera.update_projection();
 
+        let background = renderer.fog.color;
 
-        // let mut n_triangles = 0;
-        // Count all of the triangles in the whole world
-        // for object in &self.objects {
-        //     for mesh in object.meshes.iter() {
-        //         n_triangles += mesh.mesh.faces.borrow().len();
-        //     }
-        // }
-
-        // if triangles.borrow().len() != n_triangles {
-        // Get all of the triangles in the whole world
-        triangles.borrow_mut().clear();
-        for object in &self.objects {
-            for mesh in object.meshes.iter() {
-                for face in mesh.mesh.faces.borrow_mut().iter() {
-                    let d = face.distance(camera.r);
-                    triangles.borrow_mut().push(DepthTriangle {
-                        face: face.clone(),
-                        dist: d,
-                    });
-                    // println!("{:}", d);
-                }
+        gl.draw(args.viewport(), |c, gl| {
+            graphics::Rectangle::new(background).draw(rect, default_draw_state(), c.transform, gl);
+            let transform = c.transform.trans(rect[0], rect[1]);
+            let mut canvas = GlCanvas::new(gl, transform).clip([0.0, 0.0, rect[2], rect[3]]);
+            if let Some(ref sky) = *sky {
+                sky.draw(camera, &mut canvas);
             }
-        }
-        // }
-
-        // Sort those triangles based on distance from the camera
-
-        triangles.borrow_mut().sort_by(
-            |a, b| a.dist.partial_cmp(&b.dist).unwrap_or(Ordering::Less)
-        );
-
-        self.gl.draw(args.viewport(), |c, gl| {
-            clear(BLACK, gl);
-
-            // // Get all of the triangles in the whole world
-            // for object in objects.iter() {
-            //     for mesh in object.meshes.iter() {
-            //         for face in mesh.mesh.faces.borrow_mut().iter() {
-            //             if face.distance(camera.r) < 300.0 {
-            //                 face.draw_filled(
-            //                     camera,
-            //                     lights,
-            //                     c.transform,
-            //                     gl,
-            //                 )
-            //             }
-            //         }
-            //     }
-            // }
-            //
-
-            for triangle in triangles.borrow().iter() {
-                if triangle.dist < 600.0 {
-                    triangle.face.draw_filled(
-                        camera,
-                        lights,
-                        c.transform,
-                        gl,
-                    );
-                }
-            }
-
-
+            renderer.render(objects, camera, lights, &mut canvas);
         });
+    }
 
+    /// Set the simulation speed, 0 pauses and below 1 is slow motion
+    pub fn time_scale(mut self, time_scale: f64) -> World {
+        self.clock.time_scale = time_scale;
+        self
+    }
 
+    /// Run as many fixed ticks as `dt` seconds of real time make, then
+    /// place meshes between the last two ticks for drawing
+    pub fn advance(&mut self, dt: f64) {
+        let ticks = self.clock.advance(dt);
+        for _ in 0..ticks {
+            for object in self.objects.iter_mut() {
+                object.previous = Some(object.world);
+            }
+            let tick = self.clock.tick;
+            self.update(tick);
+        }
+        self.run_frame_systems(dt);
+        let alpha = self.clock.alpha();
+        self.interpolate(alpha);
+    }
 
+    /// Place meshes `alpha` of the way from their previous tick's
+    /// transform to the current one
+    fn interpolate(&mut self, alpha: f64) {
+        self.update_transforms();
+        for object in self.objects.iter_mut() {
+            let target = match object.previous {
+                Some(previous) => previous.lerp(&object.world, alpha),
+                None => object.world,
+            };
+            if target != object.placed {
+                object.placed = target;
+                for mesh in object.meshes.iter_mut() {
+                    mesh.place(&target);
+                }
+            }
+        }
     }
 
-    fn update(&mut self, args: &UpdateArgs) {
-        self.t += args.dt;
-        self.objects[1].meshes[0].translate([self.t.cos(), 0.0, self.t.sin()])
+    /// Move the world forward by one tick of `dt` seconds
+    pub fn update(&mut self, dt: f64) {
+        self.t += dt;
+        if let Some(ref mut sky) = self.sky {
+            sky.advance(dt);
+        }
+        self.apply_sky();
+        for (key, e) in self.assets.poll().into_iter() {
+            warn!("Could not load {}: {}", key, e);
+        }
+        self.move_camera(dt);
+        self.run_systems(dt);
+        self.update_transforms();
+        self.assets.unload_unused();
+        self.input.clear();
     }
 
     fn move_diamond(&mut self, key: &String) {
@@ -181,51 +903,238 @@ impl World {
             "w" => [0.0, 0.0,   d],
             _ => [0.0; 3]
         };
-        self.objects[1].meshes[0].translate(r);
-
+        if let Some(diamond) = self.find("diamond") {
+            self.translate_object(diamond, r);
+        }
     }
 
-    fn move_camera(&mut self, key: &String) {
-        let d = 10.0;
-        let r = match key.as_str() {
-            "a" => [ -d, 0.0, 0.0],
-            "d" => [  d, 0.0, 0.0],
-            "s" => [0.0, 0.0,  -d],
-            "w" => [0.0, 0.0,   d],
-            "x" => [0.0, d,  0.0],
-            "2" => [0.0, -d, 0.0],
-            _ => [0.0; 3]
-        };
+    /// Move and turn the camera by the held buttons of its axes, and
+    /// turn it by the mouse
+    fn move_camera(&mut self, dt: f64) {
+        let d = CAMERA_SPEED * dt;
+        // Up is -y
+        let r = [self.input.axis("strafe") * d,
+                 -self.input.axis("rise") * d,
+                 self.input.axis("forward") * d];
         self.camera.translate(r);
+        let a = CAMERA_TURN * dt;
+        let look = self.input.axis("look") * a + self.input.motion("look");
+        let turn = self.input.axis("turn") * a + self.input.motion("turn");
+        self.camera.rotate([look, turn, 0.0]);
+    }
+
+    /// A button went down, running the actions bound to it that act
+    /// straight away rather than on the next update
+    pub fn press(&mut self, button: Button) {
+        if !self.input.press(button) {
+            return;
+        }
+        for action in self.input.bindings.actions_of(button).into_iter() {
+            match action.as_str() {
+                "pause" => self.toggle_time(),
+                "screenshot" => {
+                    let path = format!("esparia-{}.svg", (self.t * 1000.0) as u64);
+                    match self.export_svg(&path) {
+                        Ok(()) => info!("Saved {}", path),
+                        Err(e) => warn!("Could not save {}: {}", path, e),
+                    }
+                },
+                "quicksave" => match save::save(self, QUICKSAVE) {
+                    Ok(()) => info!("Saved {}", QUICKSAVE),
+                    Err(e) => warn!("Could not save {}: {}", QUICKSAVE, e),
+                },
+                "quickload" => match save::load(self, QUICKSAVE) {
+                    Ok(()) => info!("Loaded {}", QUICKSAVE),
+                    Err(e) => warn!("Could not load {}: {}", QUICKSAVE, e),
+                },
+                _ => {},
+            }
+        }
     }
 
-    fn turn_camera(&mut self, pos: [f64; 2]) {
-        self.camera.theta[1] = (pos[0]-self.camera.width/2.0) / self.camera.width*6.0;
-        self.camera.theta[0] = -(pos[1]-self.camera.height/2.0) /  self.camera.height*2.0;
+    pub fn release(&mut self, button: Button) {
+        self.input.release(button);
     }
 
+    /// Show the world in a window until it is closed
     pub fn run(mut self) {
         println!("Running world...");
 
-        let mut events = self.window.events();
+        let opengl = OpenGL::V3_2;
+
+        // Create an Glutin window.
+        let mut window: Window = WindowSettings::new("Esparia", [800, 800])
+            .opengl(opengl)
+            .exit_on_esc(true)
+            .build()
+            .unwrap();
+        let mut gl = GlGraphics::new(opengl);
+
+        let mut events = window.events();
+        let mut last = Instant::now();
 
-        while let Some(e) = events.next(&mut self.window) {
+        while let Some(e) = events.next(&mut window) {
+            // The simulation runs on its own clock, catching up with
+            // real time before each frame
             if let Some(r) = e.render_args() {
-                self.render(&r);
+                let now = Instant::now();
+                self.advance(clock::seconds(now.duration_since(last)));
+                last = now;
+                self.render(&mut gl, &r);
+            }
+
+            if let Some(button) = e.press_args() {
+                self.press(button);
             }
 
-            else if let Some(u) = e.update_args() {
-                self.update(&u);
+            if let Some(button) = e.release_args() {
+                self.release(button);
+            }
+
+            // Releases while unfocused never arrive
+            if let Some(false) = e.focus_args() {
+                self.input.release_all();
             }
 
             if let Some(c) = e.mouse_cursor_args() {
-                self.turn_camera(c);
+                self.input.move_cursor(c);
             }
 
             else if let Some(c) = e.text_args() {
-                self.move_camera(&c);
+                self.broadcast(Event::Key(c));
             }
         }
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Handle;
+    use super::World;
+    use super::WorldObject;
+    use ecs::Velocity;
+    use math::Transform;
+
+    fn named(world: &mut World, name: &str) -> Handle {
+        world.object(WorldObject::new().name(name))
+    }
+
+    fn close(a: &Transform, b: &Transform) -> bool {
+        let near = |x: f64, y: f64| (x - y).abs() < 1e-9;
+        (0..3).all(|i| {
+            near(a.translation[i], b.translation[i]) &&
+                (0..3).all(|j| near(a.linear[i][j], b.linear[i][j]))
+        })
+    }
+
+    #[test]
+    fn handles_go_stale_when_their_slot_is_reused() {
+        let mut world = World::new();
+        let a = named(&mut world, "a");
+        assert!(world.remove(a).is_some());
+        assert!(world.get(a).is_none());
+        assert!(world.remove(a).is_none());
+
+        let b = named(&mut world, "b");
+        assert_eq!(b.index, a.index);
+        assert!(world.get(a).is_none());
+        assert!(world.remove(a).is_none());
+        assert_eq!(world.get(b).unwrap().name, Some("b".to_string()));
+        assert_eq!(world.find("a"), None);
+        assert_eq!(world.find("b"), Some(b));
+    }
+
+    #[test]
+    fn handles_follow_objects_moved_by_removal() {
+        let mut world = World::new();
+        let a = named(&mut world, "a");
+        let b = named(&mut world, "b");
+        let c = named(&mut world, "c");
+        world.components.insert(c, Velocity([1.0, 0.0, 0.0]));
+        // The last object fills the removed one's place
+        world.remove(a);
+        assert_eq!(world.get(b).unwrap().name, Some("b".to_string()));
+        assert_eq!(world.get(c).unwrap().name, Some("c".to_string()));
+        assert_eq!(world.get(c).unwrap().handle(), Some(c));
+        assert_eq!(world.components.get::<Velocity>(c).unwrap().0, [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn removing_an_object_removes_its_descendants() {
+        let mut world = World::new();
+        let parent = named(&mut world, "parent");
+        let child = world.add_child(parent, WorldObject::new().name("child")).unwrap();
+        let grandchild = world.add_child(child, WorldObject::new()).unwrap();
+        let other = named(&mut world, "other");
+        let sibling = world.add_child(other, WorldObject::new()).unwrap();
+        world.components.insert(grandchild, Velocity([0.0; 3]));
+
+        world.remove(child);
+        assert!(world.get(child).is_none());
+        assert!(world.get(grandchild).is_none());
+        assert!(world.components.get::<Velocity>(grandchild).is_none());
+        assert!(world.get(parent).unwrap().children().is_empty());
+
+        world.remove(parent);
+        assert_eq!(world.objects.len(), 2);
+        assert_eq!(world.get(other).unwrap().children(), &[sibling][..]);
+        assert_eq!(world.get(sibling).unwrap().parent(), Some(other));
+    }
+
+    #[test]
+    fn reparenting_refuses_cycles() {
+        let mut world = World::new();
+        let parent = named(&mut world, "parent");
+        let child = world.add_child(parent, WorldObject::new()).unwrap();
+        let grandchild = world.add_child(child, WorldObject::new()).unwrap();
+
+        assert!(!world.reparent(parent, Some(grandchild)));
+        assert!(!world.reparent(parent, Some(parent)));
+        assert!(!world.reparent(child, Some(grandchild)));
+        assert_eq!(world.get(parent).unwrap().parent(), None);
+        assert_eq!(world.get(child).unwrap().parent(), Some(parent));
+        assert_eq!(world.get(parent).unwrap().children(), &[child][..]);
+
+        let removed = named(&mut world, "removed");
+        world.remove(removed);
+        assert!(!world.reparent(child, Some(removed)));
+        assert!(!world.reparent(removed, None));
+        assert_eq!(world.get(child).unwrap().parent(), Some(parent));
+    }
+
+    #[test]
+    fn reparenting_keeps_the_world_transform() {
+        let mut world = World::new();
+        let parent = world.object(WorldObject::new().transform(
+            Transform::translation([10.0, 0.0, 0.0]).mul(&Transform::scale(2.0))));
+        let child = world.add_child(parent, WorldObject::new().transform(
+            Transform::translation([1.0, 0.0, 0.0]))).unwrap();
+        world.update_transforms();
+        let placed = *world.get(child).unwrap().world();
+        assert!(close(&placed, &Transform::translation([12.0, 0.0, 0.0])
+                                  .mul(&Transform::scale(2.0))));
+
+        // Out to the top of the graph
+        assert!(world.reparent(child, None));
+        world.update_transforms();
+        assert!(close(world.get(child).unwrap().world(), &placed));
+        assert!(close(world.get(child).unwrap().local(), &placed));
+        assert!(world.get(parent).unwrap().children().is_empty());
+
+        // Under a turned and moved parent
+        let other = world.object(WorldObject::new().transform(
+            Transform::translation([0.0, 5.0, 0.0]).mul(&Transform::rotation([0.3, 1.2, 0.0]))));
+        world.update_transforms();
+        assert!(world.reparent(child, Some(other)));
+        world.update_transforms();
+        assert!(close(world.get(child).unwrap().world(), &placed));
+
+        // And the child follows its new parent from then on
+        world.set_transform(other, Transform::translation([0.0, 6.0, 0.0])
+                            .mul(&Transform::rotation([0.3, 1.2, 0.0])));
+        world.update_transforms();
+        let moved = world.get(child).unwrap().world().translation;
+        assert!((moved[1] - placed.translation[1] - 1.0).abs() < 1e-9);
+    }
+}